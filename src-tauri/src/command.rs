@@ -1,6 +1,11 @@
 use crate::config::*;
 use crate::db::Database;
-use log::{error, info};
+use crate::gateway::{shutdown_gateways, CtpError, GatewayKind, GatewayQuery};
+use crate::md::Subscriber;
+use crate::settlement::{ReconcileDiff, SettlementStatement};
+use crate::stop::{StopTrigger, StopTriggerInput};
+use crate::terminal::TerminalInfo;
+use log::{error, info, warn};
 use tauri::Manager;
 use tokio::sync::Mutex;
 
@@ -17,7 +22,8 @@ pub async fn close_splashscreen(
     }
     window.get_window("main").unwrap().show().unwrap();
     info!("Sync traders on start");
-    database.lock().await.sync_traders().await;
+    let removed = database.lock().await.sync_traders().await;
+    shutdown_gateways(removed).await;
     Ok(())
 }
 
@@ -145,6 +151,652 @@ pub async fn get_order_row(
         .await)
 }
 
+#[tauri::command]
+pub async fn place_order(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    input: OrderInput,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<PlaceOrderResult, String> {
+    info!("place order [{}:{}] {:?}", broker_id, account, input);
+    let trader = database
+        .lock()
+        .await
+        .get_trader(&broker_id, &account)
+        .ok_or("账户不存在".to_string())?;
+    let (key, rx) = trader
+        .lock()
+        .await
+        .insert_order(&input)
+        .map_err(|e| e.to_string())?;
+    // 等待第一个回报, 超时时状态未知, 由调用方在报单列表中确认
+    match tokio::time::timeout(std::time::Duration::from_secs(5), rx).await {
+        Ok(Ok(Ok(()))) => Ok(PlaceOrderResult {
+            key,
+            confirmed: true,
+        }),
+        Ok(Ok(Err(msg))) => Err(msg),
+        Ok(Err(_)) => Err("报单结果未知, 交易连接已关闭".to_string()),
+        Err(_) => {
+            warn!("[{}:{}] 报单{}等待回报超时", broker_id, account, key);
+            Ok(PlaceOrderResult {
+                key,
+                confirmed: false,
+            })
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn cancel_order(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    key: String,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<(), String> {
+    info!("cancel order [{}:{}] key={}", broker_id, account, key);
+    let trader = database
+        .lock()
+        .await
+        .get_trader(&broker_id, &account)
+        .ok_or("账户不存在".to_string())?;
+    let rx = trader
+        .lock()
+        .await
+        .cancel_order(&key)
+        .map_err(|e| e.to_string())?;
+    match tokio::time::timeout(std::time::Duration::from_secs(5), rx).await {
+        Ok(Ok(r)) => r.map_err(|e| e.to_string()),
+        Ok(Err(_)) => Err("撤单结果未知, 交易连接已关闭".to_string()),
+        Err(_) => {
+            warn!("[{}:{}] 撤单{}等待回报超时", broker_id, account, key);
+            Ok(())
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn parked_order_rows(
+    _window: tauri::Window,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<Vec<ParkedOrderRow>, String> {
+    Ok(database.lock().await.parked_order_rows().await)
+}
+
+#[tauri::command]
+pub async fn get_parked_order_row(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    key: String,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<Option<ParkedOrderRow>, String> {
+    Ok(database
+        .lock()
+        .await
+        .get_parked_order_row(&broker_id, &account, &key)
+        .await)
+}
+
+#[tauri::command]
+pub async fn place_parked_order(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    input: OrderInput,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<String, String> {
+    info!("place parked order [{}:{}] {:?}", broker_id, account, input);
+    let trader = database
+        .lock()
+        .await
+        .get_trader(&broker_id, &account)
+        .ok_or("账户不存在".to_string())?;
+    let rx = trader
+        .lock()
+        .await
+        .insert_parked_order(&input)
+        .map_err(|e| e.to_string())?;
+    match tokio::time::timeout(std::time::Duration::from_secs(5), rx).await {
+        Ok(Ok(r)) => r,
+        Ok(Err(_)) => Err("预埋单结果未知, 交易连接已关闭".to_string()),
+        Err(_) => Err("预埋单等待回报超时, 请查询预埋单确认".to_string()),
+    }
+}
+
+#[tauri::command]
+pub async fn remove_parked_order(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    key: String,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<(), String> {
+    info!(
+        "remove parked order [{}:{}] key={}",
+        broker_id, account, key
+    );
+    let trader = database
+        .lock()
+        .await
+        .get_trader(&broker_id, &account)
+        .ok_or("账户不存在".to_string())?;
+    let rx = trader
+        .lock()
+        .await
+        .remove_parked_order(&key)
+        .map_err(|e| e.to_string())?;
+    match tokio::time::timeout(std::time::Duration::from_secs(5), rx).await {
+        Ok(Ok(r)) => r.map_err(|e| e.to_string()),
+        Ok(Err(_)) => Err("删除结果未知, 交易连接已关闭".to_string()),
+        Err(_) => {
+            warn!("[{}:{}] 删除预埋单{}等待回报超时", broker_id, account, key);
+            Ok(())
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn refresh_parked_orders(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<(), String> {
+    let trader = database
+        .lock()
+        .await
+        .get_trader(&broker_id, &account)
+        .ok_or("账户不存在".to_string())?;
+    let receivers = trader
+        .lock()
+        .await
+        .query(GatewayQuery::ParkedOrder)
+        .map_err(|e| e.to_string())?;
+    for rx in receivers {
+        match tokio::time::timeout(std::time::Duration::from_secs(30), rx).await {
+            Ok(Ok(r)) => r.map_err(|e| e.to_string())?,
+            Ok(Err(_)) => return Err("查询结果未知, 交易连接已关闭".to_string()),
+            Err(_) => return Err("查询预埋单超时".to_string()),
+        }
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn cancel_all(
+    _window: tauri::Window,
+    filter: CancelFilter,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<Vec<String>, String> {
+    info!("cancel all {:?}", filter);
+    Ok(database.lock().await.cancel_all(&filter).await)
+}
+
+#[tauri::command]
+pub async fn commission_rate_rows(
+    _window: tauri::Window,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<Vec<CommissionRateRow>, String> {
+    Ok(database.lock().await.commission_rate_rows().await)
+}
+
+#[tauri::command]
+pub async fn margin_rate_rows(
+    _window: tauri::Window,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<Vec<MarginRateRow>, String> {
+    Ok(database.lock().await.margin_rate_rows().await)
+}
+
+#[tauri::command]
+pub async fn pnl_summary(
+    _window: tauri::Window,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<PnlSummary, String> {
+    Ok(database.lock().await.pnl_summary().await)
+}
+
+#[tauri::command]
+pub async fn add_stop_trigger(
+    _window: tauri::Window,
+    input: StopTriggerInput,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<String, String> {
+    info!("add stop trigger {:?}", input);
+    database.lock().await.add_stop_trigger(input).await
+}
+
+#[tauri::command]
+pub async fn cancel_stop_trigger(
+    _window: tauri::Window,
+    id: String,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<(), String> {
+    info!("cancel stop trigger id={}", id);
+    database.lock().await.cancel_stop_trigger(&id).await
+}
+
+#[tauri::command]
+pub async fn stop_trigger_rows(
+    _window: tauri::Window,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<Vec<StopTrigger>, String> {
+    Ok(database.lock().await.stop_trigger_rows())
+}
+
+#[tauri::command]
+pub async fn get_stop_trigger_row(
+    _window: tauri::Window,
+    id: String,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<Option<StopTrigger>, String> {
+    Ok(database.lock().await.stops.triggers.get(&id).cloned())
+}
+
+#[tauri::command]
+pub async fn estimate_order_cost(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    input: OrderInput,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<OrderCost, String> {
+    let trader = database
+        .lock()
+        .await
+        .get_trader(&broker_id, &account)
+        .ok_or("账户不存在".to_string())?;
+    let r = trader
+        .lock()
+        .await
+        .estimate_order_cost(&input)
+        .map_err(|e| e.to_string());
+    r
+}
+
+#[tauri::command]
+pub async fn market_data_rows(
+    _window: tauri::Window,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<Vec<MarketDataRow>, String> {
+    Ok(database.lock().await.market_data_rows().await)
+}
+
+#[tauri::command]
+pub async fn get_market_data_row(
+    _window: tauri::Window,
+    key: String,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<Option<MarketDataRow>, String> {
+    Ok(database.lock().await.get_market_data_row(&key).await)
+}
+
+#[tauri::command]
+pub async fn refresh_depth_market_data(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    keys: Vec<String>,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<(), String> {
+    info!(
+        "refresh depth market data [{}:{}] {:?}",
+        broker_id, account, keys
+    );
+    let trader = database
+        .lock()
+        .await
+        .get_trader(&broker_id, &account)
+        .ok_or("账户不存在".to_string())?;
+    let receivers = trader
+        .lock()
+        .await
+        .query(GatewayQuery::DepthMarketData(keys))
+        .map_err(|e| e.to_string())?;
+    for rx in receivers {
+        match tokio::time::timeout(std::time::Duration::from_secs(30), rx).await {
+            Ok(Ok(r)) => r.map_err(|e| e.to_string())?,
+            Ok(Err(_)) => return Err("查询结果未知, 交易连接已关闭".to_string()),
+            Err(_) => return Err("查询行情快照超时".to_string()),
+        }
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn settlement_rows(
+    _window: tauri::Window,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<Vec<SettlementRow>, String> {
+    Ok(database.lock().await.settlement_rows().await)
+}
+
+#[tauri::command]
+pub async fn get_settlement_row(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    trading_day: String,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<Option<SettlementRow>, String> {
+    Ok(database
+        .lock()
+        .await
+        .get_settlement_row(&broker_id, &account, &trading_day)
+        .await)
+}
+
+/// 查询指定交易日的结算单, 交易日为空时查询上一交易日
+#[tauri::command]
+pub async fn query_settlement(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    trading_day: String,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<(), String> {
+    info!(
+        "query settlement [{}:{}] trading_day={}",
+        broker_id, account, trading_day
+    );
+    let trader = database
+        .lock()
+        .await
+        .get_trader(&broker_id, &account)
+        .ok_or("账户不存在".to_string())?;
+    let receivers = trader
+        .lock()
+        .await
+        .query(GatewayQuery::Settlement(trading_day))
+        .map_err(|e| e.to_string())?;
+    for rx in receivers {
+        match tokio::time::timeout(std::time::Duration::from_secs(30), rx).await {
+            Ok(Ok(r)) => r.map_err(|e| e.to_string())?,
+            Ok(Err(_)) => return Err("查询结果未知, 交易连接已关闭".to_string()),
+            Err(_) => return Err("查询结算单超时".to_string()),
+        }
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn parse_settlement(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    trading_day: String,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<Option<SettlementStatement>, String> {
+    Ok(database
+        .lock()
+        .await
+        .settlement_statement(&broker_id, &account, &trading_day)
+        .await)
+}
+
+/// 结算单与当前成交和持仓核对, 应在结算单对应交易日收盘后使用
+#[tauri::command]
+pub async fn reconcile_settlement(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    trading_day: String,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<Vec<ReconcileDiff>, String> {
+    database
+        .lock()
+        .await
+        .reconcile_settlement(&broker_id, &account, &trading_day)
+        .await
+}
+
+#[tauri::command]
+pub async fn confirm_settlement(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<(), String> {
+    info!("confirm settlement [{}:{}]", broker_id, account);
+    let trader = database
+        .lock()
+        .await
+        .get_trader(&broker_id, &account)
+        .ok_or("账户不存在".to_string())?;
+    let rx = trader
+        .lock()
+        .await
+        .confirm_settlement()
+        .map_err(|e| e.to_string())?;
+    match tokio::time::timeout(std::time::Duration::from_secs(5), rx).await {
+        Ok(Ok(r)) => r.map_err(|e| e.to_string()),
+        Ok(Err(_)) => Err("确认结果未知, 交易连接已关闭".to_string()),
+        Err(_) => Err("确认结算单超时".to_string()),
+    }
+}
+
+#[tauri::command]
+pub async fn bank_account_rows(
+    _window: tauri::Window,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<Vec<BankAccountRow>, String> {
+    Ok(database.lock().await.bank_account_rows().await)
+}
+
+#[tauri::command]
+pub async fn transfer_rows(
+    _window: tauri::Window,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<Vec<TransferRow>, String> {
+    Ok(database.lock().await.transfer_rows().await)
+}
+
+/// 查询签约银行或转账流水
+async fn query_bank(
+    broker_id: &str,
+    account: &str,
+    q: GatewayQuery,
+    database: &tauri::State<'_, StateTpye>,
+) -> Result<(), String> {
+    let trader = database
+        .lock()
+        .await
+        .get_trader(broker_id, account)
+        .ok_or("账户不存在".to_string())?;
+    let receivers = trader.lock().await.query(q).map_err(|e| e.to_string())?;
+    for rx in receivers {
+        match tokio::time::timeout(std::time::Duration::from_secs(30), rx).await {
+            Ok(Ok(r)) => r.map_err(|e| e.to_string())?,
+            Ok(Err(_)) => return Err("查询结果未知, 交易连接已关闭".to_string()),
+            Err(_) => return Err("查询超时".to_string()),
+        }
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn query_bank_accounts(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<(), String> {
+    info!("query bank accounts [{}:{}]", broker_id, account);
+    query_bank(&broker_id, &account, GatewayQuery::BankAccount, &database).await
+}
+
+#[tauri::command]
+pub async fn query_transfers(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<(), String> {
+    info!("query transfers [{}:{}]", broker_id, account);
+    query_bank(&broker_id, &account, GatewayQuery::Transfer, &database).await
+}
+
+/// 银期转账, 返回转账记录的key, 结果通过cta-event通知
+#[tauri::command]
+pub async fn bank_transfer(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    input: TransferInput,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<String, String> {
+    info!(
+        "bank transfer [{}:{}] {:?} bank_id={} amount={}",
+        broker_id, account, input.direction, input.bank_id, input.amount
+    );
+    let trader = database
+        .lock()
+        .await
+        .get_trader(&broker_id, &account)
+        .ok_or("账户不存在".to_string())?;
+    let k = trader
+        .lock()
+        .await
+        .transfer(&input)
+        .map_err(|e| e.to_string())?;
+    Ok(k)
+}
+
+#[tauri::command]
+pub async fn query_bank_balance(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    bank_id: String,
+    bank_password: String,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<(), String> {
+    info!(
+        "query bank balance [{}:{}] bank_id={}",
+        broker_id, account, bank_id
+    );
+    let trader = database
+        .lock()
+        .await
+        .get_trader(&broker_id, &account)
+        .ok_or("账户不存在".to_string())?;
+    let r = trader
+        .lock()
+        .await
+        .query_bank_balance(&bank_id, &bank_password)
+        .map_err(|e| e.to_string());
+    r
+}
+
+#[tauri::command]
+pub async fn instrument_status_rows(
+    _window: tauri::Window,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<Vec<InstrumentStatusRow>, String> {
+    Ok(database.lock().await.instrument_status_rows().await)
+}
+
+#[tauri::command]
+pub async fn get_instrument_status(
+    _window: tauri::Window,
+    exchange: String,
+    symbol: String,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<Option<InstrumentStatusRow>, String> {
+    Ok(database
+        .lock()
+        .await
+        .get_instrument_status(&exchange, &symbol)
+        .await)
+}
+
+/// 手动切换交易前置组, 返回新前置组的名称
+#[tauri::command]
+pub async fn switch_front(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    front_group: String,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<String, String> {
+    info!(
+        "switch front [{}:{}] front_group={}",
+        broker_id, account, front_group
+    );
+    database
+        .lock()
+        .await
+        .switch_front(&broker_id, &account, Some(&front_group))
+        .await
+}
+
+/// 重新连接并从当日开始重传, 重建报单和成交
+#[tauri::command]
+pub async fn rebuild_history(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<(), String> {
+    info!("rebuild history [{}:{}]", broker_id, account);
+    database
+        .lock()
+        .await
+        .rebuild_history(&broker_id, &account)
+        .await
+}
+
+#[tauri::command]
+pub async fn error_rows(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<Vec<CtpError>, String> {
+    Ok(database.lock().await.error_rows(&broker_id, &account).await)
+}
+
+#[tauri::command]
+pub async fn subscribe_market_data(
+    _window: tauri::Window,
+    broker_id: String,
+    keys: Vec<String>,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<(), String> {
+    info!("subscribe market data [{}] {:?}", broker_id, keys);
+    if !database
+        .lock()
+        .await
+        .subscribe_market_data(&broker_id, &keys, Subscriber::User)
+        .await
+    {
+        return Err("行情连接不存在".to_string());
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn unsubscribe_market_data(
+    _window: tauri::Window,
+    broker_id: String,
+    keys: Vec<String>,
+    database: tauri::State<'_, StateTpye>,
+) -> Result<(), String> {
+    info!("unsubscribe market data [{}] {:?}", broker_id, keys);
+    if !database
+        .lock()
+        .await
+        .unsubscribe_market_data(&broker_id, &keys, Subscriber::User)
+        .await
+    {
+        return Err("行情连接不存在".to_string());
+    }
+    Ok(())
+}
+
 #[tauri::command]
 pub async fn default_account(
     _window: tauri::Window,
@@ -161,6 +813,14 @@ pub async fn default_broker(
     Ok(TradingBroker::default())
 }
 
+/// 采集本机的IP/MAC/硬盘序列号, 用于填写经纪商的终端信息
+#[tauri::command]
+pub async fn collect_terminal_info(_window: tauri::Window) -> Result<TerminalInfo, String> {
+    tokio::task::spawn_blocking(TerminalInfo::collect)
+        .await
+        .map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 pub async fn add_account(
     _window: tauri::Window,
@@ -186,7 +846,9 @@ pub async fn add_account(
         conf.accounts.push(account);
         conf.save(G3Config::default_path()).unwrap();
     }
-    db.sync_traders().await;
+    let removed = db.sync_traders().await;
+    drop(db);
+    shutdown_gateways(removed).await;
     Ok(())
 }
 
@@ -205,7 +867,92 @@ pub async fn delete_account(
             .retain(|ta| !(ta.account == account && ta.broker_id == broker_id));
         conf.save(G3Config::default_path()).unwrap();
     }
-    db.sync_traders().await;
+    let removed = db.sync_traders().await;
+    drop(db);
+    shutdown_gateways(removed).await;
+    Ok(())
+}
+
+/// 等待修改密码结果
+async fn wait_password_update(
+    rx: tokio::sync::oneshot::Receiver<Result<(), crate::gateway::Error>>,
+) -> Result<(), String> {
+    match tokio::time::timeout(std::time::Duration::from_secs(5), rx).await {
+        Ok(Ok(r)) => r.map_err(|e| e.to_string()),
+        Ok(Err(_)) => Err("修改结果未知, 交易连接已关闭".to_string()),
+        Err(_) => Err("修改密码超时".to_string()),
+    }
+}
+
+/// 修改交易密码, 成功后保存到配置
+#[tauri::command]
+pub async fn update_password(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    old_password: String,
+    new_password: String,
+    db: tauri::State<'_, StateTpye>,
+) -> Result<(), String> {
+    info!("update password [{}:{}]", broker_id, account);
+    if new_password.len() == 0 {
+        return Err("新密码不能为空".to_string());
+    }
+    let trader = db
+        .lock()
+        .await
+        .get_trader(&broker_id, &account)
+        .ok_or("账户不存在".to_string())?;
+    let rx = trader
+        .lock()
+        .await
+        .update_password(&old_password, &new_password)
+        .map_err(|e| e.to_string())?;
+    wait_password_update(rx).await?;
+    let mut db = db.lock().await;
+    let conf = &mut db.conf;
+    if let Some(ta) = conf
+        .accounts
+        .iter_mut()
+        .find(|ta| ta.broker_id == broker_id && ta.account == account)
+    {
+        ta.password = new_password;
+    }
+    conf.save(G3Config::default_path()).unwrap();
+    Ok(())
+}
+
+/// 修改资金密码, 成功后保存到经纪商配置
+#[tauri::command]
+pub async fn update_money_password(
+    _window: tauri::Window,
+    broker_id: String,
+    account: String,
+    old_password: String,
+    new_password: String,
+    db: tauri::State<'_, StateTpye>,
+) -> Result<(), String> {
+    info!("update money password [{}:{}]", broker_id, account);
+    if new_password.len() == 0 {
+        return Err("新密码不能为空".to_string());
+    }
+    let trader = db
+        .lock()
+        .await
+        .get_trader(&broker_id, &account)
+        .ok_or("账户不存在".to_string())?;
+    let rx = trader
+        .lock()
+        .await
+        .update_money_password(&old_password, &new_password)
+        .map_err(|e| e.to_string())?;
+    wait_password_update(rx).await?;
+    let mut db = db.lock().await;
+    let conf = &mut db.conf;
+    if let Some(b) = conf.brokers.iter_mut().find(|b| b.broker_id == broker_id) {
+        b.money_password = new_password;
+    }
+    conf.save(G3Config::default_path()).unwrap();
     Ok(())
 }
 
@@ -219,6 +966,9 @@ pub async fn set_broker(
     if broker.broker_id.len() == 0 {
         return Err("broker_id不能为空".to_string());
     }
+    if broker.gateway == GatewayKind::Ctp {
+        broker.validate()?;
+    }
     let mut db = db.lock().await;
     {
         let conf = &mut db.conf;
@@ -233,7 +983,9 @@ pub async fn set_broker(
         }
         conf.save(G3Config::default_path()).unwrap();
     }
-    db.sync_traders().await;
+    let removed = db.sync_traders().await;
+    drop(db);
+    shutdown_gateways(removed).await;
     Ok(())
 }
 
@@ -250,7 +1002,9 @@ pub async fn delete_broker(
         conf.brokers.retain(|b| !(b.broker_id == broker_id));
         conf.save(G3Config::default_path()).unwrap();
     }
-    db.sync_traders().await;
+    let removed = db.sync_traders().await;
+    drop(db);
+    shutdown_gateways(removed).await;
     Ok(())
 }
 #[tauri::command]
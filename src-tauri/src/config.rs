@@ -1,9 +1,14 @@
-use crate::trader::CtaStatus;
+use crate::gateway::{CtaStatus, GatewayKind};
 use ctp_futures::*;
 use log::error;
 use rust_share_util::*;
 use serde::{Deserialize, Serialize};
 
+/// CTP的字符串字段, 按GB18030解码, 内容不是ASCII时也不会panic
+pub fn ctp_str(v: &[i8]) -> String {
+    gb18030_cstr_to_str_i8(v).to_string()
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct TradingAccountRow {
     pub broker_id: String,
@@ -14,6 +19,7 @@ pub struct TradingAccountRow {
     pub front_group_name: String,
     pub status: CtaStatus,
     pub status_description: String,
+    pub reconnect_count: u32,
     pub equity: f64,
     pub closed_profit: f64,
     pub position_profit: f64,
@@ -22,6 +28,100 @@ pub struct TradingAccountRow {
     pub frozen_margin: f64,
     pub frozen_commission: f64,
     pub available: f64,
+    pub settlement_confirm: SettlementConfirm,
+    pub settlement_confirmed: bool,
+    pub login: LoginInfo,
+}
+
+/// 登录返回的会话信息, 交易所时间用于校准本地时钟
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct LoginInfo {
+    pub trading_day: String,
+    pub login_time: String,
+    pub system_name: String,
+    pub front_id: i32,
+    pub session_id: i32,
+    pub max_order_ref: String,
+    pub shfe_time: String,
+    pub dce_time: String,
+    pub czce_time: String,
+    pub ffex_time: String,
+    pub ine_time: String,
+}
+
+impl From<&CThostFtdcRspUserLoginField> for LoginInfo {
+    fn from(u: &CThostFtdcRspUserLoginField) -> Self {
+        Self {
+            trading_day: ctp_str(&u.TradingDay),
+            login_time: ctp_str(&u.LoginTime),
+            system_name: gb18030_cstr_to_str_i8(&u.SystemName).to_string(),
+            front_id: u.FrontID,
+            session_id: u.SessionID,
+            max_order_ref: ctp_str(&u.MaxOrderRef).trim().to_string(),
+            shfe_time: ctp_str(&u.SHFETime),
+            dce_time: ctp_str(&u.DCETime),
+            czce_time: ctp_str(&u.CZCETime),
+            ffex_time: ctp_str(&u.FFEXTime),
+            ine_time: ctp_str(&u.INETime),
+        }
+    }
+}
+
+/// 账户资金, 与网关无关
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct AccountFunds {
+    pub trading_day: String,
+    pub balance: f64,
+    pub available: f64,
+    pub curr_margin: f64,
+    pub frozen_margin: f64,
+    pub frozen_commission: f64,
+    pub commission: f64,
+    pub close_profit: f64,
+    pub position_profit: f64,
+    pub pre_balance: f64,
+    pub deposit: f64,
+    pub withdraw: f64,
+}
+
+impl From<&CThostFtdcTradingAccountField> for AccountFunds {
+    fn from(value: &CThostFtdcTradingAccountField) -> Self {
+        Self {
+            trading_day: ctp_str(&value.TradingDay),
+            balance: value.Balance,
+            available: value.Available,
+            curr_margin: value.CurrMargin,
+            frozen_margin: value.FrozenMargin,
+            frozen_commission: value.FrozenCommission,
+            commission: value.Commission,
+            close_profit: value.CloseProfit,
+            position_profit: value.PositionProfit,
+            pre_balance: value.PreBalance,
+            deposit: value.Deposit,
+            withdraw: value.Withdraw,
+        }
+    }
+}
+
+/// 单个账户的盈亏
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct AccountPnlRow {
+    pub broker_id: String,
+    pub account: String,
+    pub float_profit: f64,
+    pub position_profit: f64,
+    pub close_profit: f64,
+    pub commission: f64,
+}
+
+/// 所有账户的盈亏汇总
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct PnlSummary {
+    pub accounts: Vec<AccountPnlRow>,
+    pub float_profit: f64,
+    pub position_profit: f64,
+    pub close_profit: f64,
+    pub commission: f64,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
@@ -35,6 +135,8 @@ pub struct TradeRow {
     pub offset: i32,
     pub price: f64,
     pub volume: i32,
+    /// 按手续费率计算的手续费, 费率未查询到时为0
+    pub commission: f64,
 }
 impl TradeRow {
     pub fn key(&self) -> String {
@@ -44,17 +146,16 @@ impl TradeRow {
 impl From<&CThostFtdcTradeField> for TradeRow {
     fn from(value: &CThostFtdcTradeField) -> Self {
         Self {
-            broker_id: ascii_cstr_to_str_i8(&value.BrokerID).unwrap().to_string(),
-            account: ascii_cstr_to_str_i8(&value.InvestorID).unwrap().to_string(),
-            trade_id: ascii_cstr_to_str_i8(&value.TradeID).unwrap().to_string(),
-            exchange: ascii_cstr_to_str_i8(&value.ExchangeID).unwrap().to_string(),
-            symbol: ascii_cstr_to_str_i8(&value.InstrumentID)
-                .unwrap()
-                .to_string(),
+            broker_id: ctp_str(&value.BrokerID),
+            account: ctp_str(&value.InvestorID),
+            trade_id: ctp_str(&value.TradeID),
+            exchange: ctp_str(&value.ExchangeID),
+            symbol: ctp_str(&value.InstrumentID),
             direction: value.Direction as i32,
             offset: value.OffsetFlag as i32,
             price: value.Price,
             volume: value.Volume,
+            commission: 0.0,
         }
     }
 }
@@ -67,6 +168,7 @@ pub struct OrderRow {
     pub broker_id: String,
     pub account: String,
     pub order_sys_id: String,
+    pub exchange: String,
     pub symbol: String,
     pub direction: i32,
     pub offset: i32,
@@ -82,6 +184,39 @@ impl OrderRow {
     pub fn key(&self) -> String {
         format!("{}:{}:{}", self.front_id, self.session_id, self.order_ref)
     }
+
+    /// 未全部成交且未撤销的报单可以撤单
+    pub fn is_active(&self) -> bool {
+        self.status != THOST_FTDC_OST_AllTraded as i32
+            && self.status != THOST_FTDC_OST_Canceled as i32
+    }
+
+    /// 重传或查询结果晚于回报到达时, 旧的状态不能覆盖新的状态
+    pub fn is_stale(&self, current: &OrderRow) -> bool {
+        self.volume_traded < current.volume_traded || (self.is_active() && !current.is_active())
+    }
+}
+
+/// 批量撤单条件, 为None的字段不参与过滤
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct CancelFilter {
+    pub broker_id: Option<String>,
+    pub account: Option<String>,
+    pub symbol: Option<String>,
+    pub direction: Option<i32>,
+}
+
+impl CancelFilter {
+    pub fn matches_account(&self, broker_id: &str, account: &str) -> bool {
+        self.broker_id.as_ref().map_or(true, |b| b == broker_id)
+            && self.account.as_ref().map_or(true, |a| a == account)
+    }
+
+    pub fn matches(&self, o: &OrderRow) -> bool {
+        self.matches_account(&o.broker_id, &o.account)
+            && self.symbol.as_ref().map_or(true, |s| *s == o.symbol)
+            && self.direction.map_or(true, |d| d == o.direction)
+    }
 }
 
 impl From<&CThostFtdcOrderField> for OrderRow {
@@ -89,11 +224,12 @@ impl From<&CThostFtdcOrderField> for OrderRow {
         Self {
             front_id: o.FrontID,
             session_id: o.SessionID,
-            order_ref: ascii_cstr_to_str_i8(&o.OrderRef).unwrap().to_string(),
-            broker_id: ascii_cstr_to_str_i8(&o.BrokerID).unwrap().to_string(),
-            account: ascii_cstr_to_str_i8(&o.InvestorID).unwrap().to_string(),
-            order_sys_id: ascii_cstr_to_str_i8(&o.OrderSysID).unwrap().to_string(),
-            symbol: ascii_cstr_to_str_i8(&o.InstrumentID).unwrap().to_string(),
+            order_ref: ctp_str(&o.OrderRef),
+            broker_id: ctp_str(&o.BrokerID),
+            account: ctp_str(&o.InvestorID),
+            order_sys_id: ctp_str(&o.OrderSysID),
+            exchange: ctp_str(&o.ExchangeID),
+            symbol: ctp_str(&o.InstrumentID),
             direction: o.Direction as i32,
             offset: o.CombOffsetFlag[0] as i32,
             limit_price: o.LimitPrice,
@@ -107,12 +243,235 @@ impl From<&CThostFtdcOrderField> for OrderRow {
     }
 }
 
+/// 报单类型, 决定OrderPriceType/TimeCondition/VolumeCondition的组合
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum OrderType {
+    /// 限价, 当日有效
+    Limit,
+    /// 市价, 立即完成否则撤销
+    Market,
+    /// 限价, 立即成交剩余撤销
+    Fak,
+    /// 限价, 立即全部成交否则撤销
+    Fok,
+}
+
+impl Default for OrderType {
+    fn default() -> Self {
+        OrderType::Limit
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum VolumeCondition {
+    Any,
+    Min,
+    All,
+}
+
+impl Default for VolumeCondition {
+    fn default() -> Self {
+        VolumeCondition::Any
+    }
+}
+
+/// 前端下单参数, direction/offset与OrderRow一致使用CTP的字符值
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct OrderInput {
+    pub exchange: String,
+    pub symbol: String,
+    pub direction: i32,
+    pub offset: i32,
+    pub price: f64,
+    pub volume: i32,
+    pub order_type: OrderType,
+    /// 仅对Fak生效, Fok固定为全部成交
+    pub volume_condition: VolumeCondition,
+    pub min_volume: i32,
+    /// 条件单触发条件, 使用CTP的ContingentCondition字符值, 为0时立即触发
+    #[serde(default)]
+    pub contingent_condition: i32,
+    /// 条件单触发价
+    #[serde(default)]
+    pub stop_price: f64,
+}
+
+impl OrderInput {
+    pub fn is_conditional(&self) -> bool {
+        self.contingent_condition != 0
+            && self.contingent_condition != THOST_FTDC_CC_Immediately as i32
+    }
+
+    /// 价格条件单的触发规则(比较的价格, 大于触发价时触发, 等于触发价时触发), 预埋单等其他条件为None
+    pub fn trigger_rule(&self) -> Option<(TriggerPrice, bool, bool)> {
+        use TriggerPrice::*;
+        let c = self.contingent_condition;
+        // 止损: 买入时价格上涨到触发价, 卖出时下跌到触发价; 止赢相反
+        let buy = self.direction == THOST_FTDC_D_Buy as i32;
+        if c == THOST_FTDC_CC_Touch as i32 {
+            return Some((Last, buy, true));
+        }
+        if c == THOST_FTDC_CC_TouchProfit as i32 {
+            return Some((Last, !buy, true));
+        }
+        // 每种价格依次为大于, 大于等于, 小于, 小于等于
+        let rules = [
+            (
+                Last,
+                [
+                    THOST_FTDC_CC_LastPriceGreaterThanStopPrice,
+                    THOST_FTDC_CC_LastPriceGreaterEqualStopPrice,
+                    THOST_FTDC_CC_LastPriceLesserThanStopPrice,
+                    THOST_FTDC_CC_LastPriceLesserEqualStopPrice,
+                ],
+            ),
+            (
+                Ask,
+                [
+                    THOST_FTDC_CC_AskPriceGreaterThanStopPrice,
+                    THOST_FTDC_CC_AskPriceGreaterEqualStopPrice,
+                    THOST_FTDC_CC_AskPriceLesserThanStopPrice,
+                    THOST_FTDC_CC_AskPriceLesserEqualStopPrice,
+                ],
+            ),
+            (
+                Bid,
+                [
+                    THOST_FTDC_CC_BidPriceGreaterThanStopPrice,
+                    THOST_FTDC_CC_BidPriceGreaterEqualStopPrice,
+                    THOST_FTDC_CC_BidPriceLesserThanStopPrice,
+                    THOST_FTDC_CC_BidPriceLesserEqualStopPrice,
+                ],
+            ),
+        ];
+        for (price, conditions) in rules {
+            if let Some(i) = conditions.iter().position(|cc| *cc as i32 == c) {
+                return Some((price, i < 2, i % 2 == 1));
+            }
+        }
+        None
+    }
+
+    /// 价格条件单是否被行情触发
+    pub fn touched(&self, md: &MarketDataRow) -> bool {
+        let (price, greater, equal) = match self.trigger_rule() {
+            Some(rule) => rule,
+            None => return false,
+        };
+        let price = match price {
+            TriggerPrice::Last => md.last_price,
+            TriggerPrice::Ask => md.ask_prices.first().cloned().unwrap_or(0.0),
+            TriggerPrice::Bid => md.bid_prices.first().cloned().unwrap_or(0.0),
+        };
+        let stop = self.stop_price;
+        price > 0.0
+            && ((greater && price > stop) || (!greater && price < stop) || (equal && price == stop))
+    }
+}
+
+/// 条件单比较的价格
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriggerPrice {
+    Last,
+    Ask,
+    Bid,
+}
+
+/// 下单结果, confirmed为false时等待第一个回报超时, 报单状态未知, 之后通过OnRtnOrder事件更新
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct PlaceOrderResult {
+    pub key: String,
+    pub confirmed: bool,
+}
+
+/// 预埋单和条件单, 保存在经纪商服务器上
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct ParkedOrderRow {
+    pub broker_id: String,
+    pub account: String,
+    /// 预埋单为ParkedOrderID, 条件单为对应OrderRow的key
+    pub id: String,
+    pub conditional: bool,
+    pub order_ref: String,
+    pub exchange: String,
+    pub symbol: String,
+    pub direction: i32,
+    pub offset: i32,
+    pub limit_price: f64,
+    pub volume: i32,
+    pub contingent_condition: i32,
+    pub stop_price: f64,
+    /// 预埋单为ParkedOrderStatus, 条件单为OrderStatus
+    pub status: i32,
+    pub status_description: String,
+}
+impl ParkedOrderRow {
+    pub fn key(&self) -> String {
+        self.id.clone()
+    }
+
+    /// 未发送的预埋单和未触发的条件单可以删除
+    pub fn is_active(&self) -> bool {
+        if self.conditional {
+            self.status == THOST_FTDC_OST_NotTouched as i32
+        } else {
+            self.status == THOST_FTDC_PAOS_NotSend as i32
+        }
+    }
+}
+
+impl From<&CThostFtdcParkedOrderField> for ParkedOrderRow {
+    fn from(o: &CThostFtdcParkedOrderField) -> Self {
+        Self {
+            broker_id: ctp_str(&o.BrokerID),
+            account: ctp_str(&o.InvestorID),
+            id: ctp_str(&o.ParkedOrderID).trim().to_string(),
+            conditional: false,
+            order_ref: ctp_str(&o.OrderRef),
+            exchange: ctp_str(&o.ExchangeID),
+            symbol: ctp_str(&o.InstrumentID),
+            direction: o.Direction as i32,
+            offset: o.CombOffsetFlag[0] as i32,
+            limit_price: o.LimitPrice,
+            volume: o.VolumeTotalOriginal,
+            contingent_condition: o.ContingentCondition as i32,
+            stop_price: o.StopPrice,
+            status: o.Status as i32,
+            status_description: gb18030_cstr_to_str_i8(&o.ErrorMsg).to_string(),
+        }
+    }
+}
+
+impl From<&CThostFtdcOrderField> for ParkedOrderRow {
+    fn from(o: &CThostFtdcOrderField) -> Self {
+        let row = OrderRow::from(o);
+        Self {
+            id: row.key(),
+            conditional: true,
+            broker_id: row.broker_id,
+            account: row.account,
+            order_ref: row.order_ref,
+            exchange: row.exchange,
+            symbol: row.symbol,
+            direction: row.direction,
+            offset: row.offset,
+            limit_price: row.limit_price,
+            volume: row.volume_total_original,
+            contingent_condition: o.ContingentCondition as i32,
+            stop_price: o.StopPrice,
+            status: row.status,
+            status_description: row.status_description,
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct InstrumentRow {
     pub broker_id: String,
     pub account: String,
     pub exchange: String,
     pub symbol: String,
+    pub product_id: String,
     pub name: String,
     pub volume_multiple: i32,
     pub price_tick: f64,
@@ -130,21 +489,289 @@ impl From<&CThostFtdcInstrumentField> for InstrumentRow {
         Self {
             broker_id: "".into(),
             account: "".into(),
-            exchange: ascii_cstr_to_str_i8(&value.ExchangeID).unwrap().to_string(),
-            symbol: ascii_cstr_to_str_i8(&value.InstrumentID)
-                .unwrap()
-                .to_string(),
+            exchange: ctp_str(&value.ExchangeID),
+            symbol: ctp_str(&value.InstrumentID),
+            product_id: ctp_str(&value.ProductID),
             name: gb18030_cstr_to_str_i8(&value.InstrumentName).to_string(),
             volume_multiple: value.VolumeMultiple,
             price_tick: value.PriceTick,
             product_type: value.ProductClass as i32,
-            expire_date: ascii_cstr_to_str_i8(&value.ExpireDate).unwrap().to_string(),
+            expire_date: ctp_str(&value.ExpireDate),
+        }
+    }
+}
+
+/// 交易所推送的交易状态, 一般按品种推送, 部分交易所按合约推送
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct InstrumentStatusRow {
+    pub exchange: String,
+    /// 品种或合约代码
+    pub product: String,
+    pub status: i32,
+    pub trading_segment_sn: i32,
+    pub enter_time: String,
+    pub enter_reason: i32,
+}
+impl InstrumentStatusRow {
+    pub fn key(&self) -> String {
+        format!("{}:{}", self.exchange, self.product)
+    }
+
+    pub fn is_continuous(&self) -> bool {
+        self.status == THOST_FTDC_IS_Continous as i32
+    }
+
+    /// 连续交易和集合竞价报单阶段可以报单
+    pub fn accepts_orders(&self) -> bool {
+        self.is_continuous() || self.status == THOST_FTDC_IS_AuctionOrdering as i32
+    }
+
+    pub fn status_name(&self) -> &'static str {
+        match self.status as u8 {
+            b'0' => "开盘前",
+            b'1' => "非交易",
+            b'2' => "连续交易",
+            b'3' => "集合竞价报单",
+            b'4' => "集合竞价价格平衡",
+            b'5' => "集合竞价撮合",
+            b'6' => "收盘",
+            _ => "未知",
+        }
+    }
+}
+
+impl From<&CThostFtdcInstrumentStatusField> for InstrumentStatusRow {
+    fn from(value: &CThostFtdcInstrumentStatusField) -> Self {
+        Self {
+            exchange: ctp_str(&value.ExchangeID),
+            product: ctp_str(&value.InstrumentID),
+            status: value.InstrumentStatus as i32,
+            trading_segment_sn: value.TradingSegmentSN,
+            enter_time: ctp_str(&value.EnterTime),
+            enter_reason: value.EnterReason as i32,
         }
     }
 }
 
+/// 手续费率, symbol可能是合约也可能是品种
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
-pub struct MarketDataRow {}
+pub struct CommissionRateRow {
+    pub broker_id: String,
+    pub account: String,
+    pub exchange: String,
+    pub symbol: String,
+    pub open_ratio_by_money: f64,
+    pub open_ratio_by_volume: f64,
+    pub close_ratio_by_money: f64,
+    pub close_ratio_by_volume: f64,
+    pub close_today_ratio_by_money: f64,
+    pub close_today_ratio_by_volume: f64,
+}
+impl CommissionRateRow {
+    pub fn key(&self) -> String {
+        self.symbol.clone()
+    }
+
+    pub fn commission(&self, offset: i32, price: f64, volume: i32, volume_multiple: i32) -> f64 {
+        let (by_money, by_volume) = if offset == THOST_FTDC_OF_Open as i32 {
+            (self.open_ratio_by_money, self.open_ratio_by_volume)
+        } else if offset == THOST_FTDC_OF_CloseToday as i32 {
+            (
+                self.close_today_ratio_by_money,
+                self.close_today_ratio_by_volume,
+            )
+        } else {
+            (self.close_ratio_by_money, self.close_ratio_by_volume)
+        };
+        price * volume as f64 * volume_multiple as f64 * by_money + volume as f64 * by_volume
+    }
+}
+impl From<&CThostFtdcInstrumentCommissionRateField> for CommissionRateRow {
+    fn from(value: &CThostFtdcInstrumentCommissionRateField) -> Self {
+        Self {
+            broker_id: ctp_str(&value.BrokerID),
+            account: ctp_str(&value.InvestorID),
+            exchange: ctp_str(&value.ExchangeID),
+            symbol: ctp_str(&value.InstrumentID),
+            open_ratio_by_money: value.OpenRatioByMoney,
+            open_ratio_by_volume: value.OpenRatioByVolume,
+            close_ratio_by_money: value.CloseRatioByMoney,
+            close_ratio_by_volume: value.CloseRatioByVolume,
+            close_today_ratio_by_money: value.CloseTodayRatioByMoney,
+            close_today_ratio_by_volume: value.CloseTodayRatioByVolume,
+        }
+    }
+}
+
+/// 投机保证金率, symbol可能是合约也可能是品种
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct MarginRateRow {
+    pub broker_id: String,
+    pub account: String,
+    pub exchange: String,
+    pub symbol: String,
+    pub long_margin_ratio_by_money: f64,
+    pub long_margin_ratio_by_volume: f64,
+    pub short_margin_ratio_by_money: f64,
+    pub short_margin_ratio_by_volume: f64,
+}
+impl MarginRateRow {
+    pub fn key(&self) -> String {
+        self.symbol.clone()
+    }
+
+    pub fn margin(&self, direction: i32, price: f64, volume: i32, volume_multiple: i32) -> f64 {
+        let (by_money, by_volume) = if direction == THOST_FTDC_D_Buy as i32 {
+            (
+                self.long_margin_ratio_by_money,
+                self.long_margin_ratio_by_volume,
+            )
+        } else {
+            (
+                self.short_margin_ratio_by_money,
+                self.short_margin_ratio_by_volume,
+            )
+        };
+        price * volume as f64 * volume_multiple as f64 * by_money + volume as f64 * by_volume
+    }
+}
+impl From<&CThostFtdcInstrumentMarginRateField> for MarginRateRow {
+    fn from(value: &CThostFtdcInstrumentMarginRateField) -> Self {
+        Self {
+            broker_id: ctp_str(&value.BrokerID),
+            account: ctp_str(&value.InvestorID),
+            exchange: ctp_str(&value.ExchangeID),
+            symbol: ctp_str(&value.InstrumentID),
+            long_margin_ratio_by_money: value.LongMarginRatioByMoney,
+            long_margin_ratio_by_volume: value.LongMarginRatioByVolume,
+            short_margin_ratio_by_money: value.ShortMarginRatioByMoney,
+            short_margin_ratio_by_volume: value.ShortMarginRatioByVolume,
+        }
+    }
+}
+
+/// 预估报单占用的保证金和手续费
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct OrderCost {
+    pub margin: f64,
+    pub commission: f64,
+}
+
+/// CTP用DBL_MAX表示无效价格
+fn valid_price(v: f64) -> f64 {
+    if v == f64::MAX || !v.is_finite() {
+        0.0
+    } else {
+        v
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct MarketDataRow {
+    pub exchange: String,
+    pub symbol: String,
+    pub trading_day: String,
+    pub update_time: String,
+    pub update_millisec: i32,
+    pub last_price: f64,
+    pub pre_close_price: f64,
+    pub pre_settlement_price: f64,
+    pub settlement_price: f64,
+    pub open_price: f64,
+    pub highest_price: f64,
+    pub lowest_price: f64,
+    pub upper_limit_price: f64,
+    pub lower_limit_price: f64,
+    pub volume: i32,
+    pub turnover: f64,
+    pub open_interest: f64,
+    pub pre_open_interest: f64,
+    pub bid_prices: Vec<f64>,
+    pub bid_volumes: Vec<i32>,
+    pub ask_prices: Vec<f64>,
+    pub ask_volumes: Vec<i32>,
+    /// 模拟账户回放的行情, 只发给该账户(broker_id:account), 实盘行情为空
+    #[serde(default)]
+    pub replay_account: String,
+}
+impl MarketDataRow {
+    pub fn key(&self) -> String {
+        format!("{}:{}", self.exchange, self.symbol)
+    }
+
+    /// 估值价格, 没有最新价时依次使用结算价/昨结算价
+    pub fn valuation_price(&self) -> f64 {
+        [
+            self.last_price,
+            self.settlement_price,
+            self.pre_settlement_price,
+        ]
+        .into_iter()
+        .find(|p| *p > 0.0)
+        .unwrap_or(0.0)
+    }
+}
+
+impl From<&CThostFtdcDepthMarketDataField> for MarketDataRow {
+    fn from(value: &CThostFtdcDepthMarketDataField) -> Self {
+        Self {
+            exchange: ctp_str(&value.ExchangeID),
+            symbol: ctp_str(&value.InstrumentID),
+            trading_day: ctp_str(&value.TradingDay),
+            update_time: ctp_str(&value.UpdateTime),
+            update_millisec: value.UpdateMillisec,
+            last_price: valid_price(value.LastPrice),
+            pre_close_price: valid_price(value.PreClosePrice),
+            pre_settlement_price: valid_price(value.PreSettlementPrice),
+            settlement_price: valid_price(value.SettlementPrice),
+            open_price: valid_price(value.OpenPrice),
+            highest_price: valid_price(value.HighestPrice),
+            lowest_price: valid_price(value.LowestPrice),
+            upper_limit_price: valid_price(value.UpperLimitPrice),
+            lower_limit_price: valid_price(value.LowerLimitPrice),
+            volume: value.Volume,
+            turnover: valid_price(value.Turnover),
+            open_interest: valid_price(value.OpenInterest),
+            pre_open_interest: valid_price(value.PreOpenInterest),
+            bid_prices: [
+                value.BidPrice1,
+                value.BidPrice2,
+                value.BidPrice3,
+                value.BidPrice4,
+                value.BidPrice5,
+            ]
+            .into_iter()
+            .map(valid_price)
+            .collect(),
+            bid_volumes: vec![
+                value.BidVolume1,
+                value.BidVolume2,
+                value.BidVolume3,
+                value.BidVolume4,
+                value.BidVolume5,
+            ],
+            ask_prices: [
+                value.AskPrice1,
+                value.AskPrice2,
+                value.AskPrice3,
+                value.AskPrice4,
+                value.AskPrice5,
+            ]
+            .into_iter()
+            .map(valid_price)
+            .collect(),
+            ask_volumes: vec![
+                value.AskVolume1,
+                value.AskVolume2,
+                value.AskVolume3,
+                value.AskVolume4,
+                value.AskVolume5,
+            ],
+            replay_account: String::new(),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct PositionDetailRow {
     pub exchange: String,
@@ -153,6 +780,8 @@ pub struct PositionDetailRow {
     pub volume: i32,
     pub volume_closed: i32,
     pub trade_id: String,
+    pub open_price: f64,
+    pub open_date: String,
 }
 impl PositionDetailRow {
     pub fn key(&self) -> String {
@@ -162,14 +791,14 @@ impl PositionDetailRow {
 impl From<&CThostFtdcInvestorPositionDetailField> for PositionDetailRow {
     fn from(value: &CThostFtdcInvestorPositionDetailField) -> Self {
         Self {
-            exchange: ascii_cstr_to_str_i8(&value.ExchangeID).unwrap().to_string(),
-            symbol: ascii_cstr_to_str_i8(&value.InstrumentID)
-                .unwrap()
-                .to_string(),
+            exchange: ctp_str(&value.ExchangeID),
+            symbol: ctp_str(&value.InstrumentID),
             direction: value.Direction as i32,
             volume: value.Volume,
             volume_closed: value.CloseVolume,
-            trade_id: ascii_cstr_to_str_i8(&value.TradeID).unwrap().to_string(),
+            trade_id: ctp_str(&value.TradeID),
+            open_price: value.OpenPrice,
+            open_date: ctp_str(&value.OpenDate),
         }
     }
 }
@@ -185,36 +814,302 @@ pub struct PositionRow {
     pub open_cost: f64,
     pub open_amount: f64,
     pub open_volume: i32,
+    pub today_position: i32,
+    /// 剩余的昨仓, 即position - today_position
+    pub yd_position: i32,
+    /// 未成交平仓单冻结的数量
+    pub frozen: i32,
+    /// 估值价格, 最新价或结算价
+    pub last_price: f64,
+    /// 按开仓成本计算的浮动盈亏
+    pub float_profit: f64,
+    /// 盯市持仓盈亏, 昨仓按昨结算价, 今仓按开仓价计算
+    pub position_profit: f64,
 }
 impl PositionRow {
     pub fn key(&self) -> String {
         format!("{}:{}:{}", self.exchange, self.symbol, self.direction)
     }
+
+    /// 上期所/能源中心的今仓和昨仓分两条返回, 合并为一条
+    pub fn merge(&mut self, other: &PositionRow) {
+        self.position += other.position;
+        self.today_position += other.today_position;
+        self.yd_position += other.yd_position;
+        self.open_cost += other.open_cost;
+        self.open_amount += other.open_amount;
+        self.open_volume += other.open_volume;
+        self.position_profit += other.position_profit;
+    }
 }
 impl From<&CThostFtdcInvestorPositionField> for PositionRow {
     fn from(value: &CThostFtdcInvestorPositionField) -> Self {
         Self {
-            broker_id: ascii_cstr_to_str_i8(&value.BrokerID).unwrap().to_string(),
-            account: ascii_cstr_to_str_i8(&value.InvestorID).unwrap().to_string(),
-            exchange: ascii_cstr_to_str_i8(&value.ExchangeID).unwrap().to_string(),
-            symbol: ascii_cstr_to_str_i8(&value.InstrumentID)
-                .unwrap()
-                .to_string(),
+            broker_id: ctp_str(&value.BrokerID),
+            account: ctp_str(&value.InvestorID),
+            exchange: ctp_str(&value.ExchangeID),
+            symbol: ctp_str(&value.InstrumentID),
             position: value.Position,
             direction: value.PosiDirection as i32,
             open_cost: value.OpenCost,
             open_amount: value.OpenAmount,
             open_volume: value.OpenVolume,
+            today_position: value.TodayPosition,
+            yd_position: value.Position - value.TodayPosition,
+            frozen: 0,
+            last_price: 0.0,
+            float_profit: 0.0,
+            position_profit: value.PositionProfit,
+        }
+    }
+}
+
+/// 银期转账方向
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum TransferDirection {
+    BankToFuture,
+    FutureToBank,
+}
+
+impl Default for TransferDirection {
+    fn default() -> Self {
+        TransferDirection::BankToFuture
+    }
+}
+
+impl TransferDirection {
+    /// 期货发起的银期转账交易代码
+    pub fn trade_code(&self) -> &'static str {
+        match self {
+            TransferDirection::BankToFuture => "202001",
+            TransferDirection::FutureToBank => "202002",
+        }
+    }
+
+    fn from_trade_code(code: &str) -> Self {
+        if code == "202002" {
+            TransferDirection::FutureToBank
+        } else {
+            TransferDirection::BankToFuture
+        }
+    }
+}
+
+/// 前端转账参数, 银行密码不保存
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct TransferInput {
+    pub bank_id: String,
+    pub bank_password: String,
+    pub direction: TransferDirection,
+    pub amount: f64,
+}
+
+/// 签约银行, 银行余额查询后更新
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct BankAccountRow {
+    pub broker_id: String,
+    pub account: String,
+    pub bank_id: String,
+    pub bank_branch_id: String,
+    pub bank_account: String,
+    pub currency_id: String,
+    pub customer_name: String,
+    pub bank_use_amount: f64,
+    pub bank_fetch_amount: f64,
+    pub status_description: String,
+}
+impl BankAccountRow {
+    pub fn key(&self) -> String {
+        self.bank_id.clone()
+    }
+}
+
+impl From<&CThostFtdcAccountregisterField> for BankAccountRow {
+    fn from(r: &CThostFtdcAccountregisterField) -> Self {
+        Self {
+            broker_id: ctp_str(&r.BrokerID),
+            account: ctp_str(&r.AccountID),
+            bank_id: ctp_str(&r.BankID),
+            bank_branch_id: ctp_str(&r.BankBranchID),
+            bank_account: ctp_str(&r.BankAccount),
+            currency_id: ctp_str(&r.CurrencyID),
+            customer_name: gb18030_cstr_to_str_i8(&r.CustomerName).to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+/// 银期转账记录, 包括查询的历史流水和本次发起的转账
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct TransferRow {
+    pub broker_id: String,
+    pub account: String,
+    pub bank_id: String,
+    pub bank_account: String,
+    pub direction: TransferDirection,
+    pub amount: f64,
+    pub trading_day: String,
+    pub trade_time: String,
+    /// 银期平台流水号, 失败的请求为0
+    pub plate_serial: i32,
+    pub future_serial: i32,
+    pub request_id: i32,
+    pub error_id: i32,
+    pub error_msg: String,
+}
+impl TransferRow {
+    pub fn key(&self) -> String {
+        if self.plate_serial != 0 {
+            format!("{}:{}", self.trading_day, self.plate_serial)
+        } else {
+            format!("R{}", self.request_id)
+        }
+    }
+}
+
+impl From<&CThostFtdcReqTransferField> for TransferRow {
+    fn from(t: &CThostFtdcReqTransferField) -> Self {
+        Self {
+            broker_id: ctp_str(&t.BrokerID),
+            account: ctp_str(&t.AccountID),
+            bank_id: ctp_str(&t.BankID),
+            bank_account: ctp_str(&t.BankAccount),
+            direction: TransferDirection::from_trade_code(&ctp_str(&t.TradeCode)),
+            amount: t.TradeAmount,
+            trading_day: ctp_str(&t.TradingDay),
+            trade_time: ctp_str(&t.TradeTime),
+            plate_serial: t.PlateSerial,
+            future_serial: t.FutureSerial,
+            request_id: t.RequestID,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<&CThostFtdcRspTransferField> for TransferRow {
+    fn from(t: &CThostFtdcRspTransferField) -> Self {
+        Self {
+            broker_id: ctp_str(&t.BrokerID),
+            account: ctp_str(&t.AccountID),
+            bank_id: ctp_str(&t.BankID),
+            bank_account: ctp_str(&t.BankAccount),
+            direction: TransferDirection::from_trade_code(&ctp_str(&t.TradeCode)),
+            amount: t.TradeAmount,
+            trading_day: ctp_str(&t.TradingDay),
+            trade_time: ctp_str(&t.TradeTime),
+            plate_serial: t.PlateSerial,
+            future_serial: t.FutureSerial,
+            request_id: t.RequestID,
+            error_id: t.ErrorID,
+            error_msg: gb18030_cstr_to_str_i8(&t.ErrorMsg).to_string(),
+        }
+    }
+}
+
+impl From<&CThostFtdcTransferSerialField> for TransferRow {
+    fn from(t: &CThostFtdcTransferSerialField) -> Self {
+        Self {
+            broker_id: ctp_str(&t.BrokerID),
+            account: ctp_str(&t.AccountID),
+            bank_id: ctp_str(&t.BankID),
+            bank_account: ctp_str(&t.BankAccount),
+            direction: TransferDirection::from_trade_code(&ctp_str(&t.TradeCode)),
+            amount: t.TradeAmount,
+            trading_day: ctp_str(&t.TradingDay),
+            trade_time: ctp_str(&t.TradeTime),
+            plate_serial: t.PlateSerial,
+            future_serial: t.FutureSerial,
+            request_id: 0,
+            error_id: t.ErrorID,
+            error_msg: gb18030_cstr_to_str_i8(&t.ErrorMsg).to_string(),
         }
     }
 }
 
+/// 私有流和公共流的重传方式
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum ResumeType {
+    /// 从本交易日开始重传, 用于重建当日的报单和成交
+    Restart,
+    /// 从上次收到的位置续传
+    Resume,
+    /// 只接收登录后的数据
+    Quick,
+}
+
+impl Default for ResumeType {
+    fn default() -> Self {
+        ResumeType::Quick
+    }
+}
+
+impl ResumeType {
+    pub fn to_ctp(&self) -> THOST_TE_RESUME_TYPE {
+        match self {
+            ResumeType::Restart => THOST_TE_RESUME_TYPE_THOST_TERT_RESTART,
+            ResumeType::Resume => THOST_TE_RESUME_TYPE_THOST_TERT_RESUME,
+            ResumeType::Quick => THOST_TE_RESUME_TYPE_THOST_TERT_QUICK,
+        }
+    }
+}
+
+/// 结算单确认方式
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum SettlementConfirm {
+    /// 登录后查询结算单并自动确认
+    Auto,
+    /// 查询结算单后等待在界面上手动确认, 确认前不能报单
+    Manual,
+}
+
+impl Default for SettlementConfirm {
+    fn default() -> Self {
+        SettlementConfirm::Auto
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct TradingAccount {
     pub broker_id: String,
     pub account: String,
     pub password: String,
     pub front_group: String,
+    #[serde(default)]
+    pub settlement_confirm: SettlementConfirm,
+    /// 非交易时段拒绝报单, 默认只记录警告
+    #[serde(default)]
+    pub block_non_trading: bool,
+    #[serde(default)]
+    pub private_resume: ResumeType,
+    #[serde(default)]
+    pub public_resume: ResumeType,
+    /// 资金账户币种, 修改资金密码和银期转账使用, 为空时使用CNY
+    #[serde(default)]
+    pub currency_id: String,
+}
+
+impl TradingAccount {
+    pub fn currency(&self) -> &str {
+        if self.currency_id.is_empty() {
+            "CNY"
+        } else {
+            &self.currency_id
+        }
+    }
+}
+
+/// 结算单, 内容由多个GB18030分包拼接后解码
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct SettlementRow {
+    pub broker_id: String,
+    pub account: String,
+    pub trading_day: String,
+    pub content: String,
+}
+impl SettlementRow {
+    pub fn key(&self) -> String {
+        self.trading_day.clone()
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
@@ -235,6 +1130,7 @@ pub struct TradingBroker {
     pub user_product_info: String,
     pub auth_code: String,
     pub app_id: String,
+    /// 空或direct为直连, relay为中继多连接模式, relay_operator为中继操作员模式
     pub route_type: String,
     pub money_password: String,
     pub fens_trade_front: String,
@@ -243,6 +1139,143 @@ pub struct TradingBroker {
     pub hd_serial: String,
     pub inner_ip_address: String,
     pub mac_address: String,
+    /// 每秒查询次数限制, 为0时按1次处理
+    #[serde(default)]
+    pub query_per_second: u32,
+    #[serde(default)]
+    pub gateway: GatewayKind,
+    /// 模拟交易参数, 仅gateway为Paper时使用
+    #[serde(default)]
+    pub paper: PaperOptions,
+}
+
+/// 穿透式监管的终端信息上报方式, 对应TradingBroker.route_type
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RouteType {
+    /// 直连, 终端信息由CTP在认证时自动采集
+    Direct,
+    /// 中继多连接模式, 认证后登录前注册终端信息
+    Relay,
+    /// 中继操作员模式, 登录后上报终端信息
+    RelayOperator,
+}
+
+/// CThostFtdcUserSystemInfoField.ClientSystemInfo的最大长度
+pub const MAX_SYSTEM_INFO_LEN: usize = 272;
+
+impl TradingBroker {
+    pub fn route(&self) -> Result<RouteType, String> {
+        match self.route_type.trim().to_lowercase().as_str() {
+            "" | "direct" => Ok(RouteType::Direct),
+            "relay" => Ok(RouteType::Relay),
+            "relay_operator" => Ok(RouteType::RelayOperator),
+            other => Err(format!("未知的route_type: {}", other)),
+        }
+    }
+
+    /// 上报的终端信息, 未配置terminal_info时由IP/MAC/硬盘序列号拼接
+    pub fn system_info(&self) -> String {
+        if !self.terminal_info.is_empty() {
+            return self.terminal_info.clone();
+        }
+        [
+            ("IIP", &self.inner_ip_address),
+            ("MAC", &self.mac_address),
+            ("HD", &self.hd_serial),
+        ]
+        .iter()
+        .filter(|(_, v)| !v.is_empty())
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join(";")
+    }
+
+    /// 检查终端信息相关的配置, 中继模式下必须能生成终端信息
+    pub fn validate(&self) -> Result<(), String> {
+        self.validate_route()?;
+        if !self.inner_ip_address.is_empty()
+            && self.inner_ip_address.parse::<std::net::IpAddr>().is_err()
+        {
+            return Err(format!("内网IP格式错误: {}", self.inner_ip_address));
+        }
+        if !self.mac_address.is_empty() && !is_mac_address(&self.mac_address) {
+            return Err(format!("MAC地址格式错误: {}", self.mac_address));
+        }
+        if self.hd_serial.chars().any(|c| c.is_control()) {
+            return Err("硬盘序列号包含非法字符".to_string());
+        }
+        Ok(())
+    }
+
+    /// 只检查当前连接方式必需的配置, 直连时终端信息由CTP采集
+    pub fn validate_route(&self) -> Result<(), String> {
+        if self.route()? != RouteType::Direct {
+            if self.app_id.is_empty() {
+                return Err("中继模式需要AppID".to_string());
+            }
+            let info = self.system_info();
+            if info.is_empty() {
+                return Err("中继模式需要终端信息".to_string());
+            }
+            if info.len() > MAX_SYSTEM_INFO_LEN {
+                return Err(format!(
+                    "终端信息不能超过{}字节, 当前{}字节",
+                    MAX_SYSTEM_INFO_LEN,
+                    info.len()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 6组两位十六进制, 以:或-分隔
+fn is_mac_address(s: &str) -> bool {
+    let parts: Vec<&str> = s.split([':', '-']).collect();
+    parts.len() == 6
+        && parts
+            .iter()
+            .all(|p| p.len() == 2 && p.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// 模拟账户的初始资金和费率, 所有合约使用相同的费率
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct PaperOptions {
+    pub initial_balance: f64,
+    pub commission_ratio_by_money: f64,
+    pub commission_ratio_by_volume: f64,
+    pub margin_ratio: f64,
+    /// 回放的行情文件, 每行一个MarketDataRow的json, 为空时使用MdApi行情
+    pub tick_file: String,
+    pub replay_interval_ms: u64,
+    /// 模拟合约, 配置后回放行情不需要CTP账户提供合约信息
+    pub instruments: Vec<PaperInstrument>,
+}
+
+/// 模拟账户使用的合约信息
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct PaperInstrument {
+    pub exchange: String,
+    pub symbol: String,
+    pub name: String,
+    pub volume_multiple: i32,
+    pub price_tick: f64,
+}
+
+impl Default for PaperOptions {
+    fn default() -> Self {
+        Self {
+            initial_balance: 1_000_000.0,
+            commission_ratio_by_money: 0.0001,
+            commission_ratio_by_volume: 0.0,
+            margin_ratio: 0.1,
+            tick_file: String::new(),
+            replay_interval_ms: 500,
+            instruments: vec![],
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
@@ -1,8 +1,12 @@
 use crate::config::*;
-use crate::trader;
-use crate::trader::*;
+use crate::gateway::*;
+use crate::md::{MdSession, Subscriber};
+use crate::paper::PaperGateway;
+use crate::settlement::*;
+use crate::stop::*;
+use crate::trader::Trader;
 use itertools::Itertools;
-use log::error;
+use log::{error, info, warn};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
@@ -10,14 +14,39 @@ pub fn ta_key(broker_id: &str, account: &str) -> String {
     format!("{broker_id}:{account}")
 }
 
+/// 按经纪商配置的网关类型创建账户, 需要调用connect开始连接
+pub fn create_gateway(
+    conf: TradingAccount,
+    broker: TradingBroker,
+    es: tokio::sync::mpsc::Sender<CtaEvent>,
+    md: tokio::sync::mpsc::UnboundedSender<MarketDataRow>,
+) -> Result<Arc<Mutex<dyn Gateway>>, Error> {
+    match broker.gateway {
+        GatewayKind::Ctp => {
+            let trader: Arc<Mutex<dyn Gateway>> = Trader::init(conf, broker, es)?;
+            Ok(trader)
+        }
+        GatewayKind::Paper => {
+            let paper: Arc<Mutex<dyn Gateway>> = PaperGateway::init(conf, broker, es, md);
+            Ok(paper)
+        }
+    }
+}
+
 pub struct Database {
     pub conf: G3Config,
-    pub traders: std::collections::HashMap<String, Arc<Mutex<Trader>>>,
+    pub traders: std::collections::HashMap<String, Arc<Mutex<dyn Gateway>>>,
     pub cta_event_sender: tokio::sync::mpsc::Sender<CtaEvent>,
+    /// 每个经纪商一个行情连接, key为broker_id
+    pub md_sessions: std::collections::HashMap<String, Arc<Mutex<MdSession>>>,
+    pub md_sender: tokio::sync::mpsc::UnboundedSender<MarketDataRow>,
+    /// 本地止损止盈
+    pub stops: StopEngine,
 }
 
 impl Database {
-    pub async fn sync_traders(&mut self) {
+    /// 按配置创建新账户的连接, 返回配置中已删除的账户, 由调用方释放数据库锁后关闭
+    pub async fn sync_traders(&mut self) -> Vec<(String, Arc<Mutex<dyn Gateway>>)> {
         for ta in self.conf.accounts.iter().filter(|ta| {
             if ta.account.len() == 0 {
                 error!("[{}:{}] account不能为空", ta.broker_id, ta.account);
@@ -33,13 +62,17 @@ impl Database {
             if let Some(broker) = broker {
                 let key = format!("{}:{}", ta.broker_id, ta.account);
                 if !self.traders.contains_key(&key) {
-                    let trader = trader::Trader::init(
+                    let trader = create_gateway(
                         ta.clone(),
                         broker.clone(),
                         self.cta_event_sender.clone(),
+                        self.md_sender.clone(),
                     );
                     match trader {
                         Ok(trader) => {
+                            if let Err(e) = trader.lock().await.connect() {
+                                error!("{} connect {}", key, e);
+                            }
                             self.traders.insert(key, trader);
                         }
                         Err(e) => {
@@ -66,28 +99,457 @@ impl Database {
             })
             .map(|(k, _v)| k.clone())
             .collect::<Vec<_>>();
-        for k in delete_list.iter() {
-            if let Some(trader) = self.traders.remove(k) {
-                if let Some(sender) = trader.lock().await.exit_sender.take() {
-                    sender.send("exit".to_string()).unwrap();
+        let removed = delete_list
+            .into_iter()
+            .filter_map(|k| self.traders.remove(&k).map(|trader| (k, trader)))
+            .collect();
+        self.sync_md_sessions().await;
+        removed
+    }
+
+    /// CTP账户的配置, 前置组使用当前连接的前置组
+    async fn ctp_account(
+        &self,
+        broker_id: &str,
+        account: &str,
+    ) -> Result<(TradingAccount, TradingBroker), String> {
+        let mut ta = self
+            .conf
+            .accounts
+            .iter()
+            .find(|ta| ta.broker_id == broker_id && ta.account == account)
+            .cloned()
+            .ok_or("账户不存在".to_string())?;
+        let broker = self
+            .conf
+            .brokers
+            .iter()
+            .find(|b| b.broker_id == broker_id)
+            .cloned()
+            .ok_or("经纪商不存在".to_string())?;
+        if broker.gateway == GatewayKind::Paper {
+            return Err("模拟账户不支持该操作".to_string());
+        }
+        if let Some(t) = self.traders.get(&ta_key(broker_id, account)) {
+            ta.front_group = t.lock().await.conf().front_group.clone();
+        }
+        Ok((ta, broker))
+    }
+
+    /// 账户在后台任务中释放当前连接后按新的配置重新连接, 不持有数据库锁等待
+    async fn reconnect_trader(&self, ta: TradingAccount, rebuild: bool) -> Result<(), String> {
+        let trader = self
+            .traders
+            .get(&ta_key(&ta.broker_id, &ta.account))
+            .ok_or("账户未连接".to_string())?;
+        let r = trader
+            .lock()
+            .await
+            .reconnect(ta, rebuild)
+            .map_err(|e| e.to_string());
+        r
+    }
+
+    /// 断开后使用RESTART重新连接, 从当日开始重传私有流重建报单和成交, 不修改配置,
+    /// 报单引用和本交易日的会话保留, 重建后仍能区分本程序发出的报单
+    pub async fn rebuild_history(&self, broker_id: &str, account: &str) -> Result<(), String> {
+        let (mut ta, _) = self.ctp_account(broker_id, account).await?;
+        info!(
+            "{} 使用RESTART重建当日报单和成交",
+            ta_key(broker_id, account)
+        );
+        ta.private_resume = ResumeType::Restart;
+        self.reconnect_trader(ta, true).await
+    }
+
+    /// 切换交易前置组, front_group为None时切换到经纪商的下一个前置组, 返回新前置组的名称
+    pub async fn switch_front(
+        &self,
+        broker_id: &str,
+        account: &str,
+        front_group: Option<&str>,
+    ) -> Result<String, String> {
+        let (mut ta, broker) = self.ctp_account(broker_id, account).await?;
+        let next = match front_group {
+            Some(id) => broker.fronts.iter().find(|fg| fg.id == id),
+            None => {
+                let i = broker
+                    .fronts
+                    .iter()
+                    .position(|fg| fg.id == ta.front_group)
+                    .map_or(0, |i| (i + 1) % broker.fronts.len());
+                broker.fronts.get(i)
+            }
+        }
+        .cloned()
+        .ok_or("前置组不存在".to_string())?;
+        info!(
+            "{} 切换交易前置 {} -> {}({})",
+            ta_key(broker_id, account),
+            ta.front_group,
+            next.id,
+            next.name
+        );
+        ta.front_group = next.id;
+        self.reconnect_trader(ta, false).await?;
+        Ok(next.name)
+    }
+
+    /// 有交易账户的经纪商使用第一个账户的前置组建立行情连接, 模拟经纪商没有行情前置
+    async fn sync_md_sessions(&mut self) {
+        for b in self.conf.brokers.iter() {
+            if self.md_sessions.contains_key(&b.broker_id) || b.gateway == GatewayKind::Paper {
+                continue;
+            }
+            if let Some(ta) = self
+                .conf
+                .accounts
+                .iter()
+                .find(|ta| ta.broker_id == b.broker_id)
+            {
+                match MdSession::init(b, &ta.front_group, self.md_sender.clone()) {
+                    Ok(session) => {
+                        // 重启后从文件加载的止损止盈重新订阅行情
+                        session
+                            .lock()
+                            .await
+                            .subscribe(&self.stops.watched_keys(&b.broker_id), Subscriber::Stop);
+                        self.md_sessions.insert(b.broker_id.clone(), session);
+                    }
+                    Err(e) => {
+                        error!("[{}] Init md session {}", b.broker_id, e);
+                    }
                 }
             }
         }
+        let delete_list = self
+            .md_sessions
+            .keys()
+            .filter(|k| !self.conf.accounts.iter().any(|ta| ta.broker_id == **k))
+            .cloned()
+            .collect::<Vec<_>>();
+        for k in delete_list.iter() {
+            if let Some(session) = self.md_sessions.remove(k) {
+                session.lock().await.exit();
+            }
+        }
+    }
+
+    /// 程序退出前登出并释放所有交易和行情连接, 保证流文件完整写入
+    pub async fn shutdown_all(&mut self) {
+        info!("关闭{}个交易连接", self.traders.len());
+        shutdown_gateways(self.traders.drain().collect()).await;
+        futures::future::join_all(self.md_sessions.drain().map(|(k, session)| async move {
+            let done = session.lock().await.exit();
+            if let Some(done) = done {
+                if tokio::time::timeout(std::time::Duration::from_secs(5), done)
+                    .await
+                    .is_err()
+                {
+                    error!("[{}] 行情连接关闭超时", k);
+                }
+            }
+        }))
+        .await;
+        self.stops.save_if_dirty(StopEngine::default_path(), true);
     }
-    pub fn new(g3conf: G3Config, cta_es: tokio::sync::mpsc::Sender<CtaEvent>) -> Self {
+
+    pub fn new(
+        g3conf: G3Config,
+        cta_es: tokio::sync::mpsc::Sender<CtaEvent>,
+        md_es: tokio::sync::mpsc::UnboundedSender<MarketDataRow>,
+    ) -> Self {
         let db = Database {
             conf: g3conf,
             traders: std::collections::HashMap::new(),
             cta_event_sender: cta_es,
+            md_sessions: std::collections::HashMap::new(),
+            md_sender: md_es,
+            stops: StopEngine::load(StopEngine::default_path()),
         };
         db
     }
 
+    pub async fn subscribe_market_data(
+        &self,
+        broker_id: &str,
+        keys: &[String],
+        subscriber: Subscriber,
+    ) -> bool {
+        if let Some(session) = self.md_sessions.get(broker_id) {
+            session.lock().await.subscribe(keys, subscriber);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub async fn unsubscribe_market_data(
+        &self,
+        broker_id: &str,
+        keys: &[String],
+        subscriber: Subscriber,
+    ) -> bool {
+        if let Some(session) = self.md_sessions.get(broker_id) {
+            session.lock().await.unsubscribe(keys, subscriber);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// MdApi行情优先, 没有订阅的合约使用登录时查询的行情快照
+    pub async fn market_data_rows(&self) -> Vec<MarketDataRow> {
+        let mut v = std::collections::HashMap::new();
+        for (_, s) in self.md_sessions.iter() {
+            let s = s.lock().await;
+            for (k, md) in s.ticks.iter() {
+                v.entry(k.clone()).or_insert_with(|| md.clone());
+            }
+        }
+        for (_, t) in self.traders.iter() {
+            let t = t.lock().await;
+            for (k, md) in t.book().depth_snapshots.iter() {
+                v.entry(k.clone()).or_insert_with(|| md.clone());
+            }
+        }
+        v.into_values().collect()
+    }
+
+    /// 所有账户, 用于在不持有Database锁时逐个处理
+    pub fn gateways(&self) -> Vec<Arc<Mutex<dyn Gateway>>> {
+        self.traders.values().cloned().collect()
+    }
+
+    /// MdApi行情转发给所有账户, 模拟账户用于撮合, 调用时不能持有Database锁
+    pub async fn dispatch_market_data(gateways: &[Arc<Mutex<dyn Gateway>>], md: &MarketDataRow) {
+        let k = md.key();
+        let mut instrument = None;
+        for t in gateways.iter() {
+            if let Some(i) = t.lock().await.book().instruments.get(&k) {
+                instrument = Some(i.clone());
+                break;
+            }
+        }
+        for t in gateways.iter() {
+            let mut t = t.lock().await;
+            // 回放行情只发给回放的模拟账户, 不影响实盘账户估值
+            if md.replay_account.is_empty() || md.replay_account == t.key() {
+                t.on_market_data(md, instrument.as_ref());
+            }
+        }
+    }
+
+    /// 关联报单结束的止损止盈开始监控, 返回触发的止损止盈, 由调用方释放数据库锁后发出平仓单
+    pub async fn check_stops(&mut self, md: &MarketDataRow) -> Vec<StopTrigger> {
+        let mut changed = vec![];
+        for (b, a) in self.stops.pending_accounts(&md.key()) {
+            if let Some(t) = self.get_trader(&b, &a) {
+                let t = t.lock().await;
+                changed.extend(self.stops.on_orders(&b, &a, &t.book().orders));
+            }
+        }
+        let fired = self.stops.on_market_data(md);
+        changed.extend(fired.iter().cloned());
+        if changed.len() > 0 {
+            // 先保存Triggered状态, 平仓单发出前重启也不会重复触发
+            self.stops.save(StopEngine::default_path());
+            self.release_stop_keys(&changed).await;
+            for id in changed.iter() {
+                self.send_stop_event(id).await;
+            }
+        } else {
+            self.stops.save_if_dirty(StopEngine::default_path(), false);
+        }
+        fired
+            .iter()
+            .filter_map(|id| self.stops.triggers.get(id).cloned())
+            .collect()
+    }
+
+    /// 按触发时的行情发出平仓单, 只持有账户的锁, 返回平仓单的key和错误
+    pub async fn fire_stop(
+        gateway: Option<Arc<Mutex<dyn Gateway>>>,
+        t: &StopTrigger,
+        md: &MarketDataRow,
+    ) -> (Vec<String>, Vec<String>) {
+        let mut keys = vec![];
+        let mut errors = vec![];
+        match gateway {
+            None => errors.push("账户不存在".to_string()),
+            Some(g) => {
+                let mut g = g.lock().await;
+                let pk = format!("{}:{}:{}", t.exchange, t.symbol, t.posi_direction);
+                let inputs = match g.book().positions.get(&pk) {
+                    Some(p) => t.close_orders(p, t.close_price(md)),
+                    None => vec![],
+                };
+                if inputs.len() == 0 {
+                    errors.push("没有可平仓位".to_string());
+                }
+                for input in inputs.iter() {
+                    match g.insert_order(input) {
+                        Ok((key, _)) => keys.push(key),
+                        Err(e) => errors.push(e.to_string()),
+                    }
+                }
+            }
+        }
+        (keys, errors)
+    }
+
+    /// 记录发出的平仓单, 平仓单的结果由报单回报更新
+    pub async fn on_stop_fired(&mut self, id: &str, keys: Vec<String>, errors: Vec<String>) {
+        let (b, a) = match self.stops.triggers.get_mut(id) {
+            Some(t) => {
+                info!("止损止盈 id={} 平仓单{:?} 错误{:?}", id, keys, errors);
+                t.close_order_keys = keys;
+                if errors.len() > 0 {
+                    t.status = StopStatus::Failed;
+                    t.status_description = errors.join(";");
+                } else {
+                    t.status_description = "已发出平仓单".into();
+                }
+                (t.broker_id.clone(), t.account.clone())
+            }
+            None => return,
+        };
+        self.stops.save(StopEngine::default_path());
+        self.send_stop_event(id).await;
+        // 记录之前已经收到的拒单回报
+        self.check_stop_orders(&b, &a).await;
+    }
+
+    /// 报单回报后检查已触发的止损止盈的平仓单是否结束
+    pub async fn check_stop_orders(&mut self, broker_id: &str, account: &str) {
+        if !self.stops.has_close_orders(broker_id, account) {
+            return;
+        }
+        let changed = match self.get_trader(broker_id, account) {
+            Some(t) => {
+                let t = t.lock().await;
+                self.stops
+                    .on_close_orders(broker_id, account, &t.book().orders)
+            }
+            None => return,
+        };
+        if changed.len() > 0 {
+            self.stops.save(StopEngine::default_path());
+            for id in changed.iter() {
+                self.send_stop_event(id).await;
+            }
+        }
+    }
+
+    async fn send_stop_event(&self, id: &str) {
+        if let Some(t) = self.stops.triggers.get(id) {
+            let _ = self
+                .cta_event_sender
+                .send(CtaEvent::new("StopTrigger", &t.broker_id, &t.account, id))
+                .await;
+        }
+    }
+
+    /// 结束监控的止损止盈不再需要的行情, 行情表仍在使用时不会退订
+    async fn release_stop_keys(&self, ids: &[String]) {
+        for t in ids.iter().filter_map(|id| self.stops.triggers.get(id)) {
+            let k = t.market_key();
+            if !t.is_watching() && !self.stops.watched_keys(&t.broker_id).contains(&k) {
+                self.unsubscribe_market_data(&t.broker_id, &[k], Subscriber::Stop)
+                    .await;
+            }
+        }
+    }
+
+    pub async fn add_stop_trigger(&mut self, input: StopTriggerInput) -> Result<String, String> {
+        if self.get_trader(&input.broker_id, &input.account).is_none() {
+            return Err("账户不存在".into());
+        }
+        let id = self.stops.add(input)?;
+        self.stops.save(StopEngine::default_path());
+        if let Some(t) = self.stops.triggers.get(&id) {
+            let keys = vec![t.market_key()];
+            if !self
+                .subscribe_market_data(&t.broker_id, &keys, Subscriber::Stop)
+                .await
+            {
+                warn!("{} 没有行情连接, 止损止盈{}收不到行情", t.broker_id, id);
+            }
+        }
+        self.send_stop_event(&id).await;
+        Ok(id)
+    }
+
+    pub async fn cancel_stop_trigger(&mut self, id: &str) -> Result<(), String> {
+        self.stops.cancel(id)?;
+        self.stops.save(StopEngine::default_path());
+        self.release_stop_keys(&[id.to_string()]).await;
+        self.send_stop_event(id).await;
+        Ok(())
+    }
+
+    pub fn stop_trigger_rows(&self) -> Vec<StopTrigger> {
+        let mut v = self.stops.triggers.values().cloned().collect_vec();
+        v.sort_by(|a, b| a.id.cmp(&b.id));
+        v
+    }
+
+    pub async fn get_market_data_row(&self, key: &str) -> Option<MarketDataRow> {
+        for (_, s) in self.md_sessions.iter() {
+            if let Some(md) = s.lock().await.ticks.get(key) {
+                return Some(md.clone());
+            }
+        }
+        for (_, t) in self.traders.iter() {
+            if let Some(md) = t.lock().await.book().depth_snapshots.get(key) {
+                return Some(md.clone());
+            }
+        }
+        None
+    }
+
+    pub fn get_trader(&self, broker_id: &str, account: &str) -> Option<Arc<Mutex<dyn Gateway>>> {
+        self.traders.get(&ta_key(broker_id, account)).cloned()
+    }
+
+    pub async fn cancel_all(&self, filter: &CancelFilter) -> Vec<String> {
+        let mut v = vec![];
+        for (_, t) in self.traders.iter() {
+            let mut t = t.lock().await;
+            if filter.matches_account(&t.conf().broker_id, &t.conf().account) {
+                v.extend(t.cancel_all(filter));
+            }
+        }
+        v
+    }
+
+    pub async fn commission_rate_rows(&self) -> Vec<CommissionRateRow> {
+        let mut v = vec![];
+        for (_, t) in self.traders.iter() {
+            let t = t.lock().await;
+            for (_, r) in t.book().commission_rates.iter() {
+                v.push(r.clone());
+            }
+        }
+        v
+    }
+
+    pub async fn margin_rate_rows(&self) -> Vec<MarginRateRow> {
+        let mut v = vec![];
+        for (_, t) in self.traders.iter() {
+            let t = t.lock().await;
+            for (_, r) in t.book().margin_rates.iter() {
+                v.push(r.clone());
+            }
+        }
+        v
+    }
+
     pub async fn order_rows(&self) -> Vec<OrderRow> {
         let mut v = vec![];
         for (_, t) in self.traders.iter() {
             let t = t.lock().await;
-            for (_, o) in t.cta.orders.iter() {
+            for (_, o) in t.book().orders.iter() {
                 v.push(o.clone());
             }
         }
@@ -101,17 +563,158 @@ impl Database {
         key: &str,
     ) -> Option<OrderRow> {
         if let Some(t) = self.traders.get(&ta_key(broker_id, account)) {
-            t.lock().await.cta.orders.get(key).cloned()
+            t.lock().await.book().orders.get(key).cloned()
+        } else {
+            None
+        }
+    }
+
+    pub async fn parked_order_rows(&self) -> Vec<ParkedOrderRow> {
+        let mut v = vec![];
+        for (_, t) in self.traders.iter() {
+            let t = t.lock().await;
+            for (_, o) in t.book().parked_orders.iter() {
+                v.push(o.clone());
+            }
+        }
+        v
+    }
+
+    pub async fn get_parked_order_row(
+        &self,
+        broker_id: &str,
+        account: &str,
+        key: &str,
+    ) -> Option<ParkedOrderRow> {
+        if let Some(t) = self.traders.get(&ta_key(broker_id, account)) {
+            t.lock().await.book().parked_orders.get(key).cloned()
+        } else {
+            None
+        }
+    }
+
+    pub async fn bank_account_rows(&self) -> Vec<BankAccountRow> {
+        let mut v = vec![];
+        for (_, t) in self.traders.iter() {
+            let t = t.lock().await;
+            for (_, b) in t.book().bank_accounts.iter() {
+                v.push(b.clone());
+            }
+        }
+        v
+    }
+
+    pub async fn transfer_rows(&self) -> Vec<TransferRow> {
+        let mut v = vec![];
+        for (_, t) in self.traders.iter() {
+            let t = t.lock().await;
+            for (_, r) in t.book().transfers.iter() {
+                v.push(r.clone());
+            }
+        }
+        v
+    }
+
+    pub async fn settlement_rows(&self) -> Vec<SettlementRow> {
+        let mut v = vec![];
+        for (_, t) in self.traders.iter() {
+            let t = t.lock().await;
+            for (_, r) in t.book().settlements.iter() {
+                v.push(r.clone());
+            }
+        }
+        v
+    }
+
+    pub async fn get_settlement_row(
+        &self,
+        broker_id: &str,
+        account: &str,
+        trading_day: &str,
+    ) -> Option<SettlementRow> {
+        if let Some(t) = self.traders.get(&ta_key(broker_id, account)) {
+            t.lock().await.book().settlements.get(trading_day).cloned()
         } else {
             None
         }
     }
 
+    /// 所有账户收到的交易状态, 同一交易所品种只保留一条
+    pub async fn instrument_status_rows(&self) -> Vec<InstrumentStatusRow> {
+        let mut m = std::collections::HashMap::new();
+        for (_, t) in self.traders.iter() {
+            let t = t.lock().await;
+            for (k, r) in t.book().instrument_status.iter() {
+                m.insert(k.clone(), r.clone());
+            }
+        }
+        m.into_values().collect()
+    }
+
+    /// 合约当前的交易状态, 取第一个收到该状态的账户
+    pub async fn get_instrument_status(
+        &self,
+        exchange: &str,
+        symbol: &str,
+    ) -> Option<InstrumentStatusRow> {
+        for (_, t) in self.traders.iter() {
+            if let Some(r) = t.lock().await.book().trading_status(exchange, symbol) {
+                return Some(r.clone());
+            }
+        }
+        None
+    }
+
+    /// 账户最近的错误回报, 最新的在前
+    pub async fn error_rows(&self, broker_id: &str, account: &str) -> Vec<CtpError> {
+        if let Some(t) = self.traders.get(&ta_key(broker_id, account)) {
+            t.lock().await.book().errors.iter().rev().cloned().collect()
+        } else {
+            vec![]
+        }
+    }
+
+    /// 解析保存的结算单, 用于日报
+    pub async fn settlement_statement(
+        &self,
+        broker_id: &str,
+        account: &str,
+        trading_day: &str,
+    ) -> Option<SettlementStatement> {
+        self.get_settlement_row(broker_id, account, trading_day)
+            .await
+            .map(|r| SettlementStatement::parse(&r.content))
+    }
+
+    /// 结算单与账户当前的成交和持仓核对
+    pub async fn reconcile_settlement(
+        &self,
+        broker_id: &str,
+        account: &str,
+        trading_day: &str,
+    ) -> Result<Vec<ReconcileDiff>, String> {
+        let t = self
+            .get_trader(broker_id, account)
+            .ok_or("账户不存在".to_string())?;
+        let t = t.lock().await;
+        let statement = t
+            .book()
+            .settlements
+            .get(trading_day)
+            .map(|r| SettlementStatement::parse(&r.content))
+            .ok_or("结算单不存在".to_string())?;
+        let trades = t.book().trades.values().cloned().collect_vec();
+        let positions = t.book().positions.values().cloned().collect_vec();
+        let mut diffs = statement.reconcile_trades(&trades);
+        diffs.extend(statement.reconcile_positions(&positions));
+        Ok(diffs)
+    }
+
     pub async fn trade_rows(&self) -> Vec<TradeRow> {
         let mut v = vec![];
         for (_, t) in self.traders.iter() {
             let t = t.lock().await;
-            for (_, t) in t.cta.trades.iter() {
+            for (_, t) in t.book().trades.iter() {
                 v.push(t.clone());
             }
         }
@@ -124,7 +727,7 @@ impl Database {
         key: &str,
     ) -> Option<TradeRow> {
         if let Some(t) = self.traders.get(&ta_key(broker_id, account)) {
-            t.lock().await.cta.trades.get(key).cloned()
+            t.lock().await.book().trades.get(key).cloned()
         } else {
             None
         }
@@ -134,7 +737,7 @@ impl Database {
         let mut v = vec![];
         for (_, t) in self.traders.iter() {
             let t = t.lock().await;
-            for (_, t) in t.cta.positions.iter() {
+            for (_, t) in t.book().positions.iter() {
                 v.push(t.clone());
             }
         }
@@ -147,7 +750,7 @@ impl Database {
         key: &str,
     ) -> Option<PositionRow> {
         if let Some(t) = self.traders.get(&ta_key(broker_id, account)) {
-            t.lock().await.cta.positions.get(key).cloned()
+            t.lock().await.book().positions.get(key).cloned()
         } else {
             None
         }
@@ -157,7 +760,7 @@ impl Database {
         let mut v = vec![];
         for (_, t) in self.traders.iter() {
             let t = t.lock().await;
-            for (_, t) in t.cta.position_details.iter() {
+            for (_, t) in t.book().position_details.iter() {
                 v.push(t.clone());
             }
         }
@@ -170,7 +773,7 @@ impl Database {
         key: &str,
     ) -> Option<PositionDetailRow> {
         if let Some(t) = self.traders.get(&ta_key(broker_id, account)) {
-            t.lock().await.cta.position_details.get(key).cloned()
+            t.lock().await.book().position_details.get(key).cloned()
         } else {
             None
         }
@@ -180,7 +783,7 @@ impl Database {
         let mut v = vec![];
         for (_, t) in self.traders.iter() {
             let t = t.lock().await;
-            for (_, t) in t.cta.instruments.iter() {
+            for (_, t) in t.book().instruments.iter() {
                 v.push(t.clone());
             }
             break;
@@ -194,12 +797,37 @@ impl Database {
         key: &str,
     ) -> Option<InstrumentRow> {
         if let Some(t) = self.traders.get(&ta_key(broker_id, account)) {
-            t.lock().await.cta.instruments.get(key).cloned()
+            t.lock().await.book().instruments.get(key).cloned()
         } else {
             None
         }
     }
 
+    /// 按最新行情计算的各账户盈亏和汇总
+    pub async fn pnl_summary(&self) -> PnlSummary {
+        let mut summary = PnlSummary::default();
+        for (_, t) in self.traders.iter() {
+            let t = t.lock().await;
+            let book = t.book();
+            summary.accounts.push(AccountPnlRow {
+                broker_id: t.conf().broker_id.clone(),
+                account: t.conf().account.clone(),
+                float_profit: book.float_profit(),
+                position_profit: book.funds.position_profit,
+                close_profit: book.funds.close_profit,
+                commission: book.funds.commission,
+            });
+        }
+        summary
+            .accounts
+            .sort_by(|a, b| (&a.broker_id, &a.account).cmp(&(&b.broker_id, &b.account)));
+        summary.float_profit = summary.accounts.iter().map(|a| a.float_profit).sum();
+        summary.position_profit = summary.accounts.iter().map(|a| a.position_profit).sum();
+        summary.close_profit = summary.accounts.iter().map(|a| a.close_profit).sum();
+        summary.commission = summary.accounts.iter().map(|a| a.commission).sum();
+        summary
+    }
+
     pub async fn account_rows(&self) -> Vec<TradingAccountRow> {
         let mut v = self
             .conf
@@ -211,6 +839,7 @@ impl Database {
                 row.account = a.account.clone();
                 row.password = a.password.clone();
                 row.front_group = a.front_group.clone();
+                row.settlement_confirm = a.settlement_confirm.clone();
                 row
             })
             .collect_vec();
@@ -219,21 +848,28 @@ impl Database {
                 let trader = trader.lock().await;
                 row.status = trader.status();
                 row.status_description = trader.status_description();
-                row.equity = trader.cta.ta.Balance;
-                row.margin = trader.cta.ta.CurrMargin;
-                row.closed_profit = trader.cta.ta.CloseProfit;
-                row.position_profit = trader.cta.ta.PositionProfit;
-                row.available = trader.cta.ta.Available;
-                row.frozen_margin = trader.cta.ta.FrozenMargin;
-                row.frozen_commission = trader.cta.ta.FrozenCommission;
+                let book = trader.book();
+                row.reconnect_count = book.reconnect_count;
+                row.equity = book.funds.balance;
+                row.margin = book.funds.curr_margin;
+                row.closed_profit = book.funds.close_profit;
+                row.position_profit = book.funds.position_profit;
+                row.available = book.funds.available;
+                row.frozen_margin = book.funds.frozen_margin;
+                row.frozen_commission = book.funds.frozen_commission;
+                row.float_profit = book.float_profit();
+                row.settlement_confirmed = book.settlement_confirmed;
+                row.login = book.login.clone();
                 if let Some(b) = self
                     .conf
                     .brokers
                     .iter()
-                    .find(|b| b.broker_id == trader.conf.broker_id)
+                    .find(|b| b.broker_id == trader.conf().broker_id)
                 {
                     row.broker_name = b.name.clone();
-                    if let Some(fg) = b.fronts.iter().find(|fg| fg.id == row.front_group) {
+                    // 故障切换后显示当前连接的前置组
+                    let active = &trader.conf().front_group;
+                    if let Some(fg) = b.fronts.iter().find(|fg| fg.id == *active) {
                         row.front_group_name = fg.name.clone();
                     }
                 }
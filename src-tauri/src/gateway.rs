@@ -0,0 +1,594 @@
+use crate::config::*;
+use crate::db::ta_key;
+use bincode::{Decode, Encode};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::sync::oneshot;
+use tokio::sync::Mutex;
+
+/// 经纪商使用的交易网关
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum GatewayKind {
+    Ctp,
+    /// 本地模拟撮合, 不连接经纪商
+    Paper,
+}
+
+impl Default for GatewayKind {
+    fn default() -> Self {
+        GatewayKind::Ctp
+    }
+}
+
+#[derive(Decode, Encode, Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum CtaStatus {
+    UnKown,
+    Connected,
+    Disconnected,
+    AuthenticateFailed,
+    AuthenticateSucceeded,
+    LoginFailed,
+    LoginSucceeded,
+    LoginCompleted,
+}
+
+impl Default for CtaStatus {
+    fn default() -> Self {
+        CtaStatus::UnKown
+    }
+}
+
+#[derive(Decode, Encode, Debug, Clone, Serialize, Deserialize)]
+pub struct CtaEvent {
+    tp: String,
+    b: String,
+    a: String,
+    key: String,
+    /// tp为CtpError时的错误详情
+    error: Option<CtpError>,
+}
+
+impl CtaEvent {
+    pub fn new(tp: &str, b: &str, a: &str, key: &str) -> Self {
+        CtaEvent {
+            tp: tp.to_string(),
+            b: b.to_string(),
+            a: a.to_string(),
+            key: key.to_string(),
+            error: None,
+        }
+    }
+
+    pub fn with_error(mut self, e: CtpError) -> Self {
+        self.error = Some(e);
+        self
+    }
+
+    pub fn tp(&self) -> &str {
+        &self.tp
+    }
+
+    pub fn broker_id(&self) -> &str {
+        &self.b
+    }
+
+    pub fn account(&self) -> &str {
+        &self.a
+    }
+}
+
+/// 错误分类, CTP由ErrorID映射
+#[derive(Decode, Encode, Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ErrorCategory {
+    /// 认证, 登录, 会话
+    Auth,
+    /// 密码错误或需要修改密码
+    Password,
+    /// 没有交易权限或只能平仓
+    Permission,
+    /// 资金不足
+    Funds,
+    /// 平仓量超过持仓
+    Position,
+    /// 报单, 撤单的参数或状态错误
+    Order,
+    /// 合约不在交易状态
+    Market,
+    /// 超过交易所或CTP的流控
+    FlowControl,
+    /// 结算单未确认
+    Settlement,
+    Other,
+}
+
+impl Default for ErrorCategory {
+    fn default() -> Self {
+        ErrorCategory::Other
+    }
+}
+
+/// 解码后的网关错误回报
+#[derive(Decode, Encode, Debug, Clone, Serialize, Deserialize, Default)]
+pub struct CtpError {
+    pub broker_id: String,
+    pub account: String,
+    /// 产生错误的SPI回调或查询
+    pub source: String,
+    pub error_id: i32,
+    pub error_msg: String,
+    pub category: ErrorCategory,
+    pub request_id: i32,
+    /// 关联的报单, 预埋单或转账的key, 没有时为空
+    pub key: String,
+    /// unix时间戳(秒)
+    pub ts: u64,
+}
+
+pub fn now_ts() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// 成交和报单使用的买卖方向/开平/持仓方向/报单和预埋单状态, 取值与CTP相同
+pub const DIRECTION_BUY: i32 = b'0' as i32;
+pub const DIRECTION_SELL: i32 = b'1' as i32;
+pub const OFFSET_OPEN: i32 = b'0' as i32;
+pub const OFFSET_CLOSE: i32 = b'1' as i32;
+pub const OFFSET_CLOSE_TODAY: i32 = b'3' as i32;
+pub const OFFSET_CLOSE_YESTERDAY: i32 = b'4' as i32;
+pub const POSI_LONG: i32 = b'2' as i32;
+pub const POSI_SHORT: i32 = b'3' as i32;
+pub const ORDER_ALL_TRADED: i32 = b'0' as i32;
+pub const ORDER_PART_TRADED_QUEUEING: i32 = b'1' as i32;
+pub const ORDER_NO_TRADE_QUEUEING: i32 = b'3' as i32;
+pub const ORDER_CANCELED: i32 = b'5' as i32;
+pub const ORDER_NOT_TOUCHED: i32 = b'b' as i32;
+pub const ORDER_TOUCHED: i32 = b'c' as i32;
+pub const PARKED_NOT_SEND: i32 = b'1' as i32;
+pub const PARKED_SEND: i32 = b'2' as i32;
+pub const PARKED_DELETED: i32 = b'3' as i32;
+
+#[derive(Debug, derive_more::Display)]
+pub enum Error {
+    FrontGroupNotFound,
+    #[display(fmt = "账户未登录完成")]
+    NotLoggedIn,
+    #[display(fmt = "报单参数错误: {}", _0)]
+    InvalidOrder(String),
+    #[display(fmt = "请求发送失败 result={}", _0)]
+    RequestFailed(i32),
+    #[display(fmt = "报单不存在: {}", _0)]
+    OrderNotFound(String),
+    #[display(fmt = "报单已完成, 不能撤单: {}", _0)]
+    OrderNotActive(String),
+    #[display(fmt = "交易连接已断开")]
+    Disconnected,
+    #[display(fmt = "查询超时")]
+    QueryTimeout,
+    #[display(fmt = "查询失败 ErrorID={} ErrorMsg={}", error_id, msg)]
+    QueryFailed {
+        error_id: i32,
+        msg: String,
+    },
+    #[display(fmt = "费率查询中, 请稍后重试: {}", _0)]
+    RateNotReady(String),
+    #[display(fmt = "撤单失败 ErrorID={} ErrorMsg={}", error_id, msg)]
+    OrderActionFailed {
+        error_id: i32,
+        msg: String,
+    },
+    #[display(fmt = "修改密码失败 ErrorID={} ErrorMsg={}", error_id, msg)]
+    PasswordUpdateFailed {
+        error_id: i32,
+        msg: String,
+    },
+    #[display(fmt = "转账参数错误: {}", _0)]
+    InvalidTransfer(String),
+    #[display(fmt = "合约不在交易时段: {}", _0)]
+    NotTrading(String),
+    #[display(fmt = "结算单未确认")]
+    SettlementNotConfirmed,
+    #[display(fmt = "经纪商配置错误: {}", _0)]
+    InvalidBroker(String),
+    #[display(fmt = "没有合约信息: {}", _0)]
+    InstrumentNotFound(String),
+    #[display(fmt = "网关不支持: {}", _0)]
+    Unsupported(String),
+}
+
+/// 每个账户保留的错误回报条数
+const MAX_ERROR_HISTORY: usize = 200;
+
+/// 查询结果, 数据更新到AccountBook后通知
+pub type QueryReply = oneshot::Receiver<Result<(), Error>>;
+
+/// 网关对外的查询
+#[derive(Debug, Clone, PartialEq)]
+pub enum GatewayQuery {
+    Account,
+    Position,
+    Order,
+    Trade,
+    /// 预埋单, 条件单随报单查询
+    ParkedOrder,
+    /// 合约key为exchange:symbol
+    DepthMarketData(Vec<String>),
+    /// 指定交易日的结算单, 为空时查询上一交易日
+    Settlement(String),
+    /// 签约银行
+    BankAccount,
+    /// 银期转账流水
+    Transfer,
+}
+
+/// 网关推送的数据, 由AccountBook::apply更新到内存表
+#[derive(Debug, Clone)]
+pub enum GatewayEvent {
+    Account(AccountFunds),
+    Order(OrderRow),
+    Trade(TradeRow),
+    Position(PositionRow),
+    PositionDetail(PositionDetailRow),
+    Instrument(InstrumentRow),
+    InstrumentStatus(InstrumentStatusRow),
+    DepthMarketData(MarketDataRow),
+    CommissionRate(CommissionRateRow),
+    MarginRate(MarginRateRow),
+    ParkedOrder(ParkedOrderRow),
+    Settlement(SettlementRow),
+    BankAccount(BankAccountRow),
+    Transfer(TransferRow),
+}
+
+/// 交易日切换时归档的上一交易日数据
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct TradingDayArchive {
+    pub trading_day: String,
+    pub orders: Vec<OrderRow>,
+    pub trades: Vec<TradeRow>,
+    pub funds: AccountFunds,
+}
+
+/// 一个交易账户的内存数据, 与具体网关无关
+#[derive(Debug, Clone, Default)]
+pub struct AccountBook {
+    pub funds: AccountFunds,
+    pub status: CtaStatus,
+    pub status_description: String,
+    /// 登录或资金查询返回的交易日
+    pub trading_day: String,
+    pub orders: HashMap<String, OrderRow>,
+    pub trades: HashMap<String, TradeRow>,
+    pub positions: HashMap<String, PositionRow>,
+    pub position_details: HashMap<String, PositionDetailRow>,
+    pub instruments: HashMap<String, InstrumentRow>,
+    /// 交易状态, key为exchange:product
+    pub instrument_status: HashMap<String, InstrumentStatusRow>,
+    /// 查询得到的行情快照, 没有MdApi行情时用于结算价/涨跌停价等
+    pub depth_snapshots: HashMap<String, MarketDataRow>,
+    /// 手续费率和保证金率, key为合约或品种
+    pub commission_rates: HashMap<String, CommissionRateRow>,
+    pub margin_rates: HashMap<String, MarginRateRow>,
+    /// 预埋单和条件单
+    pub parked_orders: HashMap<String, ParkedOrderRow>,
+    /// 结算单, key为交易日
+    pub settlements: HashMap<String, SettlementRow>,
+    /// 当前交易日是否已确认结算单
+    pub settlement_confirmed: bool,
+    /// 签约银行, key为bank_id
+    pub bank_accounts: HashMap<String, BankAccountRow>,
+    pub transfers: HashMap<String, TransferRow>,
+    /// 最近的错误回报, 按时间顺序
+    pub errors: VecDeque<CtpError>,
+    pub front_id: i32,
+    pub session_id: i32,
+    /// 最近一次分配的报单引用, 只增不减, 重连后从MaxOrderRef和已用值中较大的继续
+    pub order_ref: i32,
+    /// 最近一次登录的会话信息
+    pub login: LoginInfo,
+    /// 本交易日登录过的(front_id, session_id), 用于区分本程序发出的报单
+    pub sessions: Vec<(i32, i32)>,
+    /// 首次登录查询是否已完成, 重连后只重新查询会变化的数据
+    pub initialized: bool,
+    pub reconnect_count: u32,
+    /// 登录成功前连续断线和认证失败的次数, 登录成功后清零
+    pub front_failures: u32,
+    pub login_failures: u32,
+    /// unix时间戳(秒)
+    pub connected_at: u64,
+    pub disconnected_at: u64,
+}
+
+impl AccountBook {
+    /// 更新内存表并返回对应行的key, 重复的成交和过期的报单状态返回None
+    pub fn apply(&mut self, e: GatewayEvent) -> Option<String> {
+        match e {
+            GatewayEvent::Account(funds) => {
+                self.funds = funds;
+                Some(String::new())
+            }
+            GatewayEvent::Order(o) => {
+                let k = o.key();
+                if matches!(self.orders.get(&k), Some(current) if o.is_stale(current)) {
+                    return None;
+                }
+                self.orders.insert(k.clone(), o);
+                Some(k)
+            }
+            GatewayEvent::Trade(t) => {
+                let k = t.key();
+                if self.trades.contains_key(&k) {
+                    return None;
+                }
+                self.trades.insert(k.clone(), t);
+                Some(k)
+            }
+            GatewayEvent::Position(p) => {
+                let k = p.key();
+                self.positions.insert(k.clone(), p);
+                Some(k)
+            }
+            GatewayEvent::PositionDetail(p) => {
+                let k = p.key();
+                self.position_details.insert(k.clone(), p);
+                Some(k)
+            }
+            GatewayEvent::Instrument(i) => {
+                let k = i.key();
+                self.instruments.insert(k.clone(), i);
+                Some(k)
+            }
+            GatewayEvent::InstrumentStatus(r) => {
+                let k = r.key();
+                self.instrument_status.insert(k.clone(), r);
+                Some(k)
+            }
+            GatewayEvent::DepthMarketData(md) => {
+                let k = md.key();
+                self.depth_snapshots.insert(k.clone(), md);
+                Some(k)
+            }
+            GatewayEvent::CommissionRate(r) => {
+                let k = r.key();
+                self.commission_rates.insert(k.clone(), r);
+                Some(k)
+            }
+            GatewayEvent::MarginRate(r) => {
+                let k = r.key();
+                self.margin_rates.insert(k.clone(), r);
+                Some(k)
+            }
+            GatewayEvent::ParkedOrder(o) => {
+                let k = o.key();
+                self.parked_orders.insert(k.clone(), o);
+                Some(k)
+            }
+            GatewayEvent::Settlement(r) => {
+                let k = r.key();
+                self.settlements.insert(k.clone(), r);
+                Some(k)
+            }
+            GatewayEvent::BankAccount(r) => {
+                let k = r.key();
+                self.bank_accounts.insert(k.clone(), r);
+                Some(k)
+            }
+            GatewayEvent::Transfer(r) => {
+                let k = r.key();
+                self.transfers.insert(k.clone(), r);
+                Some(k)
+            }
+        }
+    }
+
+    /// 切换到新的交易日, 清空上一交易日的报单, 成交和持仓等内存表, 返回需要归档的数据
+    /// 持仓的今昨仓拆分已经过期, 需要重新查询
+    pub fn rollover(&mut self, trading_day: &str) -> TradingDayArchive {
+        let archive = TradingDayArchive {
+            trading_day: std::mem::replace(&mut self.trading_day, trading_day.to_string()),
+            orders: std::mem::take(&mut self.orders).into_values().collect(),
+            trades: std::mem::take(&mut self.trades).into_values().collect(),
+            funds: self.funds.clone(),
+        };
+        self.positions.clear();
+        self.position_details.clear();
+        self.parked_orders.clear();
+        self.depth_snapshots.clear();
+        self.settlement_confirmed = false;
+        self.sessions
+            .retain(|s| *s == (self.front_id, self.session_id));
+        // 新交易日可能有新上市的合约, 重新执行完整的登录查询
+        self.initialized = false;
+        archive
+    }
+
+    /// 重传私有流之前清空当日报单/成交/预埋单和持仓, 报单引用和会话不变
+    pub fn clear_history(&mut self) {
+        self.orders.clear();
+        self.trades.clear();
+        self.parked_orders.clear();
+        self.positions.clear();
+        self.position_details.clear();
+        // 持仓由重新登录后的完整登录查询重建
+        self.initialized = false;
+    }
+
+    /// 记录登录的会话, 报单引用从MaxOrderRef之后继续分配
+    pub fn on_login(&mut self, login: LoginInfo) {
+        self.front_id = login.front_id;
+        self.session_id = login.session_id;
+        self.order_ref = self.order_ref.max(login.max_order_ref.parse().unwrap_or(0));
+        if !self.sessions.contains(&(login.front_id, login.session_id)) {
+            self.sessions.push((login.front_id, login.session_id));
+        }
+        self.login = login;
+    }
+
+    /// 报单是否由本程序在本交易日的某个会话发出
+    pub fn is_own_order(&self, o: &OrderRow) -> bool {
+        self.sessions.contains(&(o.front_id, o.session_id))
+    }
+
+    /// 合约当前的交易状态, 先按合约查找再按品种查找
+    pub fn trading_status(&self, exchange: &str, symbol: &str) -> Option<&InstrumentStatusRow> {
+        let k = format!("{exchange}:{symbol}");
+        self.instrument_status.get(&k).or_else(|| {
+            let product = &self.instruments.get(&k)?.product_id;
+            self.instrument_status.get(&format!("{exchange}:{product}"))
+        })
+    }
+
+    /// 记录错误回报, 超过MAX_ERROR_HISTORY时丢弃最早的
+    pub fn push_error(&mut self, e: CtpError) {
+        if self.errors.len() >= MAX_ERROR_HISTORY {
+            self.errors.pop_front();
+        }
+        self.errors.push_back(e);
+    }
+}
+
+/// 交易网关, Database只通过这个接口访问账户
+pub trait Gateway: Send {
+    fn conf(&self) -> &TradingAccount;
+
+    fn book(&self) -> &AccountBook;
+
+    /// 连接交易前置, 连接成功后自动认证登录
+    fn connect(&mut self) -> Result<(), Error>;
+
+    /// 手动重新认证登录
+    fn login(&mut self) -> Result<(), Error>;
+
+    fn query(&mut self, q: GatewayQuery) -> Result<Vec<QueryReply>, Error>;
+
+    /// 发送报单, 返回与OrderRow::key一致的key以及报单结果的receiver
+    fn insert_order(
+        &mut self,
+        input: &OrderInput,
+    ) -> Result<(String, oneshot::Receiver<Result<(), String>>), Error>;
+
+    /// 撤单, key与OrderRow::key一致, 撤单结果通过返回的receiver通知
+    fn cancel_order(&mut self, key: &str) -> Result<oneshot::Receiver<Result<(), Error>>, Error>;
+
+    /// 发送预埋单, 经纪商返回的ParkedOrderID通过receiver通知
+    fn insert_parked_order(
+        &mut self,
+        _input: &OrderInput,
+    ) -> Result<oneshot::Receiver<Result<String, String>>, Error> {
+        Err(Error::Unsupported("预埋单".into()))
+    }
+
+    /// 删除未发送的预埋单或未触发的条件单, key与ParkedOrderRow::key一致
+    fn remove_parked_order(
+        &mut self,
+        _key: &str,
+    ) -> Result<oneshot::Receiver<Result<(), Error>>, Error> {
+        Err(Error::Unsupported("预埋单".into()))
+    }
+
+    /// 手动确认结算单, 确认结果通过receiver通知
+    fn confirm_settlement(&mut self) -> Result<oneshot::Receiver<Result<(), Error>>, Error> {
+        Err(Error::Unsupported("结算单确认".into()))
+    }
+
+    /// 修改交易密码, 首次登录要求修改密码时可在登录失败后调用
+    fn update_password(
+        &mut self,
+        _old_password: &str,
+        _new_password: &str,
+    ) -> Result<oneshot::Receiver<Result<(), Error>>, Error> {
+        Err(Error::Unsupported("修改密码".into()))
+    }
+
+    /// 修改资金密码
+    fn update_money_password(
+        &mut self,
+        _old_password: &str,
+        _new_password: &str,
+    ) -> Result<oneshot::Receiver<Result<(), Error>>, Error> {
+        Err(Error::Unsupported("修改密码".into()))
+    }
+
+    /// 银期转账, 结果通过事件通知, 返回转账记录的key
+    fn transfer(&mut self, _input: &TransferInput) -> Result<String, Error> {
+        Err(Error::Unsupported("银期转账".into()))
+    }
+
+    /// 查询银行余额, 结果更新到签约银行并通过事件通知
+    fn query_bank_balance(&mut self, _bank_id: &str, _bank_password: &str) -> Result<(), Error> {
+        Err(Error::Unsupported("银期转账".into()))
+    }
+
+    /// 预估报单占用的保证金和手续费
+    fn estimate_order_cost(&mut self, input: &OrderInput) -> Result<OrderCost, Error>;
+
+    /// 释放当前连接后按新配置重新连接, 报单引用和登录过的会话保留,
+    /// rebuild为true时清空当日报单和成交, 由私有流重传重建
+    fn reconnect(&mut self, _conf: TradingAccount, _rebuild: bool) -> Result<(), Error> {
+        Err(Error::Unsupported("切换前置".into()))
+    }
+
+    /// 开始断开连接并释放资源, 返回的receiver在后台任务结束后收到通知
+    fn exit(&mut self) -> Option<oneshot::Receiver<()>>;
+
+    /// MdApi推送的行情, 用于持仓估值, instrument为其它账户查询到的合约信息
+    fn on_market_data(&mut self, _md: &MarketDataRow, _instrument: Option<&InstrumentRow>) {}
+
+    fn key(&self) -> String {
+        ta_key(&self.conf().broker_id, &self.conf().account)
+    }
+
+    fn status(&self) -> CtaStatus {
+        self.book().status.clone()
+    }
+
+    fn status_description(&self) -> String {
+        self.book().status_description.clone()
+    }
+
+    /// 按条件撤销所有未完成的报单, 返回已发出撤单请求的key
+    fn cancel_all(&mut self, filter: &CancelFilter) -> Vec<String> {
+        let keys = self
+            .book()
+            .orders
+            .values()
+            .filter(|o| o.is_active() && filter.matches(o))
+            .map(|o| o.key())
+            .collect::<Vec<_>>();
+        keys.into_iter()
+            .filter(|k| match self.cancel_order(k) {
+                Ok(_) => true,
+                Err(e) => {
+                    warn!("{} 撤单失败 key={} {}", self.key(), k, e);
+                    false
+                }
+            })
+            .collect()
+    }
+}
+
+/// 等待关闭的最长时间, 包括登出和释放API
+const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// 关闭账户连接并等待后台任务结束, 不能在持有该账户锁时调用
+pub async fn shutdown_gateway(key: &str, gateway: Arc<Mutex<dyn Gateway>>) {
+    let done = gateway.lock().await.exit();
+    if let Some(done) = done {
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, done).await.is_err() {
+            warn!("{} 关闭超时", key);
+        }
+    }
+}
+
+/// 并发关闭多个账户连接, 不能在持有数据库锁时调用
+pub async fn shutdown_gateways(gateways: Vec<(String, Arc<Mutex<dyn Gateway>>)>) {
+    futures::future::join_all(
+        gateways
+            .into_iter()
+            .map(|(k, gateway)| async move { shutdown_gateway(&k, gateway).await }),
+    )
+    .await;
+}
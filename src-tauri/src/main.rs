@@ -16,6 +16,13 @@ mod command;
 use command::*;
 mod db;
 use db::*;
+mod gateway;
+mod md;
+mod paper;
+mod position;
+mod settlement;
+mod stop;
+mod terminal;
 use tauri::{CustomMenuItem, Manager, Menu, Submenu};
 
 struct FrontLogWriter {
@@ -70,14 +77,16 @@ async fn main() {
     check_make_dir(".cache");
     let g3conf = G3Config::load(G3Config::default_path()).unwrap_or(G3Config::default());
     let (cta_es, mut cta_er) = tokio::sync::mpsc::channel(1000);
-    let db = Database::new(g3conf, cta_es);
+    let (md_es, mut md_er) = tokio::sync::mpsc::unbounded_channel();
+    let db = Database::new(g3conf, cta_es, md_es);
     let state = StateTpye::new(db);
     // here `"quit".to_string()` defines the menu item id, and the second parameter is the menu item label.
     let submenu = Submenu::new(
         "账号管理",
         Menu::new()
             .add_item(CustomMenuItem::new("broker-table".to_string(), "经纪商"))
-            .add_item(CustomMenuItem::new("account-table".to_string(), "交易账户")),
+            .add_item(CustomMenuItem::new("account-table".to_string(), "交易账户"))
+            .add_item(CustomMenuItem::new("settlement".to_string(), "结算单")),
     );
     let submenu2 = Submenu::new(
         "交易",
@@ -108,6 +117,7 @@ async fn main() {
             "File",
             Menu::new().add_item(CustomMenuItem::new("copy", "Copy")),
         ));
+    let mut shutting_down = false;
     tauri::Builder::default()
         .manage(state)
         .menu(menu)
@@ -133,12 +143,61 @@ async fn main() {
                 }
             });
             let main_window = app.get_window("main").unwrap();
+            let app_handle = app.handle();
             tokio::spawn(async move {
                 while let Some(e) = cta_er.recv().await {
+                    let tp = e.tp();
+                    if tp == "OnRtnOrder" || tp == "OnRspOrderInsert" || tp == "OnErrRtnOrderInsert"
+                    {
+                        // 检查时会发出止损止盈事件, 不能在接收事件的任务里等待
+                        let app_handle = app_handle.clone();
+                        let (b, a) = (e.broker_id().to_string(), e.account().to_string());
+                        tokio::spawn(async move {
+                            let state = app_handle.state::<StateTpye>();
+                            state.lock().await.check_stop_orders(&b, &a).await;
+                        });
+                    }
                     main_window.emit("cta-event", e).unwrap();
                 }
             });
             let main_window = app.get_window("main").unwrap();
+            let app_handle = app.handle();
+            tokio::spawn(async move {
+                while let Some(md) = md_er.recv().await {
+                    // 只在复制账户列表和检查止损时持有Database锁, 平仓单在锁外发出
+                    let state = app_handle.state::<StateTpye>();
+                    let gateways = state.lock().await.gateways();
+                    Database::dispatch_market_data(&gateways, &md).await;
+                    let fired = state.lock().await.check_stops(&md).await;
+                    for t in fired.iter() {
+                        let gateway = state.lock().await.get_trader(&t.broker_id, &t.account);
+                        let (keys, errors) = Database::fire_stop(gateway, t, &md).await;
+                        state.lock().await.on_stop_fired(&t.id, keys, errors).await;
+                    }
+                    main_window.emit("market-data-event", md).unwrap();
+                }
+            });
+            // 盈亏每秒最多推送一次, 没有变化时不推送
+            let main_window = app.get_window("main").unwrap();
+            let app_handle = app.handle();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+                let mut last = PnlSummary::default();
+                loop {
+                    interval.tick().await;
+                    let summary = app_handle
+                        .state::<StateTpye>()
+                        .lock()
+                        .await
+                        .pnl_summary()
+                        .await;
+                    if summary != last {
+                        main_window.emit("pnl-event", &summary).unwrap();
+                        last = summary;
+                    }
+                }
+            });
+            let main_window = app.get_window("main").unwrap();
             main_window.clone().on_menu_event(move |event| {
                 main_window
                     .emit(
@@ -160,6 +219,14 @@ async fn main() {
             delete_account,
             order_rows,
             get_order_row,
+            place_order,
+            cancel_order,
+            cancel_all,
+            parked_order_rows,
+            get_parked_order_row,
+            place_parked_order,
+            remove_parked_order,
+            refresh_parked_orders,
             trade_rows,
             get_trade_row,
             position_detail_rows,
@@ -168,9 +235,42 @@ async fn main() {
             get_position_row,
             instrument_rows,
             get_instrument_row,
+            commission_rate_rows,
+            margin_rate_rows,
+            pnl_summary,
+            add_stop_trigger,
+            cancel_stop_trigger,
+            stop_trigger_rows,
+            get_stop_trigger_row,
+            estimate_order_cost,
+            market_data_rows,
+            get_market_data_row,
+            refresh_depth_market_data,
+            settlement_rows,
+            get_settlement_row,
+            query_settlement,
+            confirm_settlement,
+            parse_settlement,
+            reconcile_settlement,
+            bank_account_rows,
+            transfer_rows,
+            query_bank_accounts,
+            query_transfers,
+            bank_transfer,
+            query_bank_balance,
+            instrument_status_rows,
+            get_instrument_status,
+            rebuild_history,
+            switch_front,
+            error_rows,
+            subscribe_market_data,
+            unsubscribe_market_data,
+            update_password,
+            update_money_password,
             set_broker,
             delete_broker,
             broker_list,
+            collect_terminal_info,
             default_broker
         ])
         .on_window_event(|event| match event.event() {
@@ -182,6 +282,26 @@ async fn main() {
             }
             _ => {}
         })
-        .run(tauri::generate_context!())
-        .expect("failed to run app");
+        .build(tauri::generate_context!())
+        .expect("failed to build app")
+        .run(move |app_handle, event| {
+            // 先登出并释放所有连接再退出, 保证流文件完整写入
+            if let tauri::RunEvent::ExitRequested { api, .. } = event {
+                if !shutting_down {
+                    shutting_down = true;
+                    api.prevent_exit();
+                    let app_handle = app_handle.clone();
+                    tokio::spawn(async move {
+                        app_handle
+                            .state::<StateTpye>()
+                            .lock()
+                            .await
+                            .shutdown_all()
+                            .await;
+                        info!("所有连接已关闭, 退出");
+                        app_handle.exit(0);
+                    });
+                }
+            }
+        });
 }
@@ -0,0 +1,327 @@
+use crate::config::*;
+use crate::gateway::CtaStatus;
+use ctp_futures::md_api::*;
+use ctp_futures::*;
+use futures::StreamExt;
+use itertools::Itertools;
+use log::{error, info, warn};
+use rust_share_util::*;
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+use tokio::sync::Mutex;
+
+#[derive(Debug, derive_more::Display)]
+pub enum Error {
+    #[display(fmt = "没有找到行情前置[front_group={}]", _0)]
+    MdFrontNotFound(String),
+}
+
+/// 行情订阅的使用方, 所有使用方都退订后才向CTP退订
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Subscriber {
+    /// 行情表
+    User,
+    /// 止损止盈监控
+    Stop,
+}
+
+/// symbol -> (exchange, 使用方), MdApi推送的行情里ExchangeID可能为空
+#[derive(Default)]
+struct Subscriptions(HashMap<String, (String, HashSet<Subscriber>)>);
+
+impl Subscriptions {
+    /// 添加使用方, 返回需要向CTP订阅的symbol, keys为exchange:symbol
+    fn add(&mut self, keys: &[String], subscriber: Subscriber) -> Vec<String> {
+        let mut symbols = vec![];
+        for k in keys.iter() {
+            if let Some((exchange, symbol)) = k.split_once(':') {
+                let (_, subscribers) = self
+                    .0
+                    .entry(symbol.to_string())
+                    .or_insert_with(|| (exchange.to_string(), HashSet::new()));
+                if subscribers.is_empty() {
+                    symbols.push(symbol.to_string());
+                }
+                subscribers.insert(subscriber);
+            } else {
+                warn!("无效的合约key {}", k);
+            }
+        }
+        symbols
+    }
+
+    /// 移除使用方, 返回没有使用方需要向CTP退订的key
+    fn remove(&mut self, keys: &[String], subscriber: Subscriber) -> Vec<String> {
+        let mut removed = vec![];
+        for k in keys.iter() {
+            if let Some((_, symbol)) = k.split_once(':') {
+                if let Some((_, subscribers)) = self.0.get_mut(symbol) {
+                    subscribers.remove(&subscriber);
+                    if subscribers.is_empty() {
+                        self.0.remove(symbol);
+                        removed.push(k.clone());
+                    }
+                }
+            }
+        }
+        removed
+    }
+
+    fn exchange(&self, symbol: &str) -> Option<&String> {
+        self.0.get(symbol).map(|(exchange, _)| exchange)
+    }
+
+    fn symbols(&self) -> Vec<String> {
+        self.0.keys().cloned().collect()
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// 每个经纪商一个MdApi连接, 负责订阅和缓存最新行情
+pub struct MdSession {
+    pub broker_id: String,
+    pub status: CtaStatus,
+    /// Release会释放API对象, 之后不能再调用, Box也不能再drop
+    api: std::mem::ManuallyDrop<Box<CThostFtdcMdApi>>,
+    exit_sender: Option<oneshot::Sender<String>>,
+    exit_done: Option<oneshot::Receiver<()>>,
+    /// 最新行情, key与InstrumentRow::key一致
+    pub ticks: HashMap<String, MarketDataRow>,
+    subscriptions: Subscriptions,
+    /// 止损止盈和模拟撮合依赖每一笔行情, 不能丢弃
+    tick_sender: tokio::sync::mpsc::UnboundedSender<MarketDataRow>,
+    request_id: i32,
+}
+
+impl MdSession {
+    pub fn init(
+        broker: &TradingBroker,
+        front_group: &str,
+        tick_sender: tokio::sync::mpsc::UnboundedSender<MarketDataRow>,
+    ) -> Result<Arc<Mutex<Self>>, Error> {
+        let broker_id = broker.broker_id.clone();
+        // 指定的前置组在前, 其它前置组作为备用, 断线后API在注册的前置之间轮换
+        let md_fronts = broker
+            .fronts
+            .iter()
+            .filter(|fg| fg.id == front_group)
+            .chain(broker.fronts.iter().filter(|fg| fg.id != front_group))
+            .map(|fg| fg.md_front.clone())
+            .filter(|f| f.len() > 0)
+            .unique()
+            .collect::<Vec<_>>();
+        if broker.fens_md_front.len() == 0 && md_fronts.len() == 0 {
+            return Err(Error::MdFrontNotFound(front_group.to_string()));
+        }
+        let flow_path = format!(".cache/ctp_futures_md_flow_{}//", broker_id);
+        check_make_dir(&flow_path);
+        let mut api = create_api(&flow_path, false, false);
+        let mut stream = {
+            let (stream, pp) = create_spi();
+            api.register_spi(pp);
+            stream
+        };
+        if broker.fens_md_front.len() > 0 {
+            api.register_name_server(CString::new(broker.fens_md_front.as_str()).unwrap());
+            info!(
+                "[{broker_id}] register md name server {}",
+                broker.fens_md_front
+            );
+        } else {
+            for f in md_fronts.iter() {
+                api.register_front(CString::new(f.as_str()).unwrap());
+                info!("[{broker_id}] register md front {}", f);
+            }
+        }
+        api.init();
+        let (exit_sender, mut exit_receiver) = oneshot::channel::<String>();
+        let (done_sender, done_receiver) = oneshot::channel::<()>();
+        let session = MdSession {
+            broker_id: broker_id.clone(),
+            status: CtaStatus::default(),
+            api: std::mem::ManuallyDrop::new(api),
+            exit_sender: Some(exit_sender),
+            exit_done: Some(done_receiver),
+            ticks: HashMap::new(),
+            subscriptions: Subscriptions::default(),
+            tick_sender,
+            request_id: 0,
+        };
+        let session = Arc::new(Mutex::new(session));
+        let s1 = Arc::clone(&session);
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    msg = stream.next() => {
+                        if let Some(msg) = msg {
+                            s1.lock().await.handle_spi_msg(&msg);
+                        }
+                    }
+                    _ = &mut exit_receiver => {
+                        info!("[{broker_id}] md exited on receiver, start to release api");
+                        let mut session = s1.lock().await;
+                        // 状态不再是LoginSucceeded, 之后的订阅和退订不会调用已释放的API
+                        session.status = CtaStatus::Disconnected;
+                        session.api.release();
+                        break;
+                    }
+                }
+            }
+            info!("[{broker_id}] md exited loop");
+            let _ = done_sender.send(());
+        });
+        Ok(session)
+    }
+
+    /// 开始断开行情连接, 返回的receiver在API释放后收到通知
+    pub fn exit(&mut self) -> Option<oneshot::Receiver<()>> {
+        if let Some(sender) = self.exit_sender.take() {
+            let _ = sender.send("exit".to_string());
+        }
+        self.exit_done.take()
+    }
+
+    fn get_request_id(&mut self) -> i32 {
+        self.request_id += 1;
+        self.request_id
+    }
+
+    /// 订阅行情, keys为exchange:symbol
+    pub fn subscribe(&mut self, keys: &[String], subscriber: Subscriber) {
+        let symbols = self.subscriptions.add(keys, subscriber);
+        if self.status == CtaStatus::LoginSucceeded {
+            self.req_subscribe(&symbols);
+        }
+    }
+
+    /// 其它使用方仍在使用的合约不会退订
+    pub fn unsubscribe(&mut self, keys: &[String], subscriber: Subscriber) {
+        let removed = self.subscriptions.remove(keys, subscriber);
+        let symbols = removed
+            .iter()
+            .filter_map(|k| k.split_once(':').map(|(_, symbol)| symbol.to_string()))
+            .collect::<Vec<_>>();
+        // 缓存的行情只在最后一个使用方退订后删除
+        for k in removed.iter() {
+            self.ticks.remove(k);
+        }
+        if self.status != CtaStatus::LoginSucceeded || symbols.len() == 0 {
+            return;
+        }
+        let count = symbols.len() as i32;
+        let v = symbols
+            .iter()
+            .map(|s| CString::new(s.as_str()).unwrap())
+            .collect::<Vec<_>>();
+        let result = self.api.unsubscribe_market_data(v, count);
+        if result != 0 {
+            error!("[{}] UnSubscribeMarketData={}", self.broker_id, result);
+        }
+    }
+
+    fn req_subscribe(&mut self, symbols: &[String]) {
+        if symbols.len() == 0 {
+            return;
+        }
+        let count = symbols.len() as i32;
+        let v = symbols
+            .iter()
+            .map(|s| CString::new(s.as_str()).unwrap())
+            .collect::<Vec<_>>();
+        let result = self.api.subscribe_market_data(v, count);
+        if result != 0 {
+            error!("[{}] SubscribeMarketData={}", self.broker_id, result);
+        }
+    }
+
+    fn handle_spi_msg(&mut self, spi_msg: &CThostFtdcMdSpiOutput) {
+        use ctp_futures::md_api::CThostFtdcMdSpiOutput::*;
+        match spi_msg {
+            OnFrontConnected(_p) => {
+                info!("[{}] md OnFrontConnected", self.broker_id);
+                self.status = CtaStatus::Connected;
+                let mut req = CThostFtdcReqUserLoginField::default();
+                set_cstr_from_str_truncate_i8(&mut req.BrokerID, &self.broker_id);
+                let request_id = self.get_request_id();
+                let result = self.api.req_user_login(&mut req, request_id);
+                if result != 0 {
+                    error!("[{}] md ReqUserLogin={}", self.broker_id, result);
+                }
+            }
+            OnFrontDisconnected(p) => {
+                warn!("[{}] md on front disconnected {:?}", self.broker_id, p);
+                self.status = CtaStatus::Disconnected;
+            }
+            OnRspUserLogin(ref p) => {
+                if let Some(info) = p.p_rsp_info.filter(|r| r.ErrorID != 0) {
+                    self.status = CtaStatus::LoginFailed;
+                    warn!(
+                        "[{}] Md RspUserLogin ErrorID={} ErrorMsg={}",
+                        self.broker_id,
+                        info.ErrorID,
+                        gb18030_cstr_to_str_i8(&info.ErrorMsg)
+                    );
+                    return;
+                }
+                info!(
+                    "[{}] md login succeeded, 订阅{}个合约",
+                    self.broker_id,
+                    self.subscriptions.len()
+                );
+                self.status = CtaStatus::LoginSucceeded;
+                // 重连后重新订阅
+                let symbols = self.subscriptions.symbols();
+                self.req_subscribe(&symbols);
+            }
+            OnRspSubMarketData(ref p) => {
+                if let Some(info) = p.p_rsp_info.filter(|r| r.ErrorID != 0) {
+                    warn!(
+                        "[{}] RspSubMarketData {:?} ErrorID={} ErrorMsg={}",
+                        self.broker_id,
+                        p.p_specific_instrument.map(|i| ctp_str(&i.InstrumentID)),
+                        info.ErrorID,
+                        gb18030_cstr_to_str_i8(&info.ErrorMsg)
+                    );
+                }
+            }
+            OnRtnDepthMarketData(ref p) => {
+                if let Some(md) = &p.p_depth_market_data {
+                    let mut row = MarketDataRow::from(md);
+                    if row.exchange.len() == 0 {
+                        if let Some(exchange) = self.subscriptions.exchange(&row.symbol) {
+                            row.exchange = exchange.clone();
+                        }
+                    }
+                    if self.tick_sender.send(row.clone()).is_err() {
+                        warn!("[{}] 行情通道已关闭", self.broker_id);
+                    }
+                    self.ticks.insert(row.key(), row);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsubscribe_keeps_symbols_watched_by_stops() {
+        let mut subs = Subscriptions::default();
+        let keys = vec!["SHFE:rb2305".to_string()];
+        assert_eq!(subs.add(&keys, Subscriber::Stop), vec!["rb2305"]);
+        // 已订阅的合约不重复向CTP订阅
+        assert!(subs.add(&keys, Subscriber::User).is_empty());
+        assert!(subs.remove(&keys, Subscriber::User).is_empty());
+        assert_eq!(subs.exchange("rb2305").map(|e| e.as_str()), Some("SHFE"));
+        assert_eq!(subs.remove(&keys, Subscriber::Stop), keys);
+        assert_eq!(subs.len(), 0);
+    }
+}
@@ -0,0 +1,1020 @@
+use crate::config::*;
+use crate::gateway::*;
+use crate::position::{open_direction, posi_direction};
+use log::{info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::sync::oneshot;
+use tokio::sync::Mutex;
+
+/// 模拟账户的FrontID, 与CTP报单的key区分
+const PAPER_FRONT_ID: i32 = 0;
+
+/// 未完成报单的撮合参数和冻结资金(每手)
+struct WorkingOrder {
+    input: OrderInput,
+    frozen_margin: f64,
+    frozen_commission: f64,
+}
+
+/// 本地模拟撮合的交易账户, 使用最新行情或回放行情成交
+pub struct PaperGateway {
+    conf: TradingAccount,
+    options: PaperOptions,
+    book: AccountBook,
+    /// 由转发任务按顺序送入CtaEvent通道, 同步方法中发送也不会丢弃
+    event_sender: tokio::sync::mpsc::UnboundedSender<CtaEvent>,
+    /// 回放行情和MdApi行情走同一个通道, 驱动止损止盈和界面
+    md_sender: tokio::sync::mpsc::UnboundedSender<MarketDataRow>,
+    exit_sender: Option<oneshot::Sender<String>>,
+    exit_done: Option<oneshot::Receiver<()>>,
+    working: HashMap<String, WorkingOrder>,
+    /// 未触发的条件单, key为OrderRow的key
+    conditional: HashMap<String, OrderInput>,
+    /// 未发送的预埋单, key为ParkedOrderRow的id
+    parked: HashMap<String, OrderInput>,
+    replay: VecDeque<MarketDataRow>,
+    order_sys_id: i32,
+    trade_id: i32,
+    parked_id: i32,
+}
+
+impl PaperGateway {
+    pub fn init(
+        conf: TradingAccount,
+        broker: TradingBroker,
+        es: tokio::sync::mpsc::Sender<CtaEvent>,
+        md: tokio::sync::mpsc::UnboundedSender<MarketDataRow>,
+    ) -> Arc<Mutex<Self>> {
+        let ak = format!("{}:{}", conf.broker_id, conf.account);
+        let (event_sender, mut event_receiver) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(e) = event_receiver.recv().await {
+                if es.send(e).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let (exit_sender, mut exit_receiver) = oneshot::channel::<String>();
+        let (done_sender, done_receiver) = oneshot::channel::<()>();
+        let replay_interval = broker.paper.replay_interval_ms.max(1);
+        let paper = PaperGateway {
+            conf,
+            options: broker.paper,
+            book: AccountBook::default(),
+            event_sender,
+            md_sender: md,
+            exit_sender: Some(exit_sender),
+            exit_done: Some(done_receiver),
+            working: HashMap::new(),
+            conditional: HashMap::new(),
+            parked: HashMap::new(),
+            replay: VecDeque::new(),
+            order_sys_id: 0,
+            trade_id: 0,
+            parked_id: 0,
+        };
+        let paper = Arc::new(Mutex::new(paper));
+        let p1 = Arc::clone(&paper);
+        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(10));
+        let mut replay_timer =
+            tokio::time::interval(tokio::time::Duration::from_millis(replay_interval));
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = replay_timer.tick() => {
+                        p1.lock().await.replay_next();
+                    }
+                    _ = interval.tick() => {
+                        let p = p1.lock().await;
+                        if p.book.status == CtaStatus::LoginCompleted {
+                            p.send_event("OnRspQryTradingAccount", &p.key());
+                        }
+                    }
+                    _ = &mut exit_receiver => {
+                        info!("[{ak}] paper exited on receiver");
+                        p1.lock().await.book.status = CtaStatus::Disconnected;
+                        break;
+                    }
+                }
+            }
+            info!("[{ak}] paper exited loop");
+            let _ = done_sender.send(());
+        });
+        paper
+    }
+
+    fn send_event(&self, tp: &str, key: &str) {
+        let e = CtaEvent::new(tp, &self.conf.broker_id, &self.conf.account, key);
+        if self.event_sender.send(e).is_err() {
+            warn!("{} 事件通道已关闭 {}", self.key(), tp);
+        }
+    }
+
+    fn login_completed(&self) -> bool {
+        self.book.status == CtaStatus::LoginCompleted
+    }
+
+    fn set_status(&mut self, status: CtaStatus, tp: &str) {
+        info!(
+            "{} status {:?} -> {:?}",
+            self.key(),
+            self.book.status,
+            status
+        );
+        self.book.status = status;
+        self.send_event(tp, "");
+    }
+
+    /// 读取回放文件, 每行一个MarketDataRow的json
+    fn load_replay(&mut self) {
+        if self.options.tick_file.len() == 0 {
+            return;
+        }
+        match std::fs::read_to_string(&self.options.tick_file) {
+            Ok(s) => {
+                for (i, line) in s.lines().enumerate() {
+                    if line.trim().len() == 0 {
+                        continue;
+                    }
+                    match serde_json::from_str::<MarketDataRow>(line) {
+                        Ok(md) => self.replay.push_back(md),
+                        Err(e) => warn!(
+                            "{} 回放文件{}第{}行格式错误 {}",
+                            self.key(),
+                            self.options.tick_file,
+                            i + 1,
+                            e
+                        ),
+                    }
+                }
+                info!(
+                    "{} 加载回放行情{}条 {}",
+                    self.key(),
+                    self.replay.len(),
+                    self.options.tick_file
+                );
+            }
+            Err(e) => warn!(
+                "{} 读取回放文件失败 {} {}",
+                self.key(),
+                self.options.tick_file,
+                e
+            ),
+        }
+    }
+
+    /// 加载配置的模拟合约, 之后CTP账户提供的同一合约不再覆盖
+    fn load_instruments(&mut self) {
+        for i in self.options.instruments.clone() {
+            let i = InstrumentRow {
+                broker_id: self.conf.broker_id.clone(),
+                account: self.conf.account.clone(),
+                exchange: i.exchange,
+                symbol: i.symbol,
+                name: i.name,
+                volume_multiple: i.volume_multiple.max(1),
+                price_tick: i.price_tick,
+                ..Default::default()
+            };
+            self.book.apply(GatewayEvent::Instrument(i));
+        }
+    }
+
+    fn replay_next(&mut self) {
+        if !self.login_completed() {
+            return;
+        }
+        if let Some(mut md) = self.replay.pop_front() {
+            md.replay_account = self.key();
+            // 经Database转发回本账户, 同时检查止损止盈和推送界面
+            if let Err(e) = self.md_sender.send(md) {
+                self.on_tick(e.0);
+            }
+            if self.replay.len() == 0 {
+                info!("{} 行情回放完成", self.key());
+            }
+        }
+    }
+
+    fn next_order_ref(&mut self) -> String {
+        self.book.order_ref += 1;
+        self.book.order_ref.to_string()
+    }
+
+    fn volume_multiple(&self, exchange: &str, symbol: &str) -> i32 {
+        self.book
+            .instruments
+            .get(&format!("{exchange}:{symbol}"))
+            .map_or(1, |i| i.volume_multiple.max(1))
+    }
+
+    /// 模拟账户所有合约使用配置的费率, 第一次使用时加入费率表
+    fn ensure_rates(&mut self, exchange: &str, symbol: &str) {
+        if !self.book.commission_rates.contains_key(symbol) {
+            let r = CommissionRateRow {
+                broker_id: self.conf.broker_id.clone(),
+                account: self.conf.account.clone(),
+                exchange: exchange.to_string(),
+                symbol: symbol.to_string(),
+                open_ratio_by_money: self.options.commission_ratio_by_money,
+                open_ratio_by_volume: self.options.commission_ratio_by_volume,
+                close_ratio_by_money: self.options.commission_ratio_by_money,
+                close_ratio_by_volume: self.options.commission_ratio_by_volume,
+                close_today_ratio_by_money: self.options.commission_ratio_by_money,
+                close_today_ratio_by_volume: self.options.commission_ratio_by_volume,
+            };
+            self.book.apply(GatewayEvent::CommissionRate(r));
+        }
+        if !self.book.margin_rates.contains_key(symbol) {
+            let r = MarginRateRow {
+                broker_id: self.conf.broker_id.clone(),
+                account: self.conf.account.clone(),
+                exchange: exchange.to_string(),
+                symbol: symbol.to_string(),
+                long_margin_ratio_by_money: self.options.margin_ratio,
+                short_margin_ratio_by_money: self.options.margin_ratio,
+                ..Default::default()
+            };
+            self.book.apply(GatewayEvent::MarginRate(r));
+        }
+    }
+
+    fn update_time(&self, exchange: &str, symbol: &str) -> String {
+        self.book
+            .depth_snapshots
+            .get(&format!("{exchange}:{symbol}"))
+            .map_or(String::new(), |md| md.update_time.clone())
+    }
+
+    /// 报单被拒绝, 记录为已撤销的报单
+    fn reject(&mut self, mut o: OrderRow, msg: &str) -> Result<(), String> {
+        warn!("{} 模拟报单被拒绝 key={} {}", self.key(), o.key(), msg);
+        o.status = ORDER_CANCELED;
+        o.status_description = msg.to_string();
+        let k = self.book.apply(GatewayEvent::Order(o)).unwrap_or_default();
+        self.send_event("OnErrRtnOrderInsert", &k);
+        Err(msg.to_string())
+    }
+
+    fn new_order(&mut self, input: &OrderInput) -> OrderRow {
+        let order_ref = self.next_order_ref();
+        self.order_sys_id += 1;
+        OrderRow {
+            front_id: self.book.front_id,
+            session_id: self.book.session_id,
+            order_ref,
+            broker_id: self.conf.broker_id.clone(),
+            account: self.conf.account.clone(),
+            order_sys_id: self.order_sys_id.to_string(),
+            exchange: input.exchange.clone(),
+            symbol: input.symbol.clone(),
+            direction: input.direction,
+            offset: input.offset,
+            limit_price: if input.order_type == OrderType::Market {
+                0.0
+            } else {
+                input.price
+            },
+            volume_total: input.volume,
+            volume_total_original: input.volume,
+            volume_traded: 0,
+            status: ORDER_NO_TRADE_QUEUEING,
+            status_description: "未成交".into(),
+            insert_time: self.update_time(&input.exchange, &input.symbol),
+        }
+    }
+
+    /// 检查资金和可平仓位, 冻结后进入撮合, 条件单触发后也从这里进入
+    fn submit(&mut self, mut o: OrderRow, input: &OrderInput) -> Result<(), String> {
+        let key = o.key();
+        let md_key = format!("{}:{}", input.exchange, input.symbol);
+        if input.order_type == OrderType::Market && !self.book.depth_snapshots.contains_key(&md_key)
+        {
+            return self.reject(o, "没有行情, 不能市价下单");
+        }
+        let (mut frozen_margin, mut frozen_commission) = (0.0, 0.0);
+        if input.offset == OFFSET_OPEN {
+            let cost = match self.estimate_order_cost(input) {
+                Ok(cost) => cost,
+                Err(e) => return self.reject(o, &e.to_string()),
+            };
+            if cost.margin + cost.commission > self.book.funds.available {
+                return self.reject(o, "可用资金不足");
+            }
+            frozen_margin = cost.margin / input.volume as f64;
+            frozen_commission = cost.commission / input.volume as f64;
+        } else {
+            // 触发的条件单自身已冻结持仓, 不重复扣除
+            self.book.update_frozen();
+            let own = self
+                .book
+                .orders
+                .get(&key)
+                .filter(|current| current.is_active())
+                .map_or(0, |current| current.volume_total);
+            let direction = posi_direction(input.direction, input.offset);
+            if self
+                .book
+                .closable_volume(&input.exchange, &input.symbol, direction)
+                + own
+                < input.volume
+            {
+                return self.reject(o, "可平仓位不足");
+            }
+        }
+        self.book.funds.frozen_margin += frozen_margin * input.volume as f64;
+        self.book.funds.frozen_commission += frozen_commission * input.volume as f64;
+        self.working.insert(
+            key.clone(),
+            WorkingOrder {
+                input: input.clone(),
+                frozen_margin,
+                frozen_commission,
+            },
+        );
+        o.status = ORDER_NO_TRADE_QUEUEING;
+        o.status_description = "未成交".into();
+        self.book.apply(GatewayEvent::Order(o));
+        self.update_funds();
+        self.send_event("OnRtnOrder", &key);
+        self.match_order(&key, false);
+        Ok(())
+    }
+
+    /// 条件单在本地等待行情触发, 触发前不冻结资金
+    fn hold_conditional(&mut self, mut o: OrderRow, input: &OrderInput) {
+        o.status = ORDER_NOT_TOUCHED;
+        o.status_description = "尚未触发".into();
+        let key = o.key();
+        let row = ParkedOrderRow {
+            broker_id: o.broker_id.clone(),
+            account: o.account.clone(),
+            id: key.clone(),
+            conditional: true,
+            order_ref: o.order_ref.clone(),
+            exchange: o.exchange.clone(),
+            symbol: o.symbol.clone(),
+            direction: o.direction,
+            offset: o.offset,
+            limit_price: o.limit_price,
+            volume: o.volume_total_original,
+            contingent_condition: input.contingent_condition,
+            stop_price: input.stop_price,
+            status: o.status,
+            status_description: o.status_description.clone(),
+        };
+        self.book.apply(GatewayEvent::Order(o));
+        self.book.apply(GatewayEvent::ParkedOrder(row));
+        self.conditional.insert(key.clone(), input.clone());
+        self.update_funds();
+        self.send_event("OnRtnOrder", &key);
+    }
+
+    fn set_parked_status(&mut self, id: &str, status: i32, msg: &str) {
+        if let Some(p) = self.book.parked_orders.get_mut(id) {
+            p.status = status;
+            p.status_description = msg.to_string();
+        }
+    }
+
+    /// 新行情触发该合约的条件单, 发出该合约的预埋单
+    fn trigger_orders(&mut self, md: &MarketDataRow) {
+        let same = |i: &OrderInput| i.exchange == md.exchange && i.symbol == md.symbol;
+        let mut ids = self
+            .parked
+            .iter()
+            .filter(|(_, i)| same(i))
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        ids.sort();
+        for id in ids {
+            if let Some(input) = self.parked.remove(&id) {
+                info!("{} 发出模拟预埋单 id={}", self.key(), id);
+                self.set_parked_status(&id, PARKED_SEND, "已发送");
+                self.send_event("ParkedOrder", &id);
+                let o = self.new_order(&input);
+                if input.is_conditional() {
+                    self.hold_conditional(o, &input);
+                } else {
+                    let _ = self.submit(o, &input);
+                }
+            }
+        }
+        let mut keys = self
+            .conditional
+            .iter()
+            .filter(|(_, i)| same(i) && i.touched(md))
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<_>>();
+        keys.sort();
+        for key in keys {
+            let input = match self.conditional.remove(&key) {
+                Some(input) => input,
+                None => continue,
+            };
+            let o = match self.book.orders.get(&key) {
+                Some(o) => o.clone(),
+                None => continue,
+            };
+            info!("{} 模拟条件单触发 key={}", self.key(), key);
+            self.set_parked_status(&key, ORDER_TOUCHED, "已触发");
+            self.send_event("ParkedOrder", &key);
+            let _ = self.submit(o, &input);
+        }
+    }
+
+    /// 用当前行情撮合报单, passive为true时表示挂单被动成交, 以报单价格成交
+    fn match_order(&mut self, key: &str, passive: bool) {
+        let (input, volume_total) = match (self.working.get(key), self.book.orders.get(key)) {
+            (Some(w), Some(o)) => (w.input.clone(), o.volume_total),
+            _ => return,
+        };
+        let ioc = input.order_type != OrderType::Limit;
+        let md = self
+            .book
+            .depth_snapshots
+            .get(&format!("{}:{}", input.exchange, input.symbol))
+            .cloned();
+        let mut fill = 0;
+        let mut price = 0.0;
+        if let Some(md) = md {
+            let buy = input.direction == DIRECTION_BUY;
+            let (best, available) = if buy {
+                (md.ask_prices.first(), md.ask_volumes.first())
+            } else {
+                (md.bid_prices.first(), md.bid_volumes.first())
+            };
+            let best = best.cloned().unwrap_or(0.0);
+            let available = available.cloned().unwrap_or(0);
+            // 没有盘口时使用最新价, 数量不限
+            let (best, available) = if best > 0.0 {
+                (best, available)
+            } else {
+                (md.last_price, 0)
+            };
+            let crossed = best > 0.0
+                && (input.order_type == OrderType::Market
+                    || (buy && best <= input.price)
+                    || (!buy && best >= input.price));
+            if crossed {
+                fill = if available > 0 {
+                    volume_total.min(available)
+                } else {
+                    volume_total
+                };
+                let all = input.order_type == OrderType::Fok
+                    || (input.order_type == OrderType::Fak
+                        && input.volume_condition == VolumeCondition::All);
+                let min = input.order_type == OrderType::Fak
+                    && input.volume_condition == VolumeCondition::Min;
+                if (all && fill < volume_total) || (min && fill < input.min_volume) {
+                    fill = 0;
+                }
+                price = if passive { input.price } else { best };
+            }
+        }
+        if fill > 0 {
+            self.fill(key, price, fill);
+        }
+        // FAK/FOK/市价单剩余部分撤销
+        if ioc && self.working.contains_key(key) {
+            self.cancel_working(key, "剩余部分已撤销");
+        }
+    }
+
+    fn release_frozen(&mut self, key: &str, volume: i32) {
+        if let Some(w) = self.working.get(key) {
+            self.book.funds.frozen_margin -= w.frozen_margin * volume as f64;
+            self.book.funds.frozen_commission -= w.frozen_commission * volume as f64;
+        }
+    }
+
+    fn cancel_working(&mut self, key: &str, msg: &str) {
+        let volume_total = self.book.orders.get(key).map_or(0, |o| o.volume_total);
+        self.release_frozen(key, volume_total);
+        self.working.remove(key);
+        if self.conditional.remove(key).is_some() {
+            self.set_parked_status(key, ORDER_CANCELED, msg);
+        }
+        if let Some(o) = self.book.orders.get_mut(key) {
+            o.status = ORDER_CANCELED;
+            o.status_description = msg.to_string();
+        }
+        self.update_funds();
+        self.send_event("OnRtnOrder", key);
+    }
+
+    /// 成交, 更新报单/成交/持仓/持仓明细和资金
+    fn fill(&mut self, key: &str, price: f64, volume: i32) {
+        self.release_frozen(key, volume);
+        let o = match self.book.orders.get_mut(key) {
+            Some(o) => o,
+            None => return,
+        };
+        o.volume_traded += volume;
+        o.volume_total -= volume;
+        if o.volume_total == 0 {
+            o.status = ORDER_ALL_TRADED;
+            o.status_description = "全部成交".into();
+        } else {
+            o.status = ORDER_PART_TRADED_QUEUEING;
+            o.status_description = "部分成交".into();
+        }
+        let o = o.clone();
+        if o.volume_total == 0 {
+            self.working.remove(key);
+        }
+        self.trade_id += 1;
+        let multiple = self.volume_multiple(&o.exchange, &o.symbol);
+        let mut trade = TradeRow {
+            broker_id: self.conf.broker_id.clone(),
+            account: self.conf.account.clone(),
+            trade_id: self.trade_id.to_string(),
+            exchange: o.exchange.clone(),
+            symbol: o.symbol.clone(),
+            direction: o.direction,
+            offset: o.offset,
+            price,
+            volume,
+            commission: 0.0,
+        };
+        self.ensure_rates(&o.exchange, &o.symbol);
+        trade.commission = self
+            .book
+            .commission_rates
+            .get(&o.symbol)
+            .map_or(0.0, |r| r.commission(o.offset, price, volume, multiple));
+        self.book.funds.commission += trade.commission;
+        self.book.funds.close_profit += self.book.apply_trade(&trade, multiple);
+        info!(
+            "{} 模拟成交 key={} {} price={} volume={}",
+            self.key(),
+            key,
+            trade.symbol,
+            price,
+            volume
+        );
+        let pk = format!(
+            "{}:{}:{}",
+            trade.exchange,
+            trade.symbol,
+            posi_direction(trade.direction, trade.offset)
+        );
+        let tk = self
+            .book
+            .apply(GatewayEvent::Trade(trade))
+            .unwrap_or_default();
+        self.update_funds();
+        self.send_event("OnRtnOrder", key);
+        self.send_event("OnRtnTrade", &tk);
+        self.send_event("Position", &pk);
+        self.send_event("OnRspQryTradingAccount", &self.key());
+    }
+
+    /// 按最新价重新计算持仓盈亏/保证金/可用资金
+    fn update_funds(&mut self) {
+        self.book.update_frozen();
+        self.book.revalue_all();
+        let mut margin = 0.0;
+        let mut position_profit = 0.0;
+        for p in self.book.positions.values().filter(|p| p.position > 0) {
+            let multiple = self.volume_multiple(&p.exchange, &p.symbol);
+            let avg = p.open_cost / (p.position as f64 * multiple as f64);
+            let price = self
+                .book
+                .depth_snapshots
+                .get(&format!("{}:{}", p.exchange, p.symbol))
+                .map(|md| md.valuation_price())
+                .filter(|price| *price > 0.0)
+                .unwrap_or(avg);
+            let long = p.direction == POSI_LONG;
+            margin += self.book.margin_rates.get(&p.symbol).map_or(0.0, |r| {
+                r.margin(open_direction(p.direction), price, p.position, multiple)
+            });
+            let diff = if long { price - avg } else { avg - price };
+            position_profit += diff * p.position as f64 * multiple as f64;
+        }
+        let f = &mut self.book.funds;
+        f.curr_margin = margin;
+        f.position_profit = position_profit;
+        f.balance = f.pre_balance + f.deposit - f.withdraw + f.close_profit + position_profit
+            - f.commission;
+        f.available = f.balance - f.curr_margin - f.frozen_margin - f.frozen_commission;
+    }
+
+    /// 新行情, 更新快照后撮合该合约的挂单
+    fn on_tick(&mut self, md: MarketDataRow) {
+        if self.book.funds.trading_day.len() == 0 && md.trading_day.len() > 0 {
+            self.book.funds.trading_day = md.trading_day.clone();
+        }
+        let k = md.key();
+        self.book.apply(GatewayEvent::DepthMarketData(md.clone()));
+        // 触发的条件单在submit中主动撮合, 不参与本次被动撮合
+        let keys = self
+            .working
+            .iter()
+            .filter(|(_, w)| format!("{}:{}", w.input.exchange, w.input.symbol) == k)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        self.trigger_orders(&md);
+        for key in keys.iter() {
+            self.match_order(key, true);
+        }
+        if self
+            .book
+            .positions
+            .values()
+            .any(|p| p.position > 0 && p.key().starts_with(&format!("{k}:")))
+        {
+            self.update_funds();
+        }
+    }
+}
+
+impl Gateway for PaperGateway {
+    fn conf(&self) -> &TradingAccount {
+        &self.conf
+    }
+
+    fn book(&self) -> &AccountBook {
+        &self.book
+    }
+
+    fn connect(&mut self) -> Result<(), Error> {
+        self.book.front_id = PAPER_FRONT_ID;
+        self.book.session_id = (now_ts() % 1_000_000) as i32;
+        self.book.connected_at = now_ts();
+        self.book.funds.pre_balance = self.options.initial_balance;
+        self.update_funds();
+        self.load_instruments();
+        self.load_replay();
+        self.set_status(CtaStatus::Connected, "OnFrontConnected");
+        self.set_status(CtaStatus::LoginSucceeded, "OnRspUserLogin");
+        self.book.initialized = true;
+        // 模拟账户没有结算单
+        self.book.settlement_confirmed = true;
+        self.set_status(CtaStatus::LoginCompleted, "LoginCompleted");
+        Ok(())
+    }
+
+    fn login(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// 模拟账户的数据都在本地, 不需要查询
+    fn query(&mut self, _q: GatewayQuery) -> Result<Vec<QueryReply>, Error> {
+        if !self.login_completed() {
+            return Err(Error::NotLoggedIn);
+        }
+        Ok(vec![])
+    }
+
+    fn insert_order(
+        &mut self,
+        input: &OrderInput,
+    ) -> Result<(String, oneshot::Receiver<Result<(), String>>), Error> {
+        if !self.login_completed() {
+            return Err(Error::NotLoggedIn);
+        }
+        if input.symbol.len() == 0 {
+            return Err(Error::InvalidOrder("合约不能为空".into()));
+        }
+        if input.volume <= 0 {
+            return Err(Error::InvalidOrder("数量必须大于0".into()));
+        }
+        if input.order_type == OrderType::Fak
+            && input.volume_condition == VolumeCondition::Min
+            && input.min_volume <= 0
+        {
+            return Err(Error::InvalidOrder("最小成交量必须大于0".into()));
+        }
+        if input.is_conditional() && input.stop_price <= 0.0 {
+            return Err(Error::InvalidOrder("条件单触发价必须大于0".into()));
+        }
+        if input.is_conditional() && input.trigger_rule().is_none() {
+            return Err(Error::Unsupported("模拟账户只支持价格条件单".into()));
+        }
+        // 合约信息来自模拟合约配置或同一程序中的CTP账户, 没有时无法得到合约乘数
+        let md_key = format!("{}:{}", input.exchange, input.symbol);
+        if !self.book.instruments.contains_key(&md_key) {
+            return Err(Error::InstrumentNotFound(md_key));
+        }
+        let o = self.new_order(input);
+        let key = o.key();
+        info!("{} 模拟报单 key={} {:?}", self.key(), key, input);
+        let (tx, rx) = oneshot::channel();
+        if input.is_conditional() {
+            self.hold_conditional(o, input);
+            let _ = tx.send(Ok(()));
+        } else {
+            let _ = tx.send(self.submit(o, input));
+        }
+        Ok((key, rx))
+    }
+
+    fn cancel_order(&mut self, key: &str) -> Result<oneshot::Receiver<Result<(), Error>>, Error> {
+        if !self.login_completed() {
+            return Err(Error::NotLoggedIn);
+        }
+        let o = self
+            .book
+            .orders
+            .get(key)
+            .ok_or(Error::OrderNotFound(key.to_string()))?;
+        if !o.is_active() {
+            return Err(Error::OrderNotActive(key.to_string()));
+        }
+        info!("{} 模拟撤单 key={}", self.key(), key);
+        self.cancel_working(key, "已撤单");
+        let (tx, rx) = oneshot::channel();
+        let _ = tx.send(Ok(()));
+        Ok(rx)
+    }
+
+    /// 模拟预埋单在该合约的下一笔行情到达时发出
+    fn insert_parked_order(
+        &mut self,
+        input: &OrderInput,
+    ) -> Result<oneshot::Receiver<Result<String, String>>, Error> {
+        if !self.login_completed() {
+            return Err(Error::NotLoggedIn);
+        }
+        if input.symbol.len() == 0 {
+            return Err(Error::InvalidOrder("合约不能为空".into()));
+        }
+        if input.volume <= 0 {
+            return Err(Error::InvalidOrder("数量必须大于0".into()));
+        }
+        if input.order_type != OrderType::Limit {
+            return Err(Error::InvalidOrder("预埋单只支持限价单".into()));
+        }
+        if input.is_conditional() && input.stop_price <= 0.0 {
+            return Err(Error::InvalidOrder("条件单触发价必须大于0".into()));
+        }
+        if input.is_conditional() && input.trigger_rule().is_none() {
+            return Err(Error::Unsupported("模拟账户只支持价格条件单".into()));
+        }
+        let md_key = format!("{}:{}", input.exchange, input.symbol);
+        if !self.book.instruments.contains_key(&md_key) {
+            return Err(Error::InstrumentNotFound(md_key));
+        }
+        self.parked_id += 1;
+        let id = format!("{:012}", self.parked_id);
+        let row = ParkedOrderRow {
+            broker_id: self.conf.broker_id.clone(),
+            account: self.conf.account.clone(),
+            id: id.clone(),
+            conditional: false,
+            order_ref: String::new(),
+            exchange: input.exchange.clone(),
+            symbol: input.symbol.clone(),
+            direction: input.direction,
+            offset: input.offset,
+            limit_price: input.price,
+            volume: input.volume,
+            contingent_condition: input.contingent_condition,
+            stop_price: input.stop_price,
+            status: PARKED_NOT_SEND,
+            status_description: "未发送".into(),
+        };
+        info!("{} 模拟预埋单 id={} {:?}", self.key(), id, input);
+        self.book.apply(GatewayEvent::ParkedOrder(row));
+        self.parked.insert(id.clone(), input.clone());
+        self.send_event("OnRspParkedOrderInsert", &id);
+        let (tx, rx) = oneshot::channel();
+        let _ = tx.send(Ok(id));
+        Ok(rx)
+    }
+
+    fn remove_parked_order(
+        &mut self,
+        key: &str,
+    ) -> Result<oneshot::Receiver<Result<(), Error>>, Error> {
+        if !self.login_completed() {
+            return Err(Error::NotLoggedIn);
+        }
+        let o = self
+            .book
+            .parked_orders
+            .get(key)
+            .ok_or(Error::OrderNotFound(key.to_string()))?;
+        if !o.is_active() {
+            return Err(Error::OrderNotActive(key.to_string()));
+        }
+        // 条件单通过撤单删除
+        if o.conditional {
+            return self.cancel_order(key);
+        }
+        info!("{} 删除模拟预埋单 id={}", self.key(), key);
+        self.parked.remove(key);
+        self.set_parked_status(key, PARKED_DELETED, "已删除");
+        self.send_event("OnRspRemoveParkedOrder", key);
+        let (tx, rx) = oneshot::channel();
+        let _ = tx.send(Ok(()));
+        Ok(rx)
+    }
+
+    fn estimate_order_cost(&mut self, input: &OrderInput) -> Result<OrderCost, Error> {
+        let k = format!("{}:{}", input.exchange, input.symbol);
+        if !self.book.instruments.contains_key(&k) {
+            return Err(Error::InstrumentNotFound(k));
+        }
+        self.ensure_rates(&input.exchange, &input.symbol);
+        let price = if input.order_type == OrderType::Market {
+            self.book
+                .depth_snapshots
+                .get(&k)
+                .map_or(0.0, |md| md.valuation_price())
+        } else {
+            input.price
+        };
+        let multiple = self.volume_multiple(&input.exchange, &input.symbol);
+        let commission = self
+            .book
+            .commission_rates
+            .get(&input.symbol)
+            .ok_or(Error::RateNotReady(k.clone()))?
+            .commission(input.offset, price, input.volume, multiple);
+        let margin = if input.offset == OFFSET_OPEN {
+            self.book
+                .margin_rates
+                .get(&input.symbol)
+                .ok_or(Error::RateNotReady(k))?
+                .margin(input.direction, price, input.volume, multiple)
+        } else {
+            0.0
+        };
+        Ok(OrderCost { margin, commission })
+    }
+
+    fn exit(&mut self) -> Option<oneshot::Receiver<()>> {
+        if let Some(sender) = self.exit_sender.take() {
+            let _ = sender.send("exit".to_string());
+        }
+        self.exit_done.take()
+    }
+
+    fn on_market_data(&mut self, md: &MarketDataRow, instrument: Option<&InstrumentRow>) {
+        if let Some(i) = instrument {
+            if !self.book.instruments.contains_key(&i.key()) {
+                let mut i = i.clone();
+                i.broker_id = self.conf.broker_id.clone();
+                i.account = self.conf.account.clone();
+                self.book.apply(GatewayEvent::Instrument(i));
+            }
+        }
+        // 配置了回放文件时只使用本账户的回放行情, 否则只使用MdApi行情
+        let accepted = if self.options.tick_file.len() == 0 {
+            md.replay_account.is_empty()
+        } else {
+            md.replay_account == self.key()
+        };
+        if accepted {
+            self.on_tick(md.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ctp_futures::{
+        THOST_FTDC_CC_LastPriceGreaterEqualStopPrice, THOST_FTDC_CC_ParkedOrder,
+        THOST_FTDC_CC_Touch,
+    };
+
+    fn paper() -> PaperGateway {
+        let (event_sender, _) = tokio::sync::mpsc::unbounded_channel();
+        let (md_sender, _) = tokio::sync::mpsc::unbounded_channel();
+        let mut options = PaperOptions::default();
+        options.instruments.push(PaperInstrument {
+            exchange: "SHFE".into(),
+            symbol: "rb2305".into(),
+            volume_multiple: 10,
+            price_tick: 1.0,
+            ..Default::default()
+        });
+        let mut p = PaperGateway {
+            conf: TradingAccount {
+                broker_id: "paper".into(),
+                account: "001".into(),
+                ..Default::default()
+            },
+            options,
+            book: AccountBook::default(),
+            event_sender,
+            md_sender,
+            exit_sender: None,
+            exit_done: None,
+            working: HashMap::new(),
+            conditional: HashMap::new(),
+            parked: HashMap::new(),
+            replay: VecDeque::new(),
+            order_sys_id: 0,
+            trade_id: 0,
+            parked_id: 0,
+        };
+        p.connect().unwrap();
+        p
+    }
+
+    fn buy_open(price: f64) -> OrderInput {
+        OrderInput {
+            exchange: "SHFE".into(),
+            symbol: "rb2305".into(),
+            direction: DIRECTION_BUY,
+            offset: OFFSET_OPEN,
+            price,
+            volume: 1,
+            ..Default::default()
+        }
+    }
+
+    fn tick(last: f64, ask: f64) -> MarketDataRow {
+        MarketDataRow {
+            exchange: "SHFE".into(),
+            symbol: "rb2305".into(),
+            last_price: last,
+            ask_prices: vec![ask],
+            ask_volumes: vec![10],
+            bid_prices: vec![ask - 1.0],
+            bid_volumes: vec![10],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn configured_instruments_allow_orders_without_ctp() {
+        let mut p = paper();
+        assert_eq!(p.book.instruments["SHFE:rb2305"].volume_multiple, 10);
+        let (key, _) = p.insert_order(&buy_open(4000.0)).unwrap();
+        assert_eq!(p.book.orders[&key].status, ORDER_NO_TRADE_QUEUEING);
+        p.on_tick(tick(3990.0, 3990.0));
+        assert_eq!(p.book.orders[&key].status, ORDER_ALL_TRADED);
+        let pk = format!("SHFE:rb2305:{}", POSI_LONG);
+        // 挂单被动成交, 以报单价格成交
+        assert_eq!(p.book.positions[&pk].open_cost, 40000.0);
+    }
+
+    #[test]
+    fn conditional_order_waits_for_trigger() {
+        let mut p = paper();
+        let mut input = buy_open(4100.0);
+        input.contingent_condition = THOST_FTDC_CC_LastPriceGreaterEqualStopPrice as i32;
+        input.stop_price = 4050.0;
+        let (key, _) = p.insert_order(&input).unwrap();
+        assert_eq!(p.book.orders[&key].status, ORDER_NOT_TOUCHED);
+        assert!(p.book.parked_orders[&key].conditional);
+
+        p.on_tick(tick(4000.0, 4001.0));
+        assert_eq!(p.book.orders[&key].status, ORDER_NOT_TOUCHED);
+
+        p.on_tick(tick(4050.0, 4051.0));
+        assert_eq!(p.book.orders[&key].status, ORDER_ALL_TRADED);
+        assert_eq!(p.book.parked_orders[&key].status, ORDER_TOUCHED);
+        assert_eq!(p.book.trades.values().next().unwrap().price, 4051.0);
+    }
+
+    #[test]
+    fn canceled_conditional_order_never_triggers() {
+        let mut p = paper();
+        let mut input = buy_open(4100.0);
+        input.contingent_condition = THOST_FTDC_CC_Touch as i32;
+        input.stop_price = 4050.0;
+        let (key, _) = p.insert_order(&input).unwrap();
+        p.remove_parked_order(&key).unwrap();
+        assert_eq!(p.book.parked_orders[&key].status, ORDER_CANCELED);
+        p.on_tick(tick(4060.0, 4061.0));
+        assert_eq!(p.book.orders[&key].status, ORDER_CANCELED);
+        assert!(p.book.trades.is_empty());
+    }
+
+    #[test]
+    fn parked_order_is_sent_on_next_tick() {
+        let mut p = paper();
+        let mut rx = p.insert_parked_order(&buy_open(4000.0)).unwrap();
+        let id = rx.try_recv().unwrap().unwrap();
+        let mut removed = p.insert_parked_order(&buy_open(3900.0)).unwrap();
+        let removed = removed.try_recv().unwrap().unwrap();
+        p.remove_parked_order(&removed).unwrap();
+        assert_eq!(p.book.parked_orders[&id].status, PARKED_NOT_SEND);
+        assert!(p.book.orders.is_empty());
+
+        p.on_tick(tick(4100.0, 4101.0));
+        assert_eq!(p.book.parked_orders[&id].status, PARKED_SEND);
+        assert_eq!(p.book.parked_orders[&removed].status, PARKED_DELETED);
+        assert_eq!(p.book.orders.len(), 1);
+        let o = p.book.orders.values().next().unwrap();
+        assert_eq!((o.limit_price, o.status), (4000.0, ORDER_NO_TRADE_QUEUEING));
+    }
+
+    #[test]
+    fn non_price_conditions_are_unsupported() {
+        let mut p = paper();
+        let mut input = buy_open(4000.0);
+        input.contingent_condition = THOST_FTDC_CC_ParkedOrder as i32;
+        input.stop_price = 4000.0;
+        assert!(matches!(p.insert_order(&input), Err(Error::Unsupported(_))));
+    }
+}
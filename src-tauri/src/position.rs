@@ -0,0 +1,417 @@
+use crate::config::*;
+use crate::gateway::*;
+use std::collections::HashMap;
+
+/// 开仓对应的持仓方向, 平仓时为被平的持仓方向
+pub fn posi_direction(direction: i32, offset: i32) -> i32 {
+    let buy = direction == DIRECTION_BUY;
+    let open = offset == OFFSET_OPEN;
+    if buy == open {
+        POSI_LONG
+    } else {
+        POSI_SHORT
+    }
+}
+
+/// 持仓方向对应的开仓买卖方向
+pub fn open_direction(posi_direction: i32) -> i32 {
+    if posi_direction == POSI_LONG {
+        DIRECTION_BUY
+    } else {
+        DIRECTION_SELL
+    }
+}
+
+impl AccountBook {
+    /// 成交更新持仓和持仓明细, 返回逐笔平仓盈亏
+    pub fn apply_trade(&mut self, t: &TradeRow, multiple: i32) -> f64 {
+        let direction = posi_direction(t.direction, t.offset);
+        let k = format!("{}:{}:{}", t.exchange, t.symbol, direction);
+        let multiple = multiple.max(1) as f64;
+        if t.offset == OFFSET_OPEN {
+            let amount = t.price * t.volume as f64 * multiple;
+            let p = self.positions.entry(k).or_insert_with(|| PositionRow {
+                broker_id: t.broker_id.clone(),
+                account: t.account.clone(),
+                exchange: t.exchange.clone(),
+                symbol: t.symbol.clone(),
+                direction,
+                ..Default::default()
+            });
+            p.position += t.volume;
+            p.today_position += t.volume;
+            p.open_volume += t.volume;
+            p.open_cost += amount;
+            p.open_amount += amount;
+            let d = PositionDetailRow {
+                exchange: t.exchange.clone(),
+                symbol: t.symbol.clone(),
+                direction: t.direction,
+                volume: t.volume,
+                volume_closed: 0,
+                trade_id: t.trade_id.clone(),
+                open_price: t.price,
+                open_date: self.funds.trading_day.clone(),
+            };
+            self.position_details.insert(d.key(), d);
+            return 0.0;
+        }
+        let (position, yd_position, open_cost) = self
+            .positions
+            .get(&k)
+            .map_or((0, 0, 0.0), |p| (p.position, p.yd_position, p.open_cost));
+        let avg_price = if position > 0 {
+            open_cost / (position as f64 * multiple)
+        } else {
+            t.price
+        };
+        // 平今只平今仓, 平昨只平昨仓, 平仓先平昨仓
+        let close_today = if t.offset == OFFSET_CLOSE_TODAY {
+            t.volume
+        } else if t.offset == OFFSET_CLOSE_YESTERDAY {
+            0
+        } else {
+            (t.volume - yd_position.max(0)).max(0)
+        };
+        let trading_day = self.funds.trading_day.clone();
+        let od = open_direction(direction);
+        let mut details = self
+            .position_details
+            .values()
+            .filter(|d| {
+                d.exchange == t.exchange
+                    && d.symbol == t.symbol
+                    && d.direction == od
+                    && d.volume > 0
+            })
+            .map(|d| {
+                (
+                    d.open_date == trading_day,
+                    d.open_date.clone(),
+                    d.trade_id.clone(),
+                    d.key(),
+                )
+            })
+            .collect::<Vec<_>>();
+        details.sort();
+        let mut today_left = close_today;
+        let mut yd_left = t.volume - close_today;
+        let mut close_profit = 0.0;
+        let mut closed_cost = 0.0;
+        for (today, _, _, dk) in details {
+            let left = if today { &mut today_left } else { &mut yd_left };
+            if *left == 0 {
+                continue;
+            }
+            if let Some(d) = self.position_details.get_mut(&dk) {
+                let n = (*left).min(d.volume);
+                d.volume -= n;
+                d.volume_closed += n;
+                *left -= n;
+                close_profit += (t.price - d.open_price) * n as f64 * multiple;
+                closed_cost += d.open_price * n as f64 * multiple;
+            }
+        }
+        // 没有对应持仓明细的部分按持仓均价计算
+        let unmatched = (today_left + yd_left) as f64;
+        close_profit += (t.price - avg_price) * unmatched * multiple;
+        closed_cost += avg_price * unmatched * multiple;
+        if direction == POSI_SHORT {
+            close_profit = -close_profit;
+        }
+        if let Some(p) = self.positions.get_mut(&k) {
+            p.position -= t.volume;
+            p.today_position -= close_today;
+            p.yd_position -= t.volume - close_today;
+            p.open_cost -= closed_cost;
+            if p.position <= 0 {
+                p.position = 0;
+                p.today_position = 0;
+                p.yd_position = 0;
+                p.open_cost = 0.0;
+            }
+        }
+        close_profit
+    }
+
+    /// 按行情重新计算该合约持仓的浮动盈亏和盯市盈亏, 并汇总账户的持仓盈亏
+    pub fn revalue(&mut self, md: &MarketDataRow) {
+        let price = md.valuation_price();
+        if price <= 0.0 {
+            return;
+        }
+        let multiple = self
+            .instruments
+            .get(&md.key())
+            .map_or(1, |i| i.volume_multiple.max(1)) as f64;
+        // 今仓明细按开仓买卖方向汇总的数量和开仓金额
+        let mut today = HashMap::new();
+        for d in self.position_details.values().filter(|d| {
+            d.exchange == md.exchange
+                && d.symbol == md.symbol
+                && d.volume > 0
+                && d.open_date == self.funds.trading_day
+        }) {
+            let e = today.entry(d.direction).or_insert((0, 0.0));
+            e.0 += d.volume;
+            e.1 += d.open_price * d.volume as f64;
+        }
+        for p in self
+            .positions
+            .values_mut()
+            .filter(|p| p.exchange == md.exchange && p.symbol == md.symbol)
+        {
+            p.last_price = price;
+            let value = price * p.position as f64 * multiple;
+            let short = p.direction == POSI_SHORT;
+            p.float_profit = if short {
+                p.open_cost - value
+            } else {
+                value - p.open_cost
+            };
+            if p.position <= 0 {
+                p.position_profit = 0.0;
+                continue;
+            }
+            let avg = p.open_cost / (p.position as f64 * multiple);
+            let today_price = match today.get(&open_direction(p.direction)) {
+                Some((v, amount)) if *v > 0 => amount / *v as f64,
+                _ => avg,
+            };
+            let yd_price = if md.pre_settlement_price > 0.0 {
+                md.pre_settlement_price
+            } else {
+                avg
+            };
+            let diff = ((price - today_price) * p.today_position as f64
+                + (price - yd_price) * p.yd_position as f64)
+                * multiple;
+            p.position_profit = if short { -diff } else { diff };
+        }
+        self.funds.position_profit = self.positions.values().map(|p| p.position_profit).sum();
+    }
+
+    /// 持仓变化后使用行情快照重新估值
+    pub fn revalue_all(&mut self) {
+        let snapshots = self
+            .positions
+            .values()
+            .filter_map(|p| {
+                self.depth_snapshots
+                    .get(&format!("{}:{}", p.exchange, p.symbol))
+                    .cloned()
+            })
+            .collect::<Vec<_>>();
+        for md in snapshots.iter() {
+            self.revalue(md);
+        }
+    }
+
+    pub fn float_profit(&self) -> f64 {
+        self.positions.values().map(|p| p.float_profit).sum()
+    }
+
+    /// 按未完成的平仓单重新计算持仓冻结数量
+    pub fn update_frozen(&mut self) {
+        let mut frozen = HashMap::new();
+        for o in self
+            .orders
+            .values()
+            .filter(|o| o.is_active() && o.offset != OFFSET_OPEN)
+        {
+            let k = format!(
+                "{}:{}:{}",
+                o.exchange,
+                o.symbol,
+                posi_direction(o.direction, o.offset)
+            );
+            *frozen.entry(k).or_insert(0) += o.volume_total;
+        }
+        for (k, p) in self.positions.iter_mut() {
+            p.frozen = frozen.get(k).cloned().unwrap_or(0);
+        }
+    }
+
+    /// 可平数量, 扣除未成交平仓单冻结的数量
+    pub fn closable_volume(&self, exchange: &str, symbol: &str, posi_direction: i32) -> i32 {
+        self.positions
+            .get(&format!("{exchange}:{symbol}:{posi_direction}"))
+            .map_or(0, |p| p.position - p.frozen)
+    }
+
+    /// 与经纪商查询的持仓比较, 返回数量不一致的持仓key
+    pub fn position_mismatches(&self, queried: &HashMap<String, PositionRow>) -> Vec<String> {
+        let volumes = |p: Option<&PositionRow>| {
+            p.map_or((0, 0, 0), |p| (p.position, p.today_position, p.yd_position))
+        };
+        let mut keys = self
+            .positions
+            .keys()
+            .chain(queried.keys())
+            .filter(|k| volumes(self.positions.get(*k)) != volumes(queried.get(*k)))
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MULTIPLE: i32 = 10;
+    const BUY: i32 = DIRECTION_BUY;
+    const SELL: i32 = DIRECTION_SELL;
+    const OPEN: i32 = OFFSET_OPEN;
+    const CLOSE: i32 = OFFSET_CLOSE;
+    const CLOSE_TODAY: i32 = OFFSET_CLOSE_TODAY;
+    const CLOSE_YESTERDAY: i32 = OFFSET_CLOSE_YESTERDAY;
+
+    fn trade(trade_id: &str, direction: i32, offset: i32, price: f64, volume: i32) -> TradeRow {
+        TradeRow {
+            trade_id: trade_id.into(),
+            exchange: "SHFE".into(),
+            symbol: "rb2305".into(),
+            direction,
+            offset,
+            price,
+            volume,
+            ..Default::default()
+        }
+    }
+
+    fn long_key() -> String {
+        format!("SHFE:rb2305:{}", POSI_LONG)
+    }
+
+    /// 昨仓1手@3900, 今仓2手@4000
+    fn book_with_yesterday() -> AccountBook {
+        let mut book = AccountBook::default();
+        book.funds.trading_day = "20230314".into();
+        book.positions.insert(
+            long_key(),
+            PositionRow {
+                exchange: "SHFE".into(),
+                symbol: "rb2305".into(),
+                direction: POSI_LONG,
+                position: 1,
+                yd_position: 1,
+                open_cost: 39000.0,
+                ..Default::default()
+            },
+        );
+        let d = PositionDetailRow {
+            exchange: "SHFE".into(),
+            symbol: "rb2305".into(),
+            direction: BUY,
+            volume: 1,
+            volume_closed: 0,
+            trade_id: "1".into(),
+            open_price: 3900.0,
+            open_date: "20230313".into(),
+        };
+        book.position_details.insert(d.key(), d);
+        let t = trade("2", BUY, OPEN, 4000.0, 2);
+        book.apply_trade(&t, MULTIPLE);
+        book
+    }
+
+    #[test]
+    fn open_adds_today_position_and_detail() {
+        let mut book = AccountBook::default();
+        book.funds.trading_day = "20230314".into();
+        let t = trade("1", BUY, OPEN, 4000.0, 2);
+        assert_eq!(book.apply_trade(&t, MULTIPLE), 0.0);
+        let p = &book.positions[&long_key()];
+        assert_eq!(p.position, 2);
+        assert_eq!(p.today_position, 2);
+        assert_eq!(p.yd_position, 0);
+        assert_eq!(p.open_cost, 80000.0);
+        let d = &book.position_details["SHFE:rb2305:1"];
+        assert_eq!(d.volume, 2);
+        assert_eq!(d.open_date, "20230314");
+    }
+
+    #[test]
+    fn close_splits_yesterday_before_today() {
+        let mut book = book_with_yesterday();
+        let t = trade("3", SELL, CLOSE, 4100.0, 2);
+        // 昨仓(4100-3900)*10 + 今仓(4100-4000)*10
+        assert_eq!(book.apply_trade(&t, MULTIPLE), 3000.0);
+        let p = &book.positions[&long_key()];
+        assert_eq!(p.position, 1);
+        assert_eq!(p.yd_position, 0);
+        assert_eq!(p.today_position, 1);
+        assert_eq!(p.open_cost, 40000.0);
+        assert_eq!(book.position_details["SHFE:rb2305:1"].volume, 0);
+        assert_eq!(book.position_details["SHFE:rb2305:2"].volume, 1);
+    }
+
+    #[test]
+    fn close_today_and_close_yesterday() {
+        let mut book = book_with_yesterday();
+        let t = trade("3", SELL, CLOSE_TODAY, 4100.0, 1);
+        assert_eq!(book.apply_trade(&t, MULTIPLE), 1000.0);
+        let p = &book.positions[&long_key()];
+        assert_eq!((p.position, p.yd_position, p.today_position), (2, 1, 1));
+        assert_eq!(book.position_details["SHFE:rb2305:1"].volume, 1);
+
+        let t = trade("4", SELL, CLOSE_YESTERDAY, 4100.0, 1);
+        assert_eq!(book.apply_trade(&t, MULTIPLE), 2000.0);
+        let p = &book.positions[&long_key()];
+        assert_eq!((p.position, p.yd_position, p.today_position), (1, 0, 1));
+        assert_eq!(book.position_details["SHFE:rb2305:1"].volume, 0);
+    }
+
+    #[test]
+    fn short_close_profit_is_negated() {
+        let mut book = AccountBook::default();
+        let t = trade("1", SELL, OPEN, 4000.0, 1);
+        book.apply_trade(&t, MULTIPLE);
+        let t = trade("2", BUY, CLOSE, 3900.0, 1);
+        assert_eq!(book.apply_trade(&t, MULTIPLE), 1000.0);
+    }
+
+    #[test]
+    fn overclose_clamps_position_to_zero() {
+        let mut book = AccountBook::default();
+        let t = trade("1", BUY, OPEN, 4000.0, 1);
+        book.apply_trade(&t, MULTIPLE);
+        let t = trade("2", SELL, CLOSE, 4100.0, 3);
+        // 没有持仓明细的2手按持仓均价4000计算
+        assert_eq!(book.apply_trade(&t, MULTIPLE), 3000.0);
+        let p = &book.positions[&long_key()];
+        assert_eq!(p.position, 0);
+        assert_eq!(p.today_position, 0);
+        assert_eq!(p.yd_position, 0);
+        assert_eq!(p.open_cost, 0.0);
+    }
+
+    #[test]
+    fn revalue_marks_yesterday_to_pre_settlement() {
+        let mut book = book_with_yesterday();
+        let i = InstrumentRow {
+            exchange: "SHFE".into(),
+            symbol: "rb2305".into(),
+            volume_multiple: MULTIPLE,
+            ..Default::default()
+        };
+        book.instruments.insert(i.key(), i);
+        let md = MarketDataRow {
+            exchange: "SHFE".into(),
+            symbol: "rb2305".into(),
+            last_price: 4050.0,
+            pre_settlement_price: 3950.0,
+            ..Default::default()
+        };
+        book.revalue(&md);
+        let p = &book.positions[&long_key()];
+        // 浮动盈亏按开仓成本: 40500*3 - 119000
+        assert_eq!(p.float_profit, 2500.0);
+        // 盯市盈亏: 昨仓(4050-3950)*10 + 今仓(4050-4000)*2*10
+        assert_eq!(p.position_profit, 2000.0);
+        assert_eq!(book.funds.position_profit, 2000.0);
+    }
+}
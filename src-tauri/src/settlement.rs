@@ -0,0 +1,598 @@
+use crate::config::*;
+use ctp_futures::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// 资金状况
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SettlementAccount {
+    pub pre_balance: f64,
+    /// 出入金净额
+    pub deposit_withdrawal: f64,
+    pub close_profit: f64,
+    /// 持仓盯市盈亏
+    pub position_profit: f64,
+    pub commission: f64,
+    pub balance: f64,
+    pub equity: f64,
+    pub margin: f64,
+    pub available: f64,
+    /// 百分比, 14.33%为14.33
+    pub risk_degree: f64,
+    pub margin_call: f64,
+}
+
+/// 出入金明细
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SettlementCashFlow {
+    pub date: String,
+    pub kind: String,
+    pub deposit: f64,
+    pub withdrawal: f64,
+    pub note: String,
+}
+
+/// 成交记录, direction/offset使用CTP的字符值
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SettlementTrade {
+    pub date: String,
+    pub exchange: String,
+    pub product: String,
+    pub symbol: String,
+    pub direction: i32,
+    pub hedge: String,
+    pub price: f64,
+    pub volume: i32,
+    pub turnover: f64,
+    pub offset: i32,
+    pub commission: f64,
+    pub close_profit: f64,
+    pub trade_id: String,
+}
+
+/// 平仓明细, direction为平仓方向
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SettlementClosedPosition {
+    pub close_date: String,
+    pub exchange: String,
+    pub product: String,
+    pub symbol: String,
+    pub open_date: String,
+    pub direction: i32,
+    pub volume: i32,
+    pub open_price: f64,
+    pub pre_settlement_price: f64,
+    pub price: f64,
+    pub close_profit: f64,
+}
+
+/// 持仓明细, direction为开仓方向
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SettlementPositionDetail {
+    pub exchange: String,
+    pub product: String,
+    pub symbol: String,
+    pub open_date: String,
+    pub hedge: String,
+    pub direction: i32,
+    pub volume: i32,
+    pub open_price: f64,
+    pub pre_settlement_price: f64,
+    pub settlement_price: f64,
+    /// 按开仓价计算的浮动盈亏
+    pub float_profit: f64,
+    pub position_profit: f64,
+    pub margin: f64,
+}
+
+/// 持仓汇总, 多空在同一行
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SettlementPosition {
+    pub product: String,
+    pub symbol: String,
+    pub long_position: i32,
+    pub long_avg_price: f64,
+    pub short_position: i32,
+    pub short_avg_price: f64,
+    pub pre_settlement_price: f64,
+    pub settlement_price: f64,
+    pub position_profit: f64,
+    pub margin: f64,
+    pub hedge: String,
+}
+
+/// 解析后的结算单
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SettlementStatement {
+    pub account: String,
+    pub trading_day: String,
+    pub summary: SettlementAccount,
+    pub cash_flows: Vec<SettlementCashFlow>,
+    pub trades: Vec<SettlementTrade>,
+    pub closed_positions: Vec<SettlementClosedPosition>,
+    pub position_details: Vec<SettlementPositionDetail>,
+    pub positions: Vec<SettlementPosition>,
+}
+
+/// 结算单与本地数据不一致的记录
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct ReconcileDiff {
+    pub key: String,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Section {
+    Header,
+    Summary,
+    CashFlow,
+    Trade,
+    Closed,
+    PositionDetail,
+    Position,
+}
+
+/// 各部分的中文标题
+const SECTION_TITLES: [(&str, Section); 6] = [
+    ("资金状况", Section::Summary),
+    ("出入金明细", Section::CashFlow),
+    ("成交记录", Section::Trade),
+    ("平仓明细", Section::Closed),
+    ("持仓明细", Section::PositionDetail),
+    ("持仓汇总", Section::Position),
+];
+
+/// 表格, 列按中文表头查找, 不依赖列的顺序
+#[derive(Default)]
+struct Table {
+    header: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    fn push(&mut self, cells: Vec<String>) {
+        if self.header.len() == 0 {
+            self.header = cells.iter().map(|c| c.replace(' ', "")).collect();
+            return;
+        }
+        // 跳过合计行和英文表头
+        let total = cells.first().map_or(false, |c| c.starts_with('共'));
+        let english = cells.iter().all(|c| c.is_ascii()) && !starts_with_digit(&cells);
+        if !total && !english {
+            self.rows.push(cells);
+        }
+    }
+
+    fn get<'a>(&self, row: &'a [String], name: &str) -> &'a str {
+        self.header
+            .iter()
+            .position(|h| h == name)
+            .and_then(|i| row.get(i))
+            .map_or("", |c| c.as_str())
+    }
+}
+
+fn starts_with_digit(cells: &[String]) -> bool {
+    cells
+        .first()
+        .and_then(|c| c.chars().next())
+        .map_or(false, |c| c.is_ascii_digit())
+}
+
+fn number(s: &str) -> f64 {
+    s.trim()
+        .trim_end_matches('%')
+        .replace(',', "")
+        .parse()
+        .unwrap_or(0.0)
+}
+
+fn volume(s: &str) -> i32 {
+    s.trim().replace(',', "").parse().unwrap_or(0)
+}
+
+/// 标签的中文部分, 去掉空格, 如"出 入 金 Deposit/Withdrawal"为"出入金"
+fn label_name(label: &str) -> String {
+    label
+        .chars()
+        .take_while(|c| !c.is_ascii_alphabetic())
+        .filter(|c| !c.is_whitespace())
+        .collect()
+}
+
+/// 解析一行中的"标签：值", 一行可能有多组
+fn key_values(line: &str) -> Vec<(String, String)> {
+    let line = line.replace('：', ":");
+    let mut v = vec![];
+    let mut rest = line.as_str();
+    while let Some(i) = rest.find(':') {
+        let label = label_name(rest[..i].trim());
+        let after = rest[i + 1..].trim_start_matches(':').trim_start();
+        let end = after.find(char::is_whitespace).unwrap_or(after.len());
+        v.push((label, after[..end].to_string()));
+        rest = &after[end..];
+    }
+    v
+}
+
+fn exchange_code(name: &str) -> String {
+    match name {
+        "上期所" => "SHFE",
+        "大商所" => "DCE",
+        "郑商所" => "CZCE",
+        "中金所" => "CFFEX",
+        "能源中心" => "INE",
+        "广期所" => "GFEX",
+        _ => name,
+    }
+    .to_string()
+}
+
+fn direction(s: &str) -> i32 {
+    if s.starts_with('买') {
+        THOST_FTDC_D_Buy as i32
+    } else {
+        THOST_FTDC_D_Sell as i32
+    }
+}
+
+fn offset(s: &str) -> i32 {
+    match s {
+        "开" => THOST_FTDC_OF_Open as i32,
+        "平今" => THOST_FTDC_OF_CloseToday as i32,
+        "平昨" => THOST_FTDC_OF_CloseYesterday as i32,
+        "强平" => THOST_FTDC_OF_ForceClose as i32,
+        _ => THOST_FTDC_OF_Close as i32,
+    }
+}
+
+impl SettlementStatement {
+    /// 解析CTP标准格式的结算单文本
+    pub fn parse(content: &str) -> Self {
+        let mut statement = SettlementStatement::default();
+        let mut section = Section::Header;
+        let mut tables: HashMap<Section, Table> = HashMap::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.starts_with('|') {
+                let cells = line
+                    .trim_matches('|')
+                    .split('|')
+                    .map(|c| c.trim().to_string())
+                    .collect();
+                tables.entry(section).or_default().push(cells);
+                continue;
+            }
+            if let Some((_, s)) = SECTION_TITLES.iter().find(|(t, _)| line.contains(t)) {
+                section = *s;
+                continue;
+            }
+            match section {
+                Section::Header => {
+                    for (k, v) in key_values(line) {
+                        match k.as_str() {
+                            "客户号" => statement.account = v,
+                            "日期" => statement.trading_day = v,
+                            _ => {}
+                        }
+                    }
+                }
+                Section::Summary => statement.summary.parse_line(line),
+                _ => {}
+            }
+        }
+        if let Some(t) = tables.get(&Section::CashFlow) {
+            statement.cash_flows = t
+                .rows
+                .iter()
+                .map(|r| SettlementCashFlow {
+                    date: t.get(r, "发生日期").to_string(),
+                    kind: t.get(r, "出入金类型").to_string(),
+                    deposit: number(t.get(r, "入金")),
+                    withdrawal: number(t.get(r, "出金")),
+                    note: t.get(r, "说明").to_string(),
+                })
+                .collect();
+        }
+        if let Some(t) = tables.get(&Section::Trade) {
+            statement.trades = t
+                .rows
+                .iter()
+                .map(|r| SettlementTrade {
+                    date: t.get(r, "成交日期").to_string(),
+                    exchange: exchange_code(t.get(r, "交易所")),
+                    product: t.get(r, "品种").to_string(),
+                    symbol: t.get(r, "合约").to_string(),
+                    direction: direction(t.get(r, "买/卖")),
+                    hedge: t.get(r, "投/保").to_string(),
+                    price: number(t.get(r, "成交价")),
+                    volume: volume(t.get(r, "手数")),
+                    turnover: number(t.get(r, "成交额")),
+                    offset: offset(t.get(r, "开平")),
+                    commission: number(t.get(r, "手续费")),
+                    close_profit: number(t.get(r, "平仓盈亏")),
+                    trade_id: t.get(r, "成交序号").to_string(),
+                })
+                .collect();
+        }
+        if let Some(t) = tables.get(&Section::Closed) {
+            statement.closed_positions = t
+                .rows
+                .iter()
+                .map(|r| SettlementClosedPosition {
+                    close_date: t.get(r, "平仓日期").to_string(),
+                    exchange: exchange_code(t.get(r, "交易所")),
+                    product: t.get(r, "品种").to_string(),
+                    symbol: t.get(r, "合约").to_string(),
+                    open_date: t.get(r, "开仓日期").to_string(),
+                    direction: direction(t.get(r, "买/卖")),
+                    volume: volume(t.get(r, "手数")),
+                    open_price: number(t.get(r, "开仓价")),
+                    pre_settlement_price: number(t.get(r, "昨结算")),
+                    price: number(t.get(r, "成交价")),
+                    close_profit: number(t.get(r, "平仓盈亏")),
+                })
+                .collect();
+        }
+        if let Some(t) = tables.get(&Section::PositionDetail) {
+            statement.position_details = t
+                .rows
+                .iter()
+                .map(|r| SettlementPositionDetail {
+                    exchange: exchange_code(t.get(r, "交易所")),
+                    product: t.get(r, "品种").to_string(),
+                    symbol: t.get(r, "合约").to_string(),
+                    open_date: t.get(r, "开仓日期").to_string(),
+                    hedge: t.get(r, "投/保").to_string(),
+                    direction: direction(t.get(r, "买/卖")),
+                    volume: volume(t.get(r, "持仓量")),
+                    open_price: number(t.get(r, "开仓价")),
+                    pre_settlement_price: number(t.get(r, "昨结算")),
+                    settlement_price: number(t.get(r, "结算价")),
+                    float_profit: number(t.get(r, "浮动盈亏")),
+                    position_profit: number(t.get(r, "盯市盈亏")),
+                    margin: number(t.get(r, "保证金")),
+                })
+                .collect();
+        }
+        if let Some(t) = tables.get(&Section::Position) {
+            statement.positions = t
+                .rows
+                .iter()
+                .map(|r| SettlementPosition {
+                    product: t.get(r, "品种").to_string(),
+                    symbol: t.get(r, "合约").to_string(),
+                    long_position: volume(t.get(r, "买持")),
+                    long_avg_price: number(t.get(r, "买均价")),
+                    short_position: volume(t.get(r, "卖持")),
+                    short_avg_price: number(t.get(r, "卖均价")),
+                    pre_settlement_price: number(t.get(r, "昨结算")),
+                    settlement_price: number(t.get(r, "今结算")),
+                    position_profit: number(t.get(r, "持仓盯市盈亏")),
+                    margin: number(t.get(r, "保证金占用")),
+                    hedge: t.get(r, "投/保").to_string(),
+                })
+                .collect();
+        }
+        statement
+    }
+
+    /// 与本地成交核对, 按成交序号匹配, trades应为结算单同一交易日的成交
+    pub fn reconcile_trades(&self, trades: &[TradeRow]) -> Vec<ReconcileDiff> {
+        let local = trades
+            .iter()
+            .map(|t| ((t.symbol.clone(), t.trade_id.trim().to_string()), t))
+            .collect::<HashMap<_, _>>();
+        let mut diffs = vec![];
+        for s in self.trades.iter() {
+            let key = format!("{}:{}:{}", s.exchange, s.symbol, s.trade_id);
+            match local.get(&(s.symbol.clone(), s.trade_id.clone())) {
+                None => diffs.push(ReconcileDiff {
+                    key,
+                    description: "本地没有该成交".into(),
+                }),
+                Some(t) if t.volume != s.volume || (t.price - s.price).abs() > 1e-6 => {
+                    diffs.push(ReconcileDiff {
+                        key,
+                        description: format!(
+                            "成交不一致 结算单={}@{} 本地={}@{}",
+                            s.volume, s.price, t.volume, t.price
+                        ),
+                    })
+                }
+                _ => {}
+            }
+        }
+        for t in trades.iter().filter(|t| {
+            !self
+                .trades
+                .iter()
+                .any(|s| s.symbol == t.symbol && s.trade_id == t.trade_id.trim())
+        }) {
+            diffs.push(ReconcileDiff {
+                key: t.key(),
+                description: "结算单没有该成交".into(),
+            });
+        }
+        diffs
+    }
+
+    /// 与本地持仓核对, 结算单的持仓汇总没有交易所, 按合约和方向匹配
+    pub fn reconcile_positions(&self, positions: &[PositionRow]) -> Vec<ReconcileDiff> {
+        let mut statement = HashMap::new();
+        for p in self.positions.iter() {
+            *statement
+                .entry((p.symbol.clone(), THOST_FTDC_PD_Long as i32))
+                .or_insert(0) += p.long_position;
+            *statement
+                .entry((p.symbol.clone(), THOST_FTDC_PD_Short as i32))
+                .or_insert(0) += p.short_position;
+        }
+        let mut local = HashMap::new();
+        for p in positions.iter() {
+            *local.entry((p.symbol.clone(), p.direction)).or_insert(0) += p.position;
+        }
+        let mut keys = statement
+            .keys()
+            .chain(local.keys())
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        keys.into_iter()
+            .filter_map(|k| {
+                let s = statement.get(&k).cloned().unwrap_or(0);
+                let l = local.get(&k).cloned().unwrap_or(0);
+                if s == l {
+                    return None;
+                }
+                Some(ReconcileDiff {
+                    key: format!("{}:{}", k.0, k.1),
+                    description: format!("持仓不一致 结算单={} 本地={}", s, l),
+                })
+            })
+            .collect()
+    }
+}
+
+impl SettlementAccount {
+    fn parse_line(&mut self, line: &str) {
+        for (k, v) in key_values(line) {
+            let v = number(&v);
+            match k.as_str() {
+                "期初结存" => self.pre_balance = v,
+                "出入金" => self.deposit_withdrawal = v,
+                "平仓盈亏" => self.close_profit = v,
+                "持仓盯市盈亏" => self.position_profit = v,
+                "手续费" => self.commission = v,
+                "期末结存" => self.balance = v,
+                "客户权益" => self.equity = v,
+                "保证金占用" => self.margin = v,
+                "可用资金" => self.available = v,
+                "风险度" => self.risk_degree = v,
+                "应追加资金" => self.margin_call = v,
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MTM: &str = include_str!("../testdata/settlement/mtm_20230314.txt");
+    const NO_TRADES: &str = include_str!("../testdata/settlement/mtm_20230315_no_trades.txt");
+
+    #[test]
+    fn parse_header_and_summary() {
+        let s = SettlementStatement::parse(MTM);
+        assert_eq!(s.account, "00001234");
+        assert_eq!(s.trading_day, "20230314");
+        assert_eq!(
+            s.summary,
+            SettlementAccount {
+                pre_balance: 1000000.0,
+                deposit_withdrawal: 80000.0,
+                close_profit: 600.0,
+                position_profit: 6600.0,
+                commission: 75.0,
+                balance: 1087125.0,
+                equity: 1087125.0,
+                margin: 155760.0,
+                available: 931365.0,
+                risk_degree: 14.33,
+                margin_call: 0.0,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_tables() {
+        let s = SettlementStatement::parse(MTM);
+        assert_eq!(s.cash_flows.len(), 2);
+        assert_eq!(s.cash_flows[1].withdrawal, 20000.0);
+        assert_eq!(s.cash_flows[1].note, "转出");
+
+        assert_eq!(s.trades.len(), 3);
+        let t = &s.trades[1];
+        assert_eq!(t.exchange, "SHFE");
+        assert_eq!(t.symbol, "rb2305");
+        assert_eq!(t.direction, THOST_FTDC_D_Sell as i32);
+        assert_eq!(t.offset, THOST_FTDC_OF_CloseYesterday as i32);
+        assert_eq!(t.price, 4180.0);
+        assert_eq!(t.volume, 1);
+        assert_eq!(t.close_profit, 600.0);
+        assert_eq!(t.trade_id, "1002");
+        assert_eq!(s.trades[2].exchange, "CFFEX");
+        assert_eq!(s.trades[2].offset, THOST_FTDC_OF_Open as i32);
+
+        assert_eq!(s.closed_positions.len(), 1);
+        assert_eq!(s.closed_positions[0].open_date, "20230313");
+        assert_eq!(s.closed_positions[0].open_price, 4100.0);
+
+        assert_eq!(s.position_details.len(), 3);
+        assert_eq!(s.position_details.iter().map(|d| d.volume).sum::<i32>(), 4);
+        assert_eq!(s.position_details[2].direction, THOST_FTDC_D_Sell as i32);
+        assert_eq!(s.position_details[2].margin, 143280.0);
+
+        assert_eq!(s.positions.len(), 2);
+        assert_eq!(s.positions[0].long_position, 3);
+        assert_eq!(s.positions[1].short_position, 1);
+        assert_eq!(s.positions[1].settlement_price, 3980.0);
+        assert_eq!(
+            s.positions.iter().map(|p| p.margin).sum::<f64>(),
+            s.summary.margin
+        );
+    }
+
+    #[test]
+    fn parse_statement_without_trades() {
+        let s = SettlementStatement::parse(NO_TRADES);
+        assert_eq!(s.account, "00005678");
+        assert_eq!(s.trading_day, "20230315");
+        assert_eq!(s.summary.balance, 500000.0);
+        assert_eq!(s.summary.risk_degree, 0.0);
+        assert!(s.cash_flows.is_empty());
+        assert!(s.trades.is_empty());
+        assert!(s.positions.is_empty());
+    }
+
+    #[test]
+    fn reconcile_with_local_rows() {
+        let s = SettlementStatement::parse(MTM);
+        let trade = |symbol: &str, trade_id: &str, price: f64, volume: i32| TradeRow {
+            symbol: symbol.into(),
+            trade_id: trade_id.into(),
+            price,
+            volume,
+            ..Default::default()
+        };
+        let trades = vec![
+            trade("rb2305", "        1001", 4150.0, 2),
+            trade("rb2305", "        1002", 4180.0, 2),
+            trade("rb2305", "        1003", 4180.0, 1),
+        ];
+        let diffs = s.reconcile_trades(&trades);
+        assert_eq!(diffs.len(), 3);
+        assert!(diffs[0].key.ends_with("1002"));
+        assert_eq!(diffs[1].key, "CFFEX:IF2303:2001");
+        assert_eq!(diffs[1].description, "本地没有该成交");
+        assert_eq!(diffs[2].description, "结算单没有该成交");
+
+        let position = |symbol: &str, direction: i32, position: i32| PositionRow {
+            symbol: symbol.into(),
+            direction,
+            position,
+            ..Default::default()
+        };
+        let positions = vec![
+            position("rb2305", THOST_FTDC_PD_Long as i32, 3),
+            position("IF2303", THOST_FTDC_PD_Short as i32, 2),
+        ];
+        let diffs = s.reconcile_positions(&positions);
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].description, "持仓不一致 结算单=1 本地=2");
+        assert_eq!(s.reconcile_positions(&positions[..1]).len(), 1);
+    }
+}
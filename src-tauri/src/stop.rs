@@ -0,0 +1,556 @@
+use crate::config::*;
+use crate::gateway::*;
+use itertools::Itertools;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum StopKind {
+    StopLoss,
+    TakeProfit,
+    /// 从最有利价格回撤trail_distance时触发
+    TrailingStop,
+}
+
+impl Default for StopKind {
+    fn default() -> Self {
+        StopKind::StopLoss
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum StopStatus {
+    /// 关联的开仓报单未完成, 成交后开始监控
+    Pending,
+    Active,
+    /// 已发出平仓单, 等待平仓单结束
+    Triggered,
+    /// 平仓单已结束并有成交
+    Closed,
+    Failed,
+    Canceled,
+}
+
+impl Default for StopStatus {
+    fn default() -> Self {
+        StopStatus::Active
+    }
+}
+
+/// 前端添加止损止盈的参数
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct StopTriggerInput {
+    pub broker_id: String,
+    pub account: String,
+    pub exchange: String,
+    pub symbol: String,
+    /// 被保护的持仓方向, 与PositionRow::direction一致
+    pub posi_direction: i32,
+    pub kind: StopKind,
+    pub trigger_price: f64,
+    pub trail_distance: f64,
+    /// 为0时平掉全部可平仓位
+    pub volume: i32,
+    /// 平仓单在对手价基础上让出的价格
+    pub slippage: f64,
+    /// 关联的开仓报单key, 为空时直接关联持仓
+    pub order_key: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct StopTrigger {
+    pub id: String,
+    pub broker_id: String,
+    pub account: String,
+    pub exchange: String,
+    pub symbol: String,
+    pub posi_direction: i32,
+    pub kind: StopKind,
+    pub trigger_price: f64,
+    pub trail_distance: f64,
+    pub volume: i32,
+    pub slippage: f64,
+    pub order_key: String,
+    pub status: StopStatus,
+    pub status_description: String,
+    /// 移动止损监控期间的最有利价格, 变化后最多每秒保存一次
+    pub extreme_price: f64,
+    pub created_at: u64,
+    pub triggered_at: u64,
+    /// 触发后发出的平仓单
+    pub close_order_keys: Vec<String>,
+}
+
+impl StopTrigger {
+    fn is_long(&self) -> bool {
+        self.posi_direction == POSI_LONG
+    }
+
+    pub fn market_key(&self) -> String {
+        format!("{}:{}", self.exchange, self.symbol)
+    }
+
+    /// 需要行情的触发器
+    pub fn is_watching(&self) -> bool {
+        self.status == StopStatus::Pending || self.status == StopStatus::Active
+    }
+
+    /// 用最新价判断是否触发, 移动止损同时更新最有利价格
+    fn check(&mut self, price: f64) -> bool {
+        let long = self.is_long();
+        match self.kind {
+            StopKind::StopLoss => {
+                (long && price <= self.trigger_price) || (!long && price >= self.trigger_price)
+            }
+            StopKind::TakeProfit => {
+                (long && price >= self.trigger_price) || (!long && price <= self.trigger_price)
+            }
+            StopKind::TrailingStop => {
+                if self.extreme_price == 0.0
+                    || (long && price > self.extreme_price)
+                    || (!long && price < self.extreme_price)
+                {
+                    self.extreme_price = price;
+                }
+                (long && price <= self.extreme_price - self.trail_distance)
+                    || (!long && price >= self.extreme_price + self.trail_distance)
+            }
+        }
+    }
+
+    /// 平仓单价格, 对手价让出slippage并限制在涨跌停价内
+    pub fn close_price(&self, md: &MarketDataRow) -> f64 {
+        let (best, sign) = if self.is_long() {
+            (md.bid_prices.first().cloned().unwrap_or(0.0), -1.0)
+        } else {
+            (md.ask_prices.first().cloned().unwrap_or(0.0), 1.0)
+        };
+        let best = if best > 0.0 { best } else { md.last_price };
+        let mut price = best + sign * self.slippage;
+        if md.upper_limit_price > 0.0 {
+            price = price.min(md.upper_limit_price);
+        }
+        if md.lower_limit_price > 0.0 {
+            price = price.max(md.lower_limit_price);
+        }
+        price
+    }
+
+    /// 平仓单, 上期所/能源中心先平昨仓再平今仓, 其它交易所使用平仓
+    pub fn close_orders(&self, position: &PositionRow, price: f64) -> Vec<OrderInput> {
+        let closable = position.position - position.frozen;
+        let volume = if self.volume > 0 {
+            self.volume.min(closable)
+        } else {
+            closable
+        };
+        if volume <= 0 {
+            return vec![];
+        }
+        let direction = if self.is_long() {
+            DIRECTION_SELL
+        } else {
+            DIRECTION_BUY
+        };
+        let input = |offset: i32, volume: i32| OrderInput {
+            exchange: self.exchange.clone(),
+            symbol: self.symbol.clone(),
+            direction,
+            offset,
+            price,
+            volume,
+            ..Default::default()
+        };
+        if self.exchange == "SHFE" || self.exchange == "INE" {
+            let yd = volume.min(position.yd_position.max(0));
+            let mut v = vec![];
+            if yd > 0 {
+                v.push(input(OFFSET_CLOSE_YESTERDAY, yd));
+            }
+            if volume > yd {
+                v.push(input(OFFSET_CLOSE_TODAY, volume - yd));
+            }
+            v
+        } else {
+            vec![input(OFFSET_CLOSE, volume)]
+        }
+    }
+}
+
+/// 本地止损止盈, 保存在.cache中重启后继续监控
+pub struct StopEngine {
+    pub triggers: HashMap<String, StopTrigger>,
+    seq: u64,
+    /// 移动止损的最有利价格有变化, 尚未保存
+    dirty: bool,
+    saved_at: u64,
+}
+
+impl StopEngine {
+    pub fn load(path: &str) -> Self {
+        let triggers = std::fs::read_to_string(path)
+            .ok()
+            .and_then(|s| match serde_json::from_str::<Vec<StopTrigger>>(&s) {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    error!("加载止损止盈失败 {} {}", path, e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        info!("加载止损止盈{}条", triggers.len());
+        StopEngine {
+            triggers: triggers.into_iter().map(|t| (t.id.clone(), t)).collect(),
+            seq: 0,
+            dirty: false,
+            saved_at: 0,
+        }
+    }
+
+    pub fn save(&mut self, path: &str) {
+        let mut v = self.triggers.values().collect::<Vec<_>>();
+        v.sort_by(|a, b| a.id.cmp(&b.id));
+        let s = match serde_json::to_string_pretty(&v) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("序列化止损止盈失败 {}", e);
+                return;
+            }
+        };
+        if let Err(e) = std::fs::write(path, s) {
+            error!("保存止损止盈失败 {} {}", path, e);
+        }
+        self.dirty = false;
+        self.saved_at = now_ts();
+    }
+
+    /// 最有利价格变化后保存, force为false时每秒最多保存一次
+    pub fn save_if_dirty(&mut self, path: &str, force: bool) {
+        if self.dirty && (force || now_ts() > self.saved_at) {
+            self.save(path);
+        }
+    }
+
+    /// 经纪商下仍在监控的合约, exchange:symbol
+    pub fn watched_keys(&self, broker_id: &str) -> Vec<String> {
+        self.triggers
+            .values()
+            .filter(|t| t.broker_id == broker_id && t.is_watching())
+            .map(|t| t.market_key())
+            .unique()
+            .collect()
+    }
+
+    pub fn default_path() -> &'static str {
+        ".cache/stop_triggers.json"
+    }
+
+    pub fn add(&mut self, input: StopTriggerInput) -> Result<String, String> {
+        if input.symbol.len() == 0 {
+            return Err("合约不能为空".into());
+        }
+        if input.posi_direction != POSI_LONG && input.posi_direction != POSI_SHORT {
+            return Err("持仓方向错误".into());
+        }
+        match input.kind {
+            StopKind::TrailingStop if input.trail_distance <= 0.0 => {
+                return Err("回撤距离必须大于0".into())
+            }
+            StopKind::StopLoss | StopKind::TakeProfit if input.trigger_price <= 0.0 => {
+                return Err("触发价必须大于0".into())
+            }
+            _ => {}
+        }
+        // 重启后seq从0开始, 同一秒内可能与加载的id重复
+        let id = loop {
+            self.seq += 1;
+            let id = format!("{}{:04}", now_ts(), self.seq);
+            if !self.triggers.contains_key(&id) {
+                break id;
+            }
+        };
+        let status = if input.order_key.len() > 0 {
+            StopStatus::Pending
+        } else {
+            StopStatus::Active
+        };
+        let t = StopTrigger {
+            id: id.clone(),
+            broker_id: input.broker_id,
+            account: input.account,
+            exchange: input.exchange,
+            symbol: input.symbol,
+            posi_direction: input.posi_direction,
+            kind: input.kind,
+            trigger_price: input.trigger_price,
+            trail_distance: input.trail_distance,
+            volume: input.volume,
+            slippage: input.slippage,
+            order_key: input.order_key,
+            status,
+            created_at: now_ts(),
+            ..Default::default()
+        };
+        info!("添加止损止盈 {:?}", t);
+        self.triggers.insert(id.clone(), t);
+        Ok(id)
+    }
+
+    pub fn cancel(&mut self, id: &str) -> Result<(), String> {
+        let t = self
+            .triggers
+            .get_mut(id)
+            .ok_or("止损止盈不存在".to_string())?;
+        if t.status != StopStatus::Pending && t.status != StopStatus::Active {
+            return Err("止损止盈已结束".into());
+        }
+        t.status = StopStatus::Canceled;
+        t.status_description = "已取消".into();
+        Ok(())
+    }
+
+    /// 关联报单的触发器, 报单结束后有成交则开始监控, 否则取消
+    pub fn on_orders(
+        &mut self,
+        broker_id: &str,
+        account: &str,
+        orders: &HashMap<String, OrderRow>,
+    ) -> Vec<String> {
+        let mut changed = vec![];
+        for t in self.triggers.values_mut().filter(|t| {
+            t.status == StopStatus::Pending && t.broker_id == broker_id && t.account == account
+        }) {
+            let o = match orders.get(&t.order_key) {
+                Some(o) if !o.is_active() => o,
+                _ => continue,
+            };
+            if o.volume_traded > 0 {
+                if t.volume == 0 {
+                    t.volume = o.volume_traded;
+                }
+                t.status = StopStatus::Active;
+            } else {
+                t.status = StopStatus::Canceled;
+                t.status_description = "关联报单未成交".into();
+            }
+            changed.push(t.id.clone());
+        }
+        changed
+    }
+
+    /// 账户是否有等待平仓单结束的触发器
+    pub fn has_close_orders(&self, broker_id: &str, account: &str) -> bool {
+        self.triggers.values().any(|t| {
+            t.status == StopStatus::Triggered && t.broker_id == broker_id && t.account == account
+        })
+    }
+
+    /// 平仓单全部结束后, 有成交的完成, 全部被拒绝或撤销的标记失败
+    pub fn on_close_orders(
+        &mut self,
+        broker_id: &str,
+        account: &str,
+        orders: &HashMap<String, OrderRow>,
+    ) -> Vec<String> {
+        let mut changed = vec![];
+        for t in self.triggers.values_mut().filter(|t| {
+            t.status == StopStatus::Triggered
+                && t.broker_id == broker_id
+                && t.account == account
+                && t.close_order_keys.len() > 0
+        }) {
+            let closes = t
+                .close_order_keys
+                .iter()
+                .filter_map(|k| orders.get(k))
+                .collect::<Vec<_>>();
+            if closes.len() < t.close_order_keys.len() || closes.iter().any(|o| o.is_active()) {
+                continue;
+            }
+            let traded: i32 = closes.iter().map(|o| o.volume_traded).sum();
+            let total: i32 = closes.iter().map(|o| o.volume_total_original).sum();
+            if traded == 0 {
+                t.status = StopStatus::Failed;
+                t.status_description = format!(
+                    "平仓单未成交 {}",
+                    closes
+                        .iter()
+                        .map(|o| o.status_description.as_str())
+                        .join(";")
+                );
+            } else {
+                t.status = StopStatus::Closed;
+                t.status_description = format!("已平仓{}/{}手", traded, total);
+            }
+            info!("止损止盈 id={} {}", t.id, t.status_description);
+            changed.push(t.id.clone());
+        }
+        changed
+    }
+
+    /// 关联报单的合约有新行情时需要检查的账户
+    pub fn pending_accounts(&self, md_key: &str) -> Vec<(String, String)> {
+        self.triggers
+            .values()
+            .filter(|t| t.status == StopStatus::Pending && t.market_key() == md_key)
+            .map(|t| (t.broker_id.clone(), t.account.clone()))
+            .unique()
+            .collect()
+    }
+
+    /// 检查行情, 返回触发的id, 触发器状态变为Triggered
+    pub fn on_market_data(&mut self, md: &MarketDataRow) -> Vec<String> {
+        if md.last_price <= 0.0 {
+            return vec![];
+        }
+        let k = md.key();
+        let mut fired = vec![];
+        for t in self.triggers.values_mut().filter(|t| {
+            t.status == StopStatus::Active
+                && t.market_key() == k
+                && (md.replay_account.is_empty()
+                    || md.replay_account == format!("{}:{}", t.broker_id, t.account))
+        }) {
+            let extreme = t.extreme_price;
+            let hit = t.check(md.last_price);
+            if t.extreme_price != extreme {
+                self.dirty = true;
+            }
+            if hit {
+                info!("止损止盈触发 id={} price={}", t.id, md.last_price);
+                t.status = StopStatus::Triggered;
+                t.triggered_at = now_ts();
+                fired.push(t.id.clone());
+            }
+        }
+        fired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG: i32 = POSI_LONG;
+    const SHORT: i32 = POSI_SHORT;
+
+    fn trigger(kind: StopKind, posi_direction: i32, trigger_price: f64) -> StopTrigger {
+        StopTrigger {
+            id: "1".into(),
+            broker_id: "9999".into(),
+            account: "000001".into(),
+            exchange: "SHFE".into(),
+            symbol: "rb2305".into(),
+            posi_direction,
+            kind,
+            trigger_price,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn check_stop_loss_and_take_profit() {
+        let mut t = trigger(StopKind::StopLoss, LONG, 4000.0);
+        assert!(!t.check(4001.0));
+        assert!(t.check(4000.0));
+        let mut t = trigger(StopKind::StopLoss, SHORT, 4000.0);
+        assert!(!t.check(3999.0));
+        assert!(t.check(4000.0));
+        let mut t = trigger(StopKind::TakeProfit, LONG, 4100.0);
+        assert!(!t.check(4099.0));
+        assert!(t.check(4100.0));
+        let mut t = trigger(StopKind::TakeProfit, SHORT, 3900.0);
+        assert!(!t.check(3901.0));
+        assert!(t.check(3900.0));
+    }
+
+    #[test]
+    fn trailing_stop_follows_extreme_price() {
+        let mut t = trigger(StopKind::TrailingStop, LONG, 0.0);
+        t.trail_distance = 20.0;
+        assert!(!t.check(4000.0));
+        assert!(!t.check(4050.0));
+        // 回落不改变最有利价格
+        assert!(!t.check(4040.0));
+        assert_eq!(t.extreme_price, 4050.0);
+        assert!(t.check(4030.0));
+
+        let mut t = trigger(StopKind::TrailingStop, SHORT, 0.0);
+        t.trail_distance = 20.0;
+        assert!(!t.check(4000.0));
+        assert!(!t.check(3950.0));
+        assert_eq!(t.extreme_price, 3950.0);
+        assert!(t.check(3970.0));
+    }
+
+    #[test]
+    fn close_orders_split_yesterday_and_today_on_shfe() {
+        let t = trigger(StopKind::StopLoss, LONG, 4000.0);
+        let p = PositionRow {
+            position: 5,
+            yd_position: 2,
+            frozen: 1,
+            ..Default::default()
+        };
+        let v = t.close_orders(&p, 3990.0);
+        assert_eq!(v.len(), 2);
+        assert_eq!(v[0].offset, OFFSET_CLOSE_YESTERDAY);
+        assert_eq!(v[0].volume, 2);
+        assert_eq!(v[1].offset, OFFSET_CLOSE_TODAY);
+        assert_eq!(v[1].volume, 2);
+        assert!(v.iter().all(|o| o.direction == DIRECTION_SELL));
+
+        let mut t = trigger(StopKind::StopLoss, SHORT, 4000.0);
+        t.exchange = "DCE".into();
+        t.volume = 1;
+        let v = t.close_orders(&p, 4010.0);
+        assert_eq!(v.len(), 1);
+        assert_eq!(v[0].offset, OFFSET_CLOSE);
+        assert_eq!(v[0].direction, DIRECTION_BUY);
+        assert_eq!(v[0].volume, 1);
+    }
+
+    #[test]
+    fn rejected_close_orders_fail_the_trigger() {
+        let mut engine = StopEngine::load("");
+        let mut t = trigger(StopKind::StopLoss, LONG, 4000.0);
+        t.status = StopStatus::Triggered;
+        t.close_order_keys = vec!["1:1:1".into()];
+        engine.triggers.insert(t.id.clone(), t);
+        let mut orders = HashMap::new();
+        let mut o = OrderRow {
+            volume_total_original: 1,
+            ..Default::default()
+        };
+        orders.insert("1:1:1".to_string(), o.clone());
+        // 报单未结束时继续等待
+        assert!(engine.on_close_orders("9999", "000001", &orders).is_empty());
+
+        o.status = ORDER_CANCELED;
+        o.status_description = "平仓量超过持仓量".into();
+        orders.insert("1:1:1".to_string(), o);
+        assert_eq!(engine.on_close_orders("9999", "000001", &orders), vec!["1"]);
+        let t = &engine.triggers["1"];
+        assert_eq!(t.status, StopStatus::Failed);
+        assert!(t.status_description.contains("平仓量超过持仓量"));
+        assert!(!engine.has_close_orders("9999", "000001"));
+    }
+
+    #[test]
+    fn add_does_not_overwrite_loaded_ids() {
+        let mut engine = StopEngine::load("");
+        let input = StopTriggerInput {
+            symbol: "rb2305".into(),
+            posi_direction: LONG,
+            trigger_price: 4000.0,
+            ..Default::default()
+        };
+        let id = engine.add(input.clone()).unwrap();
+        // 模拟重启后seq从0开始
+        engine.seq = 0;
+        let id2 = engine.add(input).unwrap();
+        assert_ne!(id, id2);
+        assert_eq!(engine.triggers.len(), 2);
+    }
+}
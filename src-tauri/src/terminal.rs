@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+
+/// 从本机采集的终端信息, 用于填写经纪商的穿透式监管配置
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct TerminalInfo {
+    pub hostname: String,
+    pub os: String,
+    pub inner_ip_address: String,
+    pub mac_address: String,
+    pub hd_serial: String,
+    /// 拼接后的终端信息, 对应TradingBroker.terminal_info
+    pub terminal_info: String,
+}
+
+impl TerminalInfo {
+    pub fn collect() -> Self {
+        let mut ti = Self {
+            hostname: command_output("hostname", &[]).unwrap_or_default(),
+            os: std::env::consts::OS.to_string(),
+            inner_ip_address: inner_ip_address().unwrap_or_default(),
+            mac_address: mac_address().unwrap_or_default(),
+            hd_serial: hd_serial().unwrap_or_default(),
+            terminal_info: String::new(),
+        };
+        ti.terminal_info = [
+            ("PCN", &ti.hostname),
+            ("OS", &ti.os),
+            ("IIP", &ti.inner_ip_address),
+            ("MAC", &ti.mac_address),
+            ("HD", &ti.hd_serial),
+        ]
+        .iter()
+        .filter(|(_, v)| !v.is_empty())
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join(";");
+        ti
+    }
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let s = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if s.is_empty() {
+        None
+    } else {
+        Some(s)
+    }
+}
+
+/// 出口网卡的IP, UDP的connect只选择路由不发送数据
+fn inner_ip_address() -> Option<String> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("114.114.114.114:80").ok()?;
+    Some(socket.local_addr().ok()?.ip().to_string())
+}
+
+fn mac_address() -> Option<String> {
+    if cfg!(target_os = "windows") {
+        // "00-15-5D-01-02-03","\Device\Tcpip_{...}"
+        let s = command_output("getmac", &["/fo", "csv", "/nh"])?;
+        s.lines()
+            .filter_map(|l| l.split(',').next())
+            .map(|m| m.trim_matches('"').replace('-', ":"))
+            .find(|m| m.len() == 17)
+    } else if cfg!(target_os = "macos") {
+        let s = command_output("ifconfig", &["en0"])?;
+        s.lines()
+            .find_map(|l| l.trim().strip_prefix("ether "))
+            .map(|m| m.trim().to_string())
+    } else {
+        let mut names: Vec<String> = std::fs::read_dir("/sys/class/net")
+            .ok()?
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .filter(|n| n != "lo")
+            .collect();
+        names.sort();
+        names.iter().find_map(|n| {
+            let m = std::fs::read_to_string(format!("/sys/class/net/{}/address", n)).ok()?;
+            let m = m.trim().to_string();
+            if m.len() == 17 && m != "00:00:00:00:00:00" {
+                Some(m)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+fn hd_serial() -> Option<String> {
+    if cfg!(target_os = "windows") {
+        let s = command_output("wmic", &["diskdrive", "get", "serialnumber"])?;
+        s.lines()
+            .skip(1)
+            .map(|l| l.trim())
+            .find(|l| !l.is_empty())
+            .map(|l| l.to_string())
+    } else if cfg!(target_os = "macos") {
+        let s = command_output("ioreg", &["-rd1", "-c", "IOPlatformExpertDevice"])?;
+        s.lines()
+            .find(|l| l.contains("IOPlatformSerialNumber"))
+            .and_then(|l| l.split('"').nth(3))
+            .map(|l| l.to_string())
+    } else {
+        let mut names: Vec<String> = std::fs::read_dir("/sys/block")
+            .ok()?
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .filter(|n| !n.starts_with("loop") && !n.starts_with("ram"))
+            .collect();
+        names.sort();
+        names.iter().find_map(|n| {
+            let s = std::fs::read_to_string(format!("/sys/block/{}/device/serial", n)).ok()?;
+            let s = s.trim().to_string();
+            if s.is_empty() {
+                None
+            } else {
+                Some(s)
+            }
+        })
+    }
+}
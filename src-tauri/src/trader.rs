@@ -1,69 +1,301 @@
 use crate::config::*;
-use crate::db::ta_key;
-use bincode::{Decode, Encode};
+use crate::gateway::*;
+use crate::position::posi_direction;
 use ctp_futures::trader_api::*;
 use ctp_futures::*;
 use futures::StreamExt;
 use log::{error, info, warn};
 use rust_share_util::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::ffi::CString;
 use std::sync::Arc;
 use tokio::sync::oneshot;
 use tokio::sync::Mutex;
 
-#[derive(Decode, Encode, Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub enum CtaStatus {
-    UnKown,
-    Connected,
-    Disconnected,
-    AuthenticateFailed,
-    AuthenticateSucceeded,
-    LoginFailed,
-    LoginSucceeded,
-    LoginCompleted,
+impl ErrorCategory {
+    /// 按CTP error.xml中的错误码分类
+    pub fn from_error_id(error_id: i32) -> Self {
+        match error_id {
+            3 | 4 | 5 | 6 | 11 | 12 | 13 | 48 | 49 | 60 | 63 | 64 => ErrorCategory::Auth,
+            10 | 14 | 131 | 140 => ErrorCategory::Password,
+            9 | 18 | 19 | 20 | 28 | 29 | 62 => ErrorCategory::Permission,
+            31 => ErrorCategory::Funds,
+            30 | 37 | 50 | 51 => ErrorCategory::Position,
+            15 | 16 | 22 | 23 | 24 | 25 | 26 | 45 | 46 | 47 | 52 | 53 | 54 => ErrorCategory::Order,
+            17 | 44 => ErrorCategory::Market,
+            40 | 41 => ErrorCategory::FlowControl,
+            42 => ErrorCategory::Settlement,
+            _ => ErrorCategory::Other,
+        }
+    }
+}
+
+impl CtpError {
+    /// ErrorID为0或没有RspInfo时返回None
+    pub fn decode(source: &str, rsp_info: &Option<CThostFtdcRspInfoField>) -> Option<Self> {
+        match rsp_info {
+            Some(r) if r.ErrorID != 0 => Some(CtpError {
+                source: source.to_string(),
+                error_id: r.ErrorID,
+                error_msg: gb18030_cstr_to_str_i8(&r.ErrorMsg).to_string(),
+                category: ErrorCategory::from_error_id(r.ErrorID),
+                ts: now_ts(),
+                ..Default::default()
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// 认证/登录失败时不重试的错误码, 重试可能导致账户被锁定
+const FATAL_LOGIN_ERRORS: [i32; 3] = [3, 131, 140];
+const MAX_LOGIN_RETRIES: u32 = 6;
+/// 登录成功前连续断线或认证失败的次数达到后切换到下一个前置组
+const FRONT_FAILOVER_THRESHOLD: u32 = 3;
+/// 弱密码和首次登录, 需要修改密码后才能登录
+const PASSWORD_CHANGE_ERRORS: [i32; 2] = [131, 140];
+/// 关闭时等待登出回报的时间, 超时后直接释放API
+const LOGOUT_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(3);
+
+/// 受查询流控限制的CTP查询, 参数为exchange:symbol
+#[derive(Debug, Clone, PartialEq)]
+enum Query {
+    TradingAccount,
+    PositionDetail,
+    Position,
+    Instrument,
+    /// 参数为空时查询全市场
+    DepthMarketData(String),
+    Order,
+    Trade,
+    ParkedOrder,
+    /// 参数为交易日, 为空时查询上一交易日
+    SettlementInfo(String),
+    AccountRegister,
+    TransferSerial,
+    CommissionRate(String),
+    MarginRate(String),
 }
 
-impl Default for CtaStatus {
-    fn default() -> Self {
-        CtaStatus::UnKown
+impl Query {
+    /// 登录后的初始化查询顺序, 首次登录查询全部, 重连后不再查询合约和行情
+    /// 结算单未确认时先查询结算单
+    fn next_login_query(&self, full: bool) -> Option<Query> {
+        use Query::*;
+        match self {
+            SettlementInfo(_) => Some(TradingAccount),
+            TradingAccount => Some(PositionDetail),
+            PositionDetail => Some(Position),
+            Position if full => Some(Instrument),
+            Position => Some(Order),
+            Instrument => Some(DepthMarketData(String::new())),
+            DepthMarketData(_) => Some(Order),
+            Order => Some(Trade),
+            Trade => Some(ParkedOrder),
+            _ => None,
+        }
     }
 }
 
-#[derive(Decode, Encode, Debug, Clone, Serialize, Deserialize)]
-pub struct CtaEvent {
-    tp: String,
-    b: String,
-    a: String,
-    key: String,
+/// 查询没有响应时放弃等待, 避免后续查询一直排队
+const QUERY_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(10);
+
+struct QueuedQuery {
+    query: Query,
+    result: oneshot::Sender<Result<(), Error>>,
 }
 
-#[derive(Debug, Clone, Default)]
-pub struct CtpTradingAccount {
-    pub ta: CThostFtdcTradingAccountField,
-    pub status: CtaStatus,
-    pub status_description: String,
-    pub orders: HashMap<String, OrderRow>,
-    pub trades: HashMap<String, TradeRow>,
-    pub positions: HashMap<String, PositionRow>,
-    pub position_details: HashMap<String, PositionDetailRow>,
-    pub instruments: HashMap<String, InstrumentRow>,
+/// 查询调度, CTP同一时间只处理一个查询并且限制每秒查询次数
+struct QueryScheduler {
+    queue: VecDeque<QueuedQuery>,
+    in_flight: Option<(i32, QueuedQuery, tokio::time::Instant)>,
+    last_sent: Option<tokio::time::Instant>,
+    interval: tokio::time::Duration,
+}
+
+impl QueryScheduler {
+    fn new(query_per_second: u32) -> Self {
+        QueryScheduler {
+            queue: VecDeque::new(),
+            in_flight: None,
+            last_sent: None,
+            interval: tokio::time::Duration::from_millis(1000 / query_per_second.max(1) as u64),
+        }
+    }
+
+    fn contains(&self, q: &Query) -> bool {
+        self.queue.iter().any(|qq| qq.query == *q)
+            || self
+                .in_flight
+                .as_ref()
+                .map_or(false, |(_, qq, _)| qq.query == *q)
+    }
+
+    /// 登录查询插到队首, 其它查询排在队尾
+    fn push(&mut self, query: Query, front: bool) -> oneshot::Receiver<Result<(), Error>> {
+        let (tx, rx) = oneshot::channel();
+        let qq = QueuedQuery { query, result: tx };
+        if front {
+            self.queue.push_front(qq);
+        } else {
+            self.queue.push_back(qq);
+        }
+        rx
+    }
+
+    /// 没有正在进行的查询并且满足发送间隔时取出下一个查询
+    fn next_ready(&mut self, now: tokio::time::Instant) -> Option<QueuedQuery> {
+        if let Some((_, _, sent_at)) = &self.in_flight {
+            if now.duration_since(*sent_at) < QUERY_TIMEOUT {
+                return None;
+            }
+            if let Some((request_id, qq, _)) = self.in_flight.take() {
+                warn!("查询超时 request_id={} {:?}", request_id, qq.query);
+                let _ = qq.result.send(Err(Error::QueryTimeout));
+            }
+        }
+        if let Some(last_sent) = self.last_sent {
+            if now.duration_since(last_sent) < self.interval {
+                return None;
+            }
+        }
+        self.queue.pop_front()
+    }
+
+    fn sent(&mut self, request_id: i32, qq: QueuedQuery, now: tokio::time::Instant) {
+        self.in_flight = Some((request_id, qq, now));
+        self.last_sent = Some(now);
+    }
+
+    /// 被CTP流控拒绝, 放回队首等待下一个发送间隔
+    fn retry(&mut self, qq: QueuedQuery, now: tokio::time::Instant) {
+        self.queue.push_front(qq);
+        self.last_sent = Some(now);
+    }
+
+    /// 收到最后一个响应, 返回完成的查询
+    fn complete(&mut self, request_id: i32, result: Result<(), Error>) -> Option<Query> {
+        match self.in_flight.take() {
+            Some((id, qq, _)) if id == request_id => {
+                let _ = qq.result.send(result);
+                Some(qq.query)
+            }
+            other => {
+                self.in_flight = other;
+                None
+            }
+        }
+    }
+
+    /// 断线后所有查询失败, 重新登录后重新发起
+    fn reset(&mut self) {
+        if let Some((_, qq, _)) = self.in_flight.take() {
+            let _ = qq.result.send(Err(Error::Disconnected));
+        }
+        for qq in self.queue.drain(..) {
+            let _ = qq.result.send(Err(Error::Disconnected));
+        }
+        self.last_sent = None;
+    }
 }
 
 pub struct Trader {
     pub conf: TradingAccount,
     pub broker: TradingBroker,
-    pub cta: CtpTradingAccount,
-    pub api: Box<CThostFtdcTraderApi>,
+    pub cta: AccountBook,
+    /// Release会释放API对象, 之后不能再调用, Box也不能再drop
+    api: std::mem::ManuallyDrop<Box<CThostFtdcTraderApi>>,
     pub exit_sender: Option<tokio::sync::oneshot::Sender<String>>,
+    /// 后台任务登出并释放API后收到通知
+    exit_done: Option<oneshot::Receiver<()>>,
+    /// 正在关闭, 断线不再切换前置
+    shutting_down: bool,
     pub event_sender: tokio::sync::mpsc::Sender<CtaEvent>,
     request_id: i32,
+    /// 等待报单回报的请求, 收到第一个OnRtnOrder或错误回报后移除
+    pending_orders: HashMap<String, oneshot::Sender<Result<(), String>>>,
+    /// 等待撤单结果的请求, 收到撤单回报或撤单错误后移除
+    pending_actions: HashMap<String, oneshot::Sender<Result<(), Error>>>,
+    /// 等待预埋单回报的请求, key为OrderRef
+    pending_parked: HashMap<String, oneshot::Sender<Result<String, String>>>,
+    /// 等待删除预埋单结果的请求, key为ParkedOrderID
+    pending_removes: HashMap<String, oneshot::Sender<Result<(), Error>>>,
+    /// 等待结算单确认结果的请求
+    pending_confirm: Option<oneshot::Sender<Result<(), Error>>>,
+    /// 等待修改密码结果的请求和新密码
+    pending_password: Option<(String, oneshot::Sender<Result<(), Error>>)>,
+    pending_money_password: Option<(String, oneshot::Sender<Result<(), Error>>)>,
+    /// 正在接收的结算单分包, 收齐后再按GB18030解码
+    settlement_buffer: Vec<i8>,
+    settlement_day: String,
+    /// 登录失败后下一次重新认证的时间
+    retry_at: Option<tokio::time::Instant>,
+    scheduler: QueryScheduler,
+    /// 正在进行的登录初始化查询
+    login_query: Option<Query>,
+    /// 已经请求过费率的合约, 避免查询结果为空时重复查询
+    rate_requested: std::collections::HashSet<String>,
+    /// 正在查询的经纪商持仓, 查询完成后与本地持仓核对
+    queried_positions: HashMap<String, PositionRow>,
+    /// 当前连接使用FENS名字服务器, 否则直连交易前置
+    fens: bool,
+    /// 后台任务释放当前API后按该配置重新连接
+    pending_reconnect: Option<Reconnect>,
+}
+
+/// 切换前置或重建历史时的新连接, 前置组在发起时已经确认存在
+struct Reconnect {
+    conf: TradingAccount,
+    front: FrontGroup,
+    fens: bool,
+    /// 清空当日报单和成交后重传
+    rebuild: bool,
+}
+
+/// 经纪商所有可连接的交易前置, 前置组同时配置了FENS和交易前置时各算一个
+fn trade_routes(broker: &TradingBroker) -> Vec<(&FrontGroup, bool)> {
+    broker
+        .fronts
+        .iter()
+        .flat_map(|fg| [(fg, true), (fg, false)])
+        .filter(|(fg, fens)| {
+            if *fens {
+                !fg.fens_trade_front.is_empty()
+            } else {
+                !fg.trade_front.is_empty()
+            }
+        })
+        .collect()
 }
 
-#[derive(Debug, derive_more::Display, derive_more::From)]
-pub enum Error {
-    FrontGroupNotFound,
+/// 创建交易API并注册前置和私有/公共流的重传方式, 调用init后开始连接
+fn create_trader_api(
+    flow_path: &str,
+    fg: &FrontGroup,
+    fens: bool,
+    conf: &TradingAccount,
+) -> (
+    Box<CThostFtdcTraderApi>,
+    impl futures::Stream<Item = CThostFtdcTraderSpiOutput> + Unpin,
+) {
+    let mut api = create_api(flow_path, false);
+    let (stream, pp) = create_spi();
+    api.register_spi(pp);
+    if fens {
+        api.register_name_server(CString::new(fg.fens_trade_front.as_str()).unwrap());
+        info!("register name server {}", fg.fens_trade_front);
+    } else {
+        api.register_front(CString::new(fg.trade_front.as_str()).unwrap());
+        info!("register front {}", fg.trade_front);
+    }
+    api.subscribe_public_topic(conf.public_resume.to_ctp());
+    api.subscribe_private_topic(conf.private_resume.to_ctp());
+    info!(
+        "[{}:{}] resume private={:?} public={:?}",
+        conf.broker_id, conf.account, conf.private_resume, conf.public_resume
+    );
+    (api, stream)
 }
 
 impl Trader {
@@ -72,62 +304,93 @@ impl Trader {
         broker: TradingBroker,
         es: tokio::sync::mpsc::Sender<CtaEvent>,
     ) -> Result<Arc<Mutex<Self>>, Error> {
-        let conf1 = conf.clone();
+        let mut conf1 = conf.clone();
         let (exit_sender, mut exit_receiver) = oneshot::channel::<String>();
+        let (done_sender, done_receiver) = oneshot::channel::<()>();
         let broker_id = conf.broker_id;
         let account = conf.account;
         let ak = format!("{broker_id}:{account}");
-        let fg = broker
-            .fronts
-            .iter()
-            .find(|fg| fg.id == conf.front_group)
-            .ok_or(Error::FrontGroupNotFound)?;
+        broker.validate_route().map_err(Error::InvalidBroker)?;
+        if let Err(e) = broker.validate() {
+            warn!("[{ak}] 经纪商配置有误 {e}");
+        }
+        let fg = match broker.fronts.iter().find(|fg| fg.id == conf.front_group) {
+            Some(fg) => fg,
+            None => {
+                let fg = broker.fronts.first().ok_or(Error::FrontGroupNotFound)?;
+                warn!(
+                    "[{ak}] 没有找到前置组{}, 使用{}({})",
+                    conf.front_group, fg.id, fg.name
+                );
+                fg
+            }
+        };
+        conf1.front_group = fg.id.clone();
 
-        let fens_trade_front = fg.fens_trade_front.as_str();
-        let trade_front = fg.trade_front.as_str();
         let _auth_code = broker.auth_code.as_str();
         let _user_product_info = broker.user_product_info.as_str();
         let _app_id = broker.app_id.as_str();
         let _password = conf.password.as_str();
         let flow_path = format!(".cache/ctp_futures_trade_flow_{}_{}//", broker_id, account);
         check_make_dir(&flow_path);
-        let mut api = create_api(&flow_path, false);
-        let mut stream = {
-            let (stream, pp) = create_spi();
-            api.register_spi(pp);
-            stream
-        };
-        if fens_trade_front.len() > 0 {
-            api.register_name_server(CString::new(fens_trade_front).unwrap());
-        } else {
-            api.register_front(CString::new(trade_front).unwrap());
-            info!("register front {}", trade_front);
-        }
-        api.subscribe_public_topic(ctp_futures::THOST_TE_RESUME_TYPE_THOST_TERT_QUICK);
-        api.subscribe_private_topic(ctp_futures::THOST_TE_RESUME_TYPE_THOST_TERT_QUICK);
-        api.init();
+        let fens = !fg.fens_trade_front.is_empty();
+        let (api, mut stream) = create_trader_api(&flow_path, fg, fens, &conf1);
         // let (api, mut api1) = trader_api::unsafe_clone_api(api);
         // 处理登陆初始化查询
-        let cta = CtpTradingAccount::default();
+        let cta = AccountBook::default();
+        let scheduler = QueryScheduler::new(broker.query_per_second);
         let trader = Trader {
             conf: conf1,
             cta,
-            api,
+            api: std::mem::ManuallyDrop::new(api),
             exit_sender: Some(exit_sender),
+            exit_done: Some(done_receiver),
+            shutting_down: false,
             request_id: 10,
             event_sender: es,
             broker,
+            pending_orders: HashMap::new(),
+            pending_actions: HashMap::new(),
+            pending_parked: HashMap::new(),
+            pending_removes: HashMap::new(),
+            pending_confirm: None,
+            pending_password: None,
+            pending_money_password: None,
+            settlement_buffer: vec![],
+            settlement_day: String::new(),
+            retry_at: None,
+            scheduler,
+            login_query: None,
+            rate_requested: std::collections::HashSet::new(),
+            queried_positions: HashMap::new(),
+            fens,
+            pending_reconnect: None,
         };
         let trader = Arc::new(Mutex::new(trader));
         let t1 = Arc::clone(&trader);
         let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(10));
+        let mut timer = tokio::time::interval(tokio::time::Duration::from_secs(1));
+        let mut query_timer = tokio::time::interval(tokio::time::Duration::from_millis(100));
+        let mut position_timer = tokio::time::interval(tokio::time::Duration::from_secs(60));
         tokio::spawn(async move {
             loop {
                 tokio::select! {
+                    _ = query_timer.tick() => {
+                        t1.lock().await.poll_queries();
+                    }
+                    _ = timer.tick() => {
+                        t1.lock().await.on_timer();
+                    }
                     _ = interval.tick() => {
                         let mut trader = t1.lock().await;
-                        if trader.cta.status == CtaStatus::LoginCompleted {
-                            trader.req_query_trading_account();
+                        if trader.login_completed() && !trader.scheduler.contains(&Query::TradingAccount) {
+                            let _ = trader.scheduler.push(Query::TradingAccount, false);
+                        }
+                    }
+                    _ = position_timer.tick() => {
+                        let mut trader = t1.lock().await;
+                        if trader.login_completed() {
+                            let _ = trader.schedule_query(Query::Position);
                         }
                     }
                     msg = stream.next() => {
@@ -137,22 +400,91 @@ impl Trader {
                         }
                     }
                     _ = &mut exit_receiver => {
-                        info!("[{ak}] exited on receiver, start to release api");
-                        let trader = Arc::into_inner(t1).unwrap();
-                        let mut trader = trader.into_inner();
-                        trader.api.release();
-                        Box::leak(trader.api);
+                        info!("[{ak}] exited on receiver, start to logout");
                         break;
                     }
                 }
+                let reconnect = t1.lock().await.pending_reconnect.take();
+                if let Some(r) = reconnect {
+                    let mut trader = t1.lock().await;
+                    // 先释放旧连接, 流文件写完后新API才能使用同一个目录
+                    trader.set_status(CtaStatus::Disconnected);
+                    trader.api.release();
+                    let (api, new_stream) =
+                        create_trader_api(&flow_path, &r.front, r.fens, &r.conf);
+                    trader.attach_api(api, r);
+                    stream = new_stream;
+                }
             }
-            info!("[{ak}] exited loop");
+            // 已登录时先登出, 等待登出回报或断线后再释放API, 流文件在Release时写完
+            if t1.lock().await.req_user_logout() {
+                let deadline = tokio::time::sleep(LOGOUT_TIMEOUT);
+                tokio::pin!(deadline);
+                loop {
+                    tokio::select! {
+                        msg = stream.next() => {
+                            let msg = match msg {
+                                Some(msg) => msg,
+                                None => break,
+                            };
+                            let done = matches!(
+                                msg,
+                                CThostFtdcTraderSpiOutput::OnRspUserLogout(_)
+                                    | CThostFtdcTraderSpiOutput::OnFrontDisconnected(_)
+                            );
+                            t1.lock().await.handle_spi_msg(&msg).await;
+                            if done {
+                                break;
+                            }
+                        }
+                        _ = &mut deadline => {
+                            warn!("[{ak}] 等待登出回报超时");
+                            break;
+                        }
+                    }
+                }
+            }
+            {
+                let mut trader = t1.lock().await;
+                trader.set_status(CtaStatus::Disconnected);
+                trader.api.release();
+            }
+            info!("[{ak}] released api, exited loop");
+            let _ = done_sender.send(());
         });
         Ok(trader)
     }
 
-    fn key(&self) -> String {
-        format!("{}:{}", self.conf.broker_id, self.conf.account)
+    /// 旧API已经Release, 使用新API重新连接, AccountBook保留, 报单引用和会话继续使用
+    fn attach_api(&mut self, api: Box<CThostFtdcTraderApi>, r: Reconnect) {
+        info!(
+            "{} 重新连接 front_group={} fens={} rebuild={}",
+            self.key(),
+            r.conf.front_group,
+            r.fens,
+            r.rebuild
+        );
+        self.api = std::mem::ManuallyDrop::new(api);
+        self.fens = r.fens;
+        let old = std::mem::replace(&mut self.conf, r.conf);
+        if r.rebuild {
+            // RESTART只用于这次重建, 之后创建API时仍按原配置续传
+            self.conf.private_resume = old.private_resume;
+            self.cta.clear_history();
+        }
+        self.cta.front_failures = 0;
+        self.scheduler.reset();
+        self.login_query = None;
+        self.api.init();
+    }
+
+    /// 签约银行未返回币种时使用账户配置的币种
+    fn bank_currency<'a>(&'a self, bank: &'a BankAccountRow) -> &'a str {
+        if bank.currency_id.is_empty() {
+            self.conf.currency()
+        } else {
+            &bank.currency_id
+        }
     }
 
     fn get_request_id(&mut self) -> i32 {
@@ -160,137 +492,1002 @@ impl Trader {
         self.request_id
     }
 
-    pub fn status(&self) -> CtaStatus {
-        self.cta.status.clone()
+    /// 报单引用在交易日内单调递增, 不与之前会话的报单冲突
+    fn next_order_ref(&mut self) -> String {
+        self.cta.order_ref += 1;
+        self.cta.order_ref.to_string()
     }
 
-    pub fn status_description(&self) -> String {
-        self.cta.status_description.clone()
+    fn order_key(&self, order_ref: &str) -> String {
+        format!(
+            "{}:{}:{}",
+            self.cta.front_id, self.cta.session_id, order_ref
+        )
+    }
+
+    /// 报单被CTP或交易所拒绝, 更新到对应OrderRow并通知等待的调用方
+    async fn on_order_insert_error(
+        &mut self,
+        tp: &str,
+        input: &Option<CThostFtdcInputOrderField>,
+        rsp_info: &Option<CThostFtdcRspInfoField>,
+    ) {
+        let input = match input {
+            Some(input) => input,
+            None => {
+                self.on_error(tp, rsp_info, 0, "").await;
+                return;
+            }
+        };
+        let order_ref = ctp_str(&input.OrderRef);
+        let k = self.order_key(&order_ref);
+        let msg = match self.on_error(tp, rsp_info, input.RequestID, &k).await {
+            Some(e) => e.error_msg,
+            None => return,
+        };
+        let (front_id, session_id) = (self.cta.front_id, self.cta.session_id);
+        let o = self
+            .cta
+            .orders
+            .entry(k.clone())
+            .or_insert_with(|| OrderRow {
+                front_id,
+                session_id,
+                order_ref,
+                broker_id: ctp_str(&input.BrokerID),
+                account: ctp_str(&input.InvestorID),
+                exchange: ctp_str(&input.ExchangeID),
+                symbol: ctp_str(&input.InstrumentID),
+                direction: input.Direction as i32,
+                offset: input.CombOffsetFlag[0] as i32,
+                limit_price: input.LimitPrice,
+                volume_total_original: input.VolumeTotalOriginal,
+                ..Default::default()
+            });
+        o.status = THOST_FTDC_OST_Canceled as i32;
+        o.status_description = msg.clone();
+        if let Some(tx) = self.pending_orders.remove(&k) {
+            let _ = tx.send(Err(msg));
+        }
+        self.send_event(self.make_event(tp, &k)).await;
+    }
+
+    /// 撤单被CTP或交易所拒绝
+    async fn on_order_action_error(
+        &mut self,
+        tp: &str,
+        k: String,
+        rsp_info: &Option<CThostFtdcRspInfoField>,
+    ) {
+        let e = match self.on_error(tp, rsp_info, 0, &k).await {
+            Some(e) => e,
+            None => return,
+        };
+        if let Some(o) = self.cta.orders.get_mut(&k) {
+            o.status_description = format!("撤单失败: {}", e.error_msg);
+        }
+        if let Some(tx) = self.pending_actions.remove(&k) {
+            let _ = tx.send(Err(Error::OrderActionFailed {
+                error_id: e.error_id,
+                msg: e.error_msg,
+            }));
+        }
+        self.send_event(self.make_event(tp, &k)).await;
+    }
+
+    /// 记录错误回报并推送CtpError事件, 没有错误时返回None
+    async fn on_error(
+        &mut self,
+        source: &str,
+        rsp_info: &Option<CThostFtdcRspInfoField>,
+        request_id: i32,
+        key: &str,
+    ) -> Option<CtpError> {
+        let mut e = CtpError::decode(source, rsp_info)?;
+        e.broker_id = self.conf.broker_id.clone();
+        e.account = self.conf.account.clone();
+        e.request_id = request_id;
+        e.key = key.to_string();
+        warn!(
+            "{} {} key={} ErrorID={} ErrorMsg={} {:?}",
+            self.key(),
+            source,
+            key,
+            e.error_id,
+            e.error_msg,
+            e.category
+        );
+        self.cta.push_error(e.clone());
+        self.send_event(self.make_event("CtpError", key).with_error(e.clone()))
+            .await;
+        Some(e)
+    }
+
+    /// 事件通道在程序退出时关闭, 发送失败只记录日志
+    async fn send_event(&self, e: CtaEvent) {
+        if let Err(e) = self.event_sender.send(e).await {
+            warn!("{} 事件通道已关闭 {}", self.key(), e.0.tp());
+        }
     }
 
     fn make_event(&self, tp: &str, key: &str) -> CtaEvent {
-        CtaEvent {
-            tp: tp.to_string(),
-            b: self.conf.broker_id.clone(),
-            a: self.conf.account.clone(),
-            key: key.to_string(),
+        CtaEvent::new(tp, &self.conf.broker_id, &self.conf.account, key)
+    }
+
+    fn login_completed(&self) -> bool {
+        self.cta.status == CtaStatus::LoginCompleted
+    }
+
+    /// 连接状态迁移, 记录连接/断开时间和重连次数
+    fn set_status(&mut self, status: CtaStatus) {
+        match status {
+            CtaStatus::Connected => {
+                if self.cta.disconnected_at > 0 {
+                    self.cta.reconnect_count += 1;
+                }
+                self.cta.connected_at = now_ts();
+            }
+            CtaStatus::Disconnected => {
+                self.cta.disconnected_at = now_ts();
+                // 断线后未完成的报单/撤单请求结果未知, 通知调用方不再等待
+                self.pending_orders.clear();
+                self.pending_actions.clear();
+                self.retry_at = None;
+                self.scheduler.reset();
+                self.login_query = None;
+                self.rate_requested.clear();
+            }
+            CtaStatus::LoginSucceeded => {
+                self.cta.login_failures = 0;
+                self.cta.front_failures = 0;
+            }
+            _ => {}
+        }
+        if self.cta.status != status {
+            info!(
+                "{} status {:?} -> {:?}",
+                self.key(),
+                self.cta.status,
+                status
+            );
+        }
+        self.cta.status = status;
+    }
+
+    fn req_authenticate(&mut self) {
+        let mut req = CThostFtdcReqAuthenticateField::default();
+        set_cstr_from_str_truncate_i8(&mut req.BrokerID, &self.conf.broker_id);
+        set_cstr_from_str_truncate_i8(&mut req.UserID, &self.conf.account);
+        set_cstr_from_str_truncate_i8(&mut req.AuthCode, &self.broker.auth_code);
+        set_cstr_from_str_truncate_i8(&mut req.UserProductInfo, &self.broker.user_product_info);
+        set_cstr_from_str_truncate_i8(&mut req.AppID, &self.broker.app_id);
+        let request_id = self.get_request_id();
+        let result = self.api.req_authenticate(&mut req, request_id);
+        if result != 0 {
+            error!("{} ReqAuthenticate={}", self.key(), result);
+        }
+    }
+
+    fn req_user_login(&mut self) {
+        let mut req = CThostFtdcReqUserLoginField::default();
+        set_cstr_from_str_truncate_i8(&mut req.BrokerID, &self.conf.broker_id);
+        set_cstr_from_str_truncate_i8(&mut req.UserID, &self.conf.account);
+        set_cstr_from_str_truncate_i8(&mut req.Password, &self.conf.password);
+        let request_id = self.get_request_id();
+        let result = self.api.req_user_login(&mut req, request_id);
+        if result != 0 {
+            error!("{} ReqUserLogin={}", self.key(), result);
+        }
+    }
+
+    /// 已登录时发送登出请求, 返回是否需要等待登出回报
+    fn req_user_logout(&mut self) -> bool {
+        if self.cta.status != CtaStatus::LoginSucceeded && !self.login_completed() {
+            return false;
+        }
+        let mut req = CThostFtdcUserLogoutField::default();
+        set_cstr_from_str_truncate_i8(&mut req.BrokerID, &self.conf.broker_id);
+        set_cstr_from_str_truncate_i8(&mut req.UserID, &self.conf.account);
+        let request_id = self.get_request_id();
+        let result = self.api.req_user_logout(&mut req, request_id);
+        if result != 0 {
+            error!("{} ReqUserLogout={}", self.key(), result);
+        }
+        result == 0
+    }
+
+    /// 中继模式上报的终端信息, 登录时间使用北京时间
+    fn user_system_info(&self) -> CThostFtdcUserSystemInfoField {
+        let mut req = CThostFtdcUserSystemInfoField::default();
+        set_cstr_from_str_truncate_i8(&mut req.BrokerID, &self.conf.broker_id);
+        set_cstr_from_str_truncate_i8(&mut req.UserID, &self.conf.account);
+        let info = self.broker.system_info();
+        set_cstr_from_str_truncate_i8(&mut req.ClientSystemInfo, &info);
+        req.ClientSystemInfoLen = info.len().min(MAX_SYSTEM_INFO_LEN) as i32;
+        let login_time = if self.cta.login.login_time.is_empty() {
+            let t = (now_ts() + 8 * 3600) % 86400;
+            format!("{:02}:{:02}:{:02}", t / 3600, t / 60 % 60, t % 60)
+        } else {
+            self.cta.login.login_time.clone()
+        };
+        set_cstr_from_str_truncate_i8(&mut req.ClientLoginTime, &login_time);
+        set_cstr_from_str_truncate_i8(&mut req.ClientAppID, &self.broker.app_id);
+        req
+    }
+
+    /// 中继多连接模式在认证后登录前注册终端信息
+    fn req_register_user_system_info(&mut self) {
+        let mut req = self.user_system_info();
+        let result = self.api.req_register_user_system_info(&mut req);
+        if result != 0 {
+            error!("{} ReqRegisterUserSystemInfo={}", self.key(), result);
+        } else {
+            info!("{} 注册终端信息", self.key());
+        }
+    }
+
+    /// 中继操作员模式在登录后上报终端信息
+    fn req_submit_user_system_info(&mut self) {
+        let mut req = self.user_system_info();
+        let result = self.api.req_submit_user_system_info(&mut req);
+        if result != 0 {
+            error!("{} ReqSubmitUserSystemInfo={}", self.key(), result);
+        } else {
+            info!("{} 上报终端信息", self.key());
+        }
+    }
+
+    /// 认证或登录失败后按指数退避重新认证, 密码错误等不可恢复的错误不重试
+    fn schedule_relogin(&mut self, error_id: i32) {
+        if FATAL_LOGIN_ERRORS.contains(&error_id) || self.cta.login_failures >= MAX_LOGIN_RETRIES {
+            error!(
+                "{} 登录失败 ErrorID={} 已失败{}次, 不再重试",
+                self.key(),
+                error_id,
+                self.cta.login_failures
+            );
+            self.retry_at = None;
+            return;
+        }
+        self.cta.login_failures += 1;
+        let delay = (1u64 << self.cta.login_failures).min(60);
+        warn!(
+            "{} 登录失败 ErrorID={} 第{}次, {}秒后重试",
+            self.key(),
+            error_id,
+            self.cta.login_failures,
+            delay
+        );
+        self.retry_at = Some(tokio::time::Instant::now() + tokio::time::Duration::from_secs(delay));
+    }
+
+    /// 登录成功前连续失败达到阈值后切换到下一个前置组, 由后台任务释放当前连接后重新连接
+    async fn check_failover(&mut self) {
+        if self.cta.front_failures < FRONT_FAILOVER_THRESHOLD
+            || self.shutting_down
+            || self.pending_reconnect.is_some()
+        {
+            return;
+        }
+        let routes = trade_routes(&self.broker);
+        if routes.len() < 2 {
+            return;
+        }
+        let i = routes
+            .iter()
+            .position(|(fg, fens)| fg.id == self.conf.front_group && *fens == self.fens)
+            .map_or(0, |i| (i + 1) % routes.len());
+        let (front, fens) = (routes[i].0.clone(), routes[i].1);
+        let mut conf = self.conf.clone();
+        conf.front_group = front.id.clone();
+        warn!(
+            "{} 前置组{}连续失败{}次, 切换到{} fens={}",
+            self.key(),
+            self.conf.front_group,
+            self.cta.front_failures,
+            conf.front_group,
+            fens
+        );
+        self.send_event(self.make_event("FrontFailover", &conf.front_group))
+            .await;
+        self.pending_reconnect = Some(Reconnect {
+            conf,
+            front,
+            fens,
+            rebuild: false,
+        });
+    }
+
+    /// 每秒调用一次, 处理到期的重新认证
+    fn on_timer(&mut self) {
+        if let Some(at) = self.retry_at {
+            if tokio::time::Instant::now() >= at {
+                self.retry_at = None;
+                if self.cta.status == CtaStatus::AuthenticateFailed
+                    || self.cta.status == CtaStatus::LoginFailed
+                {
+                    info!("{} 重新认证", self.key());
+                    self.req_authenticate();
+                }
+            }
+        }
+    }
+
+    /// 加入查询队列, 相同的查询已经在队列中时不重复加入
+    fn schedule_query(&mut self, q: Query) -> Option<oneshot::Receiver<Result<(), Error>>> {
+        if self.scheduler.contains(&q) {
+            return None;
+        }
+        Some(self.scheduler.push(q, false))
+    }
+
+    /// 由定时器调用, 按流控发出队列中的下一个查询
+    fn poll_queries(&mut self) {
+        if self.cta.status != CtaStatus::LoginSucceeded && !self.login_completed() {
+            return;
+        }
+        let now = tokio::time::Instant::now();
+        if let Some(qq) = self.scheduler.next_ready(now) {
+            let request_id = self.get_request_id();
+            let result = self.send_query(&qq.query, request_id);
+            match result {
+                0 => self.scheduler.sent(request_id, qq, now),
+                // -2: 未处理请求超过许可数 -3: 每秒发送请求数超过许可数
+                -2 | -3 => {
+                    warn!(
+                        "{} {:?} 流控 result={}, 稍后重试",
+                        self.key(),
+                        qq.query,
+                        result
+                    );
+                    self.scheduler.retry(qq, now);
+                }
+                _ => {
+                    error!("{} {:?} = {}", self.key(), qq.query, result);
+                    let _ = qq.result.send(Err(Error::RequestFailed(result)));
+                }
+            }
         }
     }
 
-    fn req_query_trading_account(&mut self) {
-        let broker_id = &self.conf.broker_id;
-        let account = &self.conf.account;
-        let mut req = CThostFtdcQryTradingAccountField::default();
-        set_cstr_from_str_truncate_i8(&mut req.BrokerID, broker_id);
-        set_cstr_from_str_truncate_i8(&mut req.InvestorID, account);
+    fn send_query(&mut self, q: &Query, request_id: i32) -> i32 {
+        let broker_id = self.conf.broker_id.clone();
+        let account = self.conf.account.clone();
+        match q {
+            Query::TradingAccount => {
+                let mut req = CThostFtdcQryTradingAccountField::default();
+                set_cstr_from_str_truncate_i8(&mut req.BrokerID, &broker_id);
+                set_cstr_from_str_truncate_i8(&mut req.InvestorID, &account);
+                self.api.req_qry_trading_account(&mut req, request_id)
+            }
+            Query::PositionDetail => {
+                // 重连后以查询结果为准, 清除断线前的持仓明细
+                self.cta.position_details.clear();
+                let mut req = CThostFtdcQryInvestorPositionDetailField::default();
+                set_cstr_from_str_truncate_i8(&mut req.BrokerID, &broker_id);
+                set_cstr_from_str_truncate_i8(&mut req.InvestorID, &account);
+                self.api
+                    .req_qry_investor_position_detail(&mut req, request_id)
+            }
+            Query::Position => {
+                self.queried_positions.clear();
+                let mut req = CThostFtdcQryInvestorPositionField::default();
+                set_cstr_from_str_truncate_i8(&mut req.BrokerID, &broker_id);
+                set_cstr_from_str_truncate_i8(&mut req.InvestorID, &account);
+                self.api.req_qry_investor_position(&mut req, request_id)
+            }
+            Query::Instrument => {
+                let mut req = CThostFtdcQryInstrumentField::default();
+                self.api.req_qry_instrument(&mut req, request_id)
+            }
+            Query::DepthMarketData(k) => {
+                let (exchange, symbol) = k.split_once(':').unwrap_or(("", k));
+                let mut req = CThostFtdcQryDepthMarketDataField::default();
+                set_cstr_from_str_truncate_i8(&mut req.ExchangeID, exchange);
+                set_cstr_from_str_truncate_i8(&mut req.InstrumentID, symbol);
+                self.api.req_qry_depth_market_data(&mut req, request_id)
+            }
+            Query::Order => {
+                let mut req = CThostFtdcQryOrderField::default();
+                set_cstr_from_str_truncate_i8(&mut req.BrokerID, &broker_id);
+                set_cstr_from_str_truncate_i8(&mut req.InvestorID, &account);
+                self.api.req_qry_order(&mut req, request_id)
+            }
+            Query::Trade => {
+                let mut req = CThostFtdcQryTradeField::default();
+                set_cstr_from_str_truncate_i8(&mut req.BrokerID, &broker_id);
+                set_cstr_from_str_truncate_i8(&mut req.InvestorID, &account);
+                self.api.req_qry_trade(&mut req, request_id)
+            }
+            Query::ParkedOrder => {
+                // 以查询结果为准, 条件单随报单更新
+                self.cta.parked_orders.retain(|_, o| o.conditional);
+                let mut req = CThostFtdcQryParkedOrderField::default();
+                set_cstr_from_str_truncate_i8(&mut req.BrokerID, &broker_id);
+                set_cstr_from_str_truncate_i8(&mut req.InvestorID, &account);
+                self.api.req_qry_parked_order(&mut req, request_id)
+            }
+            Query::SettlementInfo(day) => {
+                self.settlement_buffer.clear();
+                self.settlement_day.clear();
+                let mut req = CThostFtdcQrySettlementInfoField::default();
+                set_cstr_from_str_truncate_i8(&mut req.BrokerID, &broker_id);
+                set_cstr_from_str_truncate_i8(&mut req.InvestorID, &account);
+                set_cstr_from_str_truncate_i8(&mut req.TradingDay, day);
+                self.api.req_qry_settlement_info(&mut req, request_id)
+            }
+            Query::AccountRegister => {
+                let mut req = CThostFtdcQryAccountregisterField::default();
+                set_cstr_from_str_truncate_i8(&mut req.BrokerID, &broker_id);
+                set_cstr_from_str_truncate_i8(&mut req.AccountID, &account);
+                self.api.req_qry_accountregister(&mut req, request_id)
+            }
+            Query::TransferSerial => {
+                let mut req = CThostFtdcQryTransferSerialField::default();
+                set_cstr_from_str_truncate_i8(&mut req.BrokerID, &broker_id);
+                set_cstr_from_str_truncate_i8(&mut req.AccountID, &account);
+                self.api.req_qry_transfer_serial(&mut req, request_id)
+            }
+            Query::CommissionRate(k) => {
+                let (exchange, symbol) = k.split_once(':').unwrap_or(("", k));
+                let mut req = CThostFtdcQryInstrumentCommissionRateField::default();
+                set_cstr_from_str_truncate_i8(&mut req.BrokerID, &broker_id);
+                set_cstr_from_str_truncate_i8(&mut req.InvestorID, &account);
+                set_cstr_from_str_truncate_i8(&mut req.ExchangeID, exchange);
+                set_cstr_from_str_truncate_i8(&mut req.InstrumentID, symbol);
+                self.api
+                    .req_qry_instrument_commission_rate(&mut req, request_id)
+            }
+            Query::MarginRate(k) => {
+                let (exchange, symbol) = k.split_once(':').unwrap_or(("", k));
+                let mut req = CThostFtdcQryInstrumentMarginRateField::default();
+                set_cstr_from_str_truncate_i8(&mut req.BrokerID, &broker_id);
+                set_cstr_from_str_truncate_i8(&mut req.InvestorID, &account);
+                set_cstr_from_str_truncate_i8(&mut req.ExchangeID, exchange);
+                set_cstr_from_str_truncate_i8(&mut req.InstrumentID, symbol);
+                req.HedgeFlag = THOST_FTDC_HF_Speculation as i8;
+                self.api
+                    .req_qry_instrument_margin_rate(&mut req, request_id)
+            }
+        }
+    }
+
+    /// 查询的最后一个响应, 通知等待的调用方并推进登录查询
+    /// 返回是否为排队的查询
+    async fn on_query_done(
+        &mut self,
+        request_id: i32,
+        rsp_info: &Option<CThostFtdcRspInfoField>,
+    ) -> bool {
+        let result = match rsp_info {
+            Some(r) if r.ErrorID != 0 => Err(Error::QueryFailed {
+                error_id: r.ErrorID,
+                msg: gb18030_cstr_to_str_i8(&r.ErrorMsg).to_string(),
+            }),
+            _ => Ok(()),
+        };
+        match self.scheduler.complete(request_id, result) {
+            Some(q) => {
+                self.on_error(&format!("Qry{:?}", q), rsp_info, request_id, "")
+                    .await;
+                if self.login_query.as_ref() == Some(&q) {
+                    self.on_login_query_done(q).await;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn req_settlement_info_confirm(&mut self) -> i32 {
+        let mut req = CThostFtdcSettlementInfoConfirmField::default();
+        set_cstr_from_str_truncate_i8(&mut req.BrokerID, &self.conf.broker_id);
+        set_cstr_from_str_truncate_i8(&mut req.InvestorID, &self.conf.account);
         let request_id = self.get_request_id();
-        let result = self.api.req_qry_trading_account(&mut req, request_id);
+        let result = self.api.req_settlement_info_confirm(&mut req, request_id);
         if result != 0 {
-            error!("{} ReqQueryTradingAccount={}", self.key(), result);
+            error!("{} ReqSettlementInfoConfirm={}", self.key(), result);
+        }
+        result
+    }
+
+    /// 结算单接收完成, 保存后按账户配置自动确认或等待手动确认
+    async fn on_settlement_queried(&mut self) {
+        let buffer = std::mem::take(&mut self.settlement_buffer);
+        let day = std::mem::take(&mut self.settlement_day);
+        if buffer.len() > 0 {
+            let row = SettlementRow {
+                broker_id: self.conf.broker_id.clone(),
+                account: self.conf.account.clone(),
+                trading_day: day,
+                content: gb18030_cstr_to_str_i8(&buffer).to_string(),
+            };
+            info!(
+                "{} 查询结算单完成 trading_day={} l={}",
+                self.key(),
+                row.trading_day,
+                row.content.len()
+            );
+            let dir = ".cache/settlements";
+            check_make_dir(dir);
+            let path = format!(
+                "{}/{}_{}_{}.txt",
+                dir, self.conf.broker_id, self.conf.account, row.trading_day
+            );
+            if let Err(e) = std::fs::write(&path, &row.content) {
+                error!("{} 保存结算单失败 {} {}", self.key(), path, e);
+            }
+            let k = self
+                .cta
+                .apply(GatewayEvent::Settlement(row))
+                .unwrap_or_default();
+            self.send_event(self.make_event("OnRspQrySettlementInfo", &k))
+                .await;
+        }
+        let login = matches!(self.login_query, Some(Query::SettlementInfo(_)));
+        if !login || self.cta.settlement_confirmed {
+            return;
+        }
+        match self.conf.settlement_confirm {
+            SettlementConfirm::Auto => {
+                self.req_settlement_info_confirm();
+            }
+            SettlementConfirm::Manual => {
+                info!("{} 等待手动确认结算单", self.key());
+                self.send_event(self.make_event("SettlementPending", ""))
+                    .await;
+            }
+        }
+    }
+
+    /// 转账需要的签约银行信息, 同时检查资金密码
+    fn bank_account(&self, bank_id: &str) -> Result<BankAccountRow, Error> {
+        if self.cta.status != CtaStatus::LoginCompleted {
+            return Err(Error::NotLoggedIn);
+        }
+        if self.broker.money_password.len() == 0 {
+            return Err(Error::InvalidTransfer("经纪商未设置资金密码".into()));
+        }
+        self.cta
+            .bank_accounts
+            .get(bank_id)
+            .cloned()
+            .ok_or(Error::InvalidTransfer(format!(
+                "没有签约银行{bank_id}, 请先查询签约银行"
+            )))
+    }
+
+    /// 转账请求被CTP或银行拒绝
+    async fn on_transfer_error(
+        &mut self,
+        tp: &str,
+        req: &Option<CThostFtdcReqTransferField>,
+        rsp_info: &Option<CThostFtdcRspInfoField>,
+    ) {
+        let req = match req {
+            Some(req) => req,
+            None => {
+                self.on_error(tp, rsp_info, 0, "").await;
+                return;
+            }
+        };
+        let mut row = TransferRow::from(req);
+        let k = row.key();
+        let e = match self.on_error(tp, rsp_info, req.RequestID, &k).await {
+            Some(e) => e,
+            None => return,
+        };
+        row.error_id = e.error_id;
+        row.error_msg = e.error_msg;
+        self.cta.apply(GatewayEvent::Transfer(row));
+        self.send_event(self.make_event("TransferFailed", &k)).await;
+    }
+
+    /// 银行返回的转账结果, 成功后刷新资金
+    async fn on_transfer_result(&mut self, tp: &str, t: &CThostFtdcRspTransferField) {
+        let row = TransferRow::from(t);
+        if row.plate_serial != 0 {
+            self.cta.transfers.remove(&format!("R{}", row.request_id));
+        }
+        let tp = if row.error_id != 0 {
+            warn!(
+                "{} 银期转账失败 {:?} amount={} ErrorID={} ErrorMsg={}",
+                self.key(),
+                row.direction,
+                row.amount,
+                row.error_id,
+                row.error_msg
+            );
+            "TransferFailed"
+        } else {
+            info!(
+                "{} 银期转账成功 {:?} amount={} plate_serial={}",
+                self.key(),
+                row.direction,
+                row.amount,
+                row.plate_serial
+            );
+            let _ = self.schedule_query(Query::TradingAccount);
+            tp
+        };
+        let k = self
+            .cta
+            .apply(GatewayEvent::Transfer(row))
+            .unwrap_or_default();
+        self.send_event(self.make_event(tp, &k)).await;
+    }
+
+    /// 银行余额查询被拒绝
+    async fn on_bank_balance_error(
+        &mut self,
+        tp: &str,
+        req: &Option<CThostFtdcReqQueryAccountField>,
+        rsp_info: &Option<CThostFtdcRspInfoField>,
+    ) {
+        let bank_id = req.as_ref().map_or(String::new(), |r| ctp_str(&r.BankID));
+        let request_id = req.as_ref().map_or(0, |r| r.RequestID);
+        let e = match self.on_error(tp, rsp_info, request_id, &bank_id).await {
+            Some(e) => e,
+            None => return,
+        };
+        if let Some(b) = self.cta.bank_accounts.get_mut(&bank_id) {
+            b.status_description = e.error_msg;
+        }
+        self.send_event(self.make_event("BankBalanceFailed", &bank_id))
+            .await;
+    }
+
+    /// 持仓查询完成, 登录后的定时查询与本地持仓核对, 以经纪商的持仓为准
+    async fn on_positions_queried(&mut self) {
+        let queried = std::mem::take(&mut self.queried_positions);
+        if self.login_completed() {
+            for k in self.cta.position_mismatches(&queried) {
+                let local = self.cta.positions.get(&k);
+                let broker = queried.get(&k);
+                warn!(
+                    "{} 持仓不一致 {} 本地={:?} 经纪商={:?}",
+                    self.key(),
+                    k,
+                    local.map(|p| (p.position, p.today_position, p.yd_position)),
+                    broker.map(|p| (p.position, p.today_position, p.yd_position))
+                );
+                self.send_event(self.make_event("PositionMismatch", &k))
+                    .await;
+            }
+        }
+        self.cta.positions.clear();
+        for (_, p) in queried {
+            self.cta.apply(GatewayEvent::Position(p));
+        }
+        self.cta.update_frozen();
+        self.cta.revalue_all();
+    }
+
+    /// 合约或所属品种的手续费率
+    pub fn commission_rate(&self, exchange: &str, symbol: &str) -> Option<&CommissionRateRow> {
+        self.cta.commission_rates.get(symbol).or_else(|| {
+            self.cta
+                .instruments
+                .get(&format!("{exchange}:{symbol}"))
+                .and_then(|i| self.cta.commission_rates.get(&i.product_id))
+        })
+    }
+
+    /// 合约或所属品种的保证金率
+    pub fn margin_rate(&self, exchange: &str, symbol: &str) -> Option<&MarginRateRow> {
+        self.cta.margin_rates.get(symbol).or_else(|| {
+            self.cta
+                .instruments
+                .get(&format!("{exchange}:{symbol}"))
+                .and_then(|i| self.cta.margin_rates.get(&i.product_id))
+        })
+    }
+
+    /// 持有或交易的合约没有费率时加入查询队列, 每个合约只查询一次
+    fn ensure_rates(&mut self, exchange: &str, symbol: &str) {
+        let k = format!("{exchange}:{symbol}");
+        if self.rate_requested.contains(&k) {
+            return;
+        }
+        self.rate_requested.insert(k.clone());
+        if self.commission_rate(exchange, symbol).is_none() {
+            let _ = self.schedule_query(Query::CommissionRate(k.clone()));
+        }
+        if self.margin_rate(exchange, symbol).is_none() {
+            let _ = self.schedule_query(Query::MarginRate(k));
+        }
+    }
+
+    fn volume_multiple(&self, exchange: &str, symbol: &str) -> i32 {
+        self.cta
+            .instruments
+            .get(&format!("{exchange}:{symbol}"))
+            .map_or(1, |i| i.volume_multiple)
+    }
+
+    fn trade_commission(&self, t: &TradeRow) -> f64 {
+        self.commission_rate(&t.exchange, &t.symbol)
+            .map_or(0.0, |r| {
+                r.commission(
+                    t.offset,
+                    t.price,
+                    t.volume,
+                    self.volume_multiple(&t.exchange, &t.symbol),
+                )
+            })
+    }
+
+    /// 登录查询按顺序进行, 插到队首优先于按需查询
+    fn start_login_query(&mut self, q: Query) {
+        self.login_query = Some(q.clone());
+        let _ = self.scheduler.push(q, true);
+    }
+
+    /// 交易日变化时归档上一交易日的报单和成交并重置内存表, 返回是否发生了切换
+    async fn check_trading_day(&mut self, trading_day: &str) -> bool {
+        if trading_day.len() == 0 || trading_day <= self.cta.trading_day.as_str() {
+            return false;
+        }
+        if self.cta.trading_day.len() == 0 {
+            self.cta.trading_day = trading_day.to_string();
+            return false;
+        }
+        let archive = self.cta.rollover(trading_day);
+        info!(
+            "{} 交易日切换 {} -> {}, 归档报单{}笔 成交{}笔",
+            self.key(),
+            archive.trading_day,
+            trading_day,
+            archive.orders.len(),
+            archive.trades.len()
+        );
+        let dir = ".cache/archive";
+        check_make_dir(dir);
+        let path = format!(
+            "{}/{}_{}_{}.json",
+            dir, self.conf.broker_id, self.conf.account, archive.trading_day
+        );
+        match serde_json::to_string_pretty(&archive) {
+            Ok(s) => {
+                if let Err(e) = std::fs::write(&path, s) {
+                    error!("{} 保存归档失败 {} {}", self.key(), path, e);
+                }
+            }
+            Err(e) => error!("{} 序列化归档失败 {}", self.key(), e),
+        }
+        self.send_event(self.make_event("TradingDayRollover", trading_day))
+            .await;
+        true
+    }
+
+    /// 登录查询的某一步完成, 发出下一步查询或者标记登录完成
+    async fn on_login_query_done(&mut self, q: Query) {
+        match q.next_login_query(!self.cta.initialized) {
+            Some(next) => self.start_login_query(next),
+            None => {
+                self.login_query = None;
+                self.set_status(CtaStatus::LoginCompleted);
+                self.cta.initialized = true;
+                self.cta.revalue_all();
+                let held = self
+                    .cta
+                    .positions
+                    .values()
+                    .map(|p| (p.exchange.clone(), p.symbol.clone()))
+                    .chain(
+                        self.cta
+                            .trades
+                            .values()
+                            .map(|t| (t.exchange.clone(), t.symbol.clone())),
+                    )
+                    .collect::<Vec<_>>();
+                for (exchange, symbol) in held.iter() {
+                    self.ensure_rates(exchange, symbol);
+                }
+                self.send_event(self.make_event("LoginCompleted", "")).await;
+            }
         }
     }
 
     async fn handle_spi_msg(&mut self, spi_msg: &CThostFtdcTraderSpiOutput) {
-        let conf = &self.conf;
-        let broker_id = conf.broker_id.as_str();
-        let account = conf.account.as_str();
-        let auth_code = self.broker.auth_code.as_str();
-        let user_product_info = self.broker.user_product_info.as_str();
-        let app_id = self.broker.app_id.as_str();
-        let password = conf.password.as_str();
-        let login_completed = || self.cta.status == CtaStatus::LoginCompleted;
         use ctp_futures::trader_api::CThostFtdcTraderSpiOutput::*;
         match spi_msg {
             OnFrontConnected(_p) => {
-                let mut req = CThostFtdcReqAuthenticateField::default();
-                set_cstr_from_str_truncate_i8(&mut req.BrokerID, broker_id);
-                set_cstr_from_str_truncate_i8(&mut req.UserID, account);
-                set_cstr_from_str_truncate_i8(&mut req.AuthCode, auth_code);
-                set_cstr_from_str_truncate_i8(&mut req.UserProductInfo, user_product_info);
-                set_cstr_from_str_truncate_i8(&mut req.AppID, app_id);
-                let request_id = self.get_request_id();
-                self.api.req_authenticate(&mut req, request_id);
-                info!("{} OnFrontConnected", self.key());
-                self.cta.status = CtaStatus::Connected;
-                self.event_sender
-                    .send(self.make_event("OnFrontConnected", ""))
-                    .await
-                    .unwrap();
+                info!(
+                    "{} OnFrontConnected reconnect_count={}",
+                    self.key(),
+                    self.cta.reconnect_count
+                );
+                self.set_status(CtaStatus::Connected);
+                self.req_authenticate();
+                self.send_event(self.make_event("OnFrontConnected", ""))
+                    .await;
             }
             OnFrontDisconnected(p) => {
-                info!("{} on front disconnected {:?} 直接Exit ", self.key(), p);
-                self.cta.status = CtaStatus::Disconnected;
-                self.event_sender
-                    .send(self.make_event("OnFrontDisconnected", ""))
-                    .await
-                    .unwrap();
-
-                return;
+                // CTP会自动重连, 重连成功后在OnFrontConnected中重新认证登录
+                warn!("{} on front disconnected {:?}, 等待自动重连", self.key(), p);
+                self.set_status(CtaStatus::Disconnected);
+                self.cta.front_failures += 1;
+                self.send_event(self.make_event("OnFrontDisconnected", ""))
+                    .await;
+                self.check_failover().await;
+            }
+            OnRspUserLogout(ref p) => {
+                self.on_error("OnRspUserLogout", &p.p_rsp_info, p.n_request_id, "")
+                    .await;
+                info!("{} 已登出", self.key());
+                self.set_status(CtaStatus::Disconnected);
+                self.send_event(self.make_event("OnRspUserLogout", ""))
+                    .await;
             }
             OnRspAuthenticate(ref p) => {
-                if p.p_rsp_info.as_ref().unwrap().ErrorID == 0 {
-                    let mut req = CThostFtdcReqUserLoginField::default();
-                    set_cstr_from_str_truncate_i8(&mut req.BrokerID, broker_id);
-                    set_cstr_from_str_truncate_i8(&mut req.UserID, account);
-                    set_cstr_from_str_truncate_i8(&mut req.Password, password);
-                    let request_id = self.get_request_id();
-                    self.api.req_user_login(&mut req, request_id);
-                    self.cta.status = CtaStatus::AuthenticateSucceeded;
-                    self.event_sender
-                        .send(self.make_event("OnRspAuthenticate", ""))
-                        .await
-                        .unwrap();
+                let error_id = p.p_rsp_info.map_or(0, |r| r.ErrorID);
+                if error_id == 0 {
+                    self.set_status(CtaStatus::AuthenticateSucceeded);
+                    if self.broker.route() == Ok(RouteType::Relay) {
+                        self.req_register_user_system_info();
+                    }
+                    self.req_user_login();
                 } else {
-                    info!("{} RspAuthenticate={:?}", self.key(), p);
-                    self.cta.status = CtaStatus::AuthenticateFailed;
-                    if let Some(p) = p.p_rsp_info {
-                        self.cta.status_description =
-                            gb18030_cstr_to_str_i8(&p.ErrorMsg).to_string();
-                    }
-                    self.event_sender
-                        .send(self.make_event("OnRspAuthenticate", ""))
+                    self.set_status(CtaStatus::AuthenticateFailed);
+                    if let Some(e) = self
+                        .on_error("OnRspAuthenticate", &p.p_rsp_info, p.n_request_id, "")
                         .await
-                        .unwrap();
-                    return;
+                    {
+                        self.cta.status_description = e.error_msg;
+                    }
+                    self.schedule_relogin(error_id);
+                    // 认证失败可能是前置的问题, 密码错误等换前置也无法恢复
+                    if !FATAL_LOGIN_ERRORS.contains(&error_id) {
+                        self.cta.front_failures += 1;
+                        self.check_failover().await;
+                    }
                 }
+                self.send_event(self.make_event("OnRspAuthenticate", ""))
+                    .await;
             }
             OnRspUserLogin(ref p) => {
-                if p.p_rsp_info.as_ref().unwrap().ErrorID == 0 {
-                    let _u = p.p_rsp_user_login.unwrap();
-                    self.cta.status = CtaStatus::LoginSucceeded;
-                } else {
-                    self.cta.status = CtaStatus::LoginFailed;
-                    if let Some(p) = p.p_rsp_info {
-                        self.cta.status_description =
-                            gb18030_cstr_to_str_i8(&p.ErrorMsg).to_string();
-                        warn!(
-                            "{} Trade RspUserLogin ErrorID={} ErrorMsg={}",
+                let error_id = p.p_rsp_info.map_or(0, |r| r.ErrorID);
+                let trading_day = p
+                    .p_rsp_user_login
+                    .map_or(String::new(), |u| ctp_str(&u.TradingDay));
+                if error_id == 0 {
+                    if let Some(u) = &p.p_rsp_user_login {
+                        let login = LoginInfo::from(u);
+                        info!(
+                            "{} 登录成功 front_id={} session_id={} max_order_ref={} trading_day={} system={}",
                             self.key(),
-                            p.ErrorID,
-                            gb18030_cstr_to_str_i8(&p.ErrorMsg)
+                            login.front_id,
+                            login.session_id,
+                            login.max_order_ref,
+                            login.trading_day,
+                            login.system_name
                         );
+                        self.cta.on_login(login);
+                    }
+                    if self.broker.route() == Ok(RouteType::RelayOperator) {
+                        self.req_submit_user_system_info();
                     }
+                    self.cta.status_description.clear();
+                    self.set_status(CtaStatus::LoginSucceeded);
+                } else {
+                    self.set_status(CtaStatus::LoginFailed);
+                    if let Some(e) = self
+                        .on_error("OnRspUserLogin", &p.p_rsp_info, p.n_request_id, "")
+                        .await
+                    {
+                        self.cta.status_description = e.error_msg;
+                    }
+                    self.schedule_relogin(error_id);
+                }
+                self.send_event(self.make_event("OnRspUserLogin", "")).await;
+                if PASSWORD_CHANGE_ERRORS.contains(&error_id) {
+                    warn!("{} 需要修改密码后重新登录", self.key());
+                    self.send_event(self.make_event("PasswordChangeRequired", ""))
+                        .await;
+                }
+                if error_id != 0 {
+                    return;
+                }
+                self.check_trading_day(&trading_day).await;
+                // 已确认过结算单的重连不再查询结算单
+                if self.cta.initialized && self.cta.settlement_confirmed {
+                    self.start_login_query(Query::TradingAccount);
+                } else {
+                    self.start_login_query(Query::SettlementInfo(String::new()));
                 }
-                self.event_sender
-                    .send(self.make_event("OnRspUserLogin", ""))
+            }
+            OnRspQrySettlementInfo(ref p) => {
+                if let Some(info) = &p.p_settlement_info {
+                    if self.settlement_day.len() == 0 {
+                        self.settlement_day = ctp_str(&info.TradingDay);
+                    }
+                    // 分包可能截断多字节字符, 拼接原始字节后统一解码
+                    self.settlement_buffer
+                        .extend(info.Content.iter().take_while(|c| **c != 0));
+                }
+                if p.b_is_last {
+                    self.on_settlement_queried().await;
+                    self.on_query_done(p.n_request_id, &p.p_rsp_info).await;
+                }
+            }
+            OnRspSettlementInfoConfirm(ref p) => {
+                let result = match self
+                    .on_error(
+                        "OnRspSettlementInfoConfirm",
+                        &p.p_rsp_info,
+                        p.n_request_id,
+                        "",
+                    )
                     .await
-                    .unwrap();
-                let mut req = CThostFtdcSettlementInfoConfirmField::default();
-                set_cstr_from_str_truncate_i8(&mut req.BrokerID, broker_id);
-                set_cstr_from_str_truncate_i8(&mut req.InvestorID, account);
-                let request_id = self.get_request_id();
-                let result = self.api.req_settlement_info_confirm(&mut req, request_id);
-                if result != 0 {
-                    error!("{} ReqSettlementInfoConfirm={}", self.key(), result);
+                {
+                    Some(e) => Err(Error::QueryFailed {
+                        error_id: e.error_id,
+                        msg: e.error_msg,
+                    }),
+                    None => {
+                        info!("{} 结算单已确认", self.key());
+                        self.cta.settlement_confirmed = true;
+                        Ok(())
+                    }
+                };
+                if let Some(tx) = self.pending_confirm.take() {
+                    let _ = tx.send(result);
+                }
+                self.send_event(self.make_event("OnRspSettlementInfoConfirm", ""))
+                    .await;
+            }
+            OnRspUserPasswordUpdate(ref p) => {
+                self.on_error("OnRspUserPasswordUpdate", &p.p_rsp_info, p.n_request_id, "")
+                    .await;
+                let result = password_update_result(&p.p_rsp_info);
+                let pending = self.pending_password.take();
+                if let (Ok(()), Some((password, _))) = (&result, &pending) {
+                    info!("{} 交易密码已修改", self.key());
+                    self.conf.password = password.clone();
                 }
+                // 因要求修改密码而登录失败时, 使用新密码重新登录
+                let relogin = result.is_ok() && self.cta.status == CtaStatus::LoginFailed;
+                if let Some((_, tx)) = pending {
+                    let _ = tx.send(result);
+                }
+                if relogin {
+                    self.cta.login_failures = 0;
+                    self.req_user_login();
+                }
+                self.send_event(self.make_event("OnRspUserPasswordUpdate", ""))
+                    .await;
             }
-            OnRspSettlementInfoConfirm(ref _p) => {
-                self.req_query_trading_account();
+            OnRspTradingAccountPasswordUpdate(ref p) => {
+                self.on_error(
+                    "OnRspTradingAccountPasswordUpdate",
+                    &p.p_rsp_info,
+                    p.n_request_id,
+                    "",
+                )
+                .await;
+                let result = password_update_result(&p.p_rsp_info);
+                let pending = self.pending_money_password.take();
+                if let (Ok(()), Some((password, _))) = (&result, &pending) {
+                    info!("{} 资金密码已修改", self.key());
+                    self.broker.money_password = password.clone();
+                }
+                if let Some((_, tx)) = pending {
+                    let _ = tx.send(result);
+                }
+                self.send_event(self.make_event("OnRspTradingAccountPasswordUpdate", ""))
+                    .await;
             }
 
             OnRspQryTradingAccount(ref p) => {
                 if let Some(taf) = &p.p_trading_account {
-                    if !login_completed() {
+                    // 不重新登录跨过交易日时, 重新执行登录查询
+                    let trading_day = ctp_str(&taf.TradingDay);
+                    if self.login_completed() && self.check_trading_day(&trading_day).await {
+                        self.set_status(CtaStatus::LoginSucceeded);
+                        self.start_login_query(Query::SettlementInfo(String::new()));
+                    }
+                    if !self.login_completed() {
                         info!(
                             "{} 查询账户资金完成.  account={} trading_day={:?} balance={}",
                             self.key(),
@@ -299,69 +1496,44 @@ impl Trader {
                             taf.Balance
                         );
                     }
-                    self.cta.ta = *taf;
-                    self.event_sender
-                        .send(
-                            self.make_event("OnRspQryTradingAccount", &ta_key(broker_id, account)),
-                        )
-                        .await
-                        .unwrap();
+                    self.cta
+                        .apply(GatewayEvent::Account(AccountFunds::from(taf)));
+                    self.send_event(self.make_event("OnRspQryTradingAccount", &self.key()))
+                        .await;
                 }
-                if p.b_is_last && !login_completed() {
-                    let mut req = CThostFtdcQryInvestorPositionDetailField::default();
-                    set_cstr_from_str_truncate_i8(&mut req.BrokerID, broker_id);
-                    set_cstr_from_str_truncate_i8(&mut req.InvestorID, account);
-                    let request_id = self.get_request_id();
-                    // flow control query limitation
-                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                    let result = self
-                        .api
-                        .req_qry_investor_position_detail(&mut req, request_id);
-                    if result != 0 {
-                        error!("{} ReqQryInvestorPositionDetail = {:?}", self.key(), result);
-                    }
+                if p.b_is_last {
+                    self.on_query_done(p.n_request_id, &p.p_rsp_info).await;
                 }
             }
             OnRspQryInvestorPositionDetail(ref detail) => {
                 if let Some(d) = &detail.p_investor_position_detail {
-                    let p = PositionDetailRow::from(d);
-                    if let Some(v) = self.cta.position_details.get_mut(&p.key()) {
-                        *v = p;
-                    } else {
-                        self.cta.position_details.insert(p.key(), p);
-                    }
+                    self.cta
+                        .apply(GatewayEvent::PositionDetail(PositionDetailRow::from(d)));
                 }
-                if detail.b_is_last && !login_completed() {
-                    info!("{} 查询持仓明细完成", self.key());
-                    let mut req = CThostFtdcQryInvestorPositionField::default();
-                    set_cstr_from_str_truncate_i8(&mut req.BrokerID, broker_id);
-                    set_cstr_from_str_truncate_i8(&mut req.InvestorID, account);
-                    let request_id = self.get_request_id();
-                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                    let result = self.api.req_qry_investor_position(&mut req, request_id);
-                    if result != 0 {
-                        error!("{} ReqQueryPosition={}", self.key(), result);
+                if detail.b_is_last {
+                    if !self.login_completed() {
+                        info!("{} 查询持仓明细完成", self.key());
                     }
+                    self.on_query_done(detail.n_request_id, &detail.p_rsp_info)
+                        .await;
                 }
             }
             OnRspQryInvestorPosition(ref p) => {
                 if let Some(p) = &p.p_investor_position {
                     let p = PositionRow::from(p);
-                    if let Some(v) = self.cta.positions.get_mut(&p.key()) {
-                        *v = p;
-                    } else {
-                        self.cta.positions.insert(p.key(), p);
+                    match self.queried_positions.get_mut(&p.key()) {
+                        Some(v) => v.merge(&p),
+                        None => {
+                            self.queried_positions.insert(p.key(), p);
+                        }
                     }
                 }
-                if p.b_is_last && !login_completed() {
-                    info!("{} 查询持仓完成", self.key());
-                    let mut req = CThostFtdcQryInstrumentField::default();
-                    let request_id = self.get_request_id();
-                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                    let result = self.api.req_qry_instrument(&mut req, request_id);
-                    if result != 0 {
-                        error!("{} ReqQryInstrument = {:?}", self.key(), result);
+                if p.b_is_last {
+                    if !self.login_completed() {
+                        info!("{} 查询持仓完成", self.key());
                     }
+                    self.on_positions_queried().await;
+                    self.on_query_done(p.n_request_id, &p.p_rsp_info).await;
                 }
             }
             OnRspQryInstrument(ref p) => {
@@ -369,113 +1541,1057 @@ impl Trader {
                     let mut instrument = InstrumentRow::from(instrument);
                     instrument.broker_id = self.conf.broker_id.clone();
                     instrument.account = self.conf.account.clone();
-                    if let Some(v) = self.cta.instruments.get_mut(&instrument.key()) {
-                        *v = instrument;
-                    } else {
-                        self.cta.instruments.insert(instrument.key(), instrument);
-                    }
+                    self.cta.apply(GatewayEvent::Instrument(instrument));
                 }
-                if p.b_is_last && !login_completed() {
-                    // 查询行情
-                    info!("{} 查询合约完成", self.key());
-                    let mut req = CThostFtdcQryDepthMarketDataField::default();
-                    let request_id = self.get_request_id();
-                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                    let result = self.api.req_qry_depth_market_data(&mut req, request_id);
-                    if result != 0 {
-                        error!("{} ReqQryDepthMarketData= {:?}", self.key(), result);
+                if p.b_is_last {
+                    if !self.login_completed() {
+                        info!("{} 查询合约完成", self.key());
                     }
+                    self.on_query_done(p.n_request_id, &p.p_rsp_info).await;
                 }
             }
             OnRspQryDepthMarketData(ref p) => {
-                if p.p_depth_market_data.is_some() {}
-                if p.b_is_last && !login_completed() {
-                    info!("{} 查询行情完成 l={}", self.key(), 0);
-                    let mut req = CThostFtdcQryOrderField::default();
-                    set_cstr_from_str_truncate_i8(&mut req.BrokerID, broker_id);
-                    set_cstr_from_str_truncate_i8(&mut req.InvestorID, account);
-                    let request_id = self.get_request_id();
-                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                    let result = self.api.req_qry_order(&mut req, request_id);
-                    if result != 0 {
-                        error!("{} ReqQryOrder = {:?}", result, self.key());
+                if let Some(md) = &p.p_depth_market_data {
+                    let k = self
+                        .cta
+                        .apply(GatewayEvent::DepthMarketData(MarketDataRow::from(md)))
+                        .unwrap_or_default();
+                    // 登录时全市场查询数量很大, 只对按需查询发送事件
+                    if self.login_completed() {
+                        self.send_event(self.make_event("OnRspQryDepthMarketData", &k))
+                            .await;
                     }
                 }
+                if p.b_is_last {
+                    if !self.login_completed() {
+                        info!(
+                            "{} 查询行情完成 l={}",
+                            self.key(),
+                            self.cta.depth_snapshots.len()
+                        );
+                    }
+                    self.on_query_done(p.n_request_id, &p.p_rsp_info).await;
+                }
             }
             OnRspQryOrder(ref p) => {
                 if let Some(o) = &p.p_order {
-                    let o = OrderRow::from(o);
-                    self.cta.orders.insert(o.key(), o);
+                    let applied = self.cta.apply(GatewayEvent::Order(OrderRow::from(o)));
+                    if applied.is_some() && o.ContingentCondition != THOST_FTDC_CC_Immediately as i8
+                    {
+                        self.cta
+                            .apply(GatewayEvent::ParkedOrder(ParkedOrderRow::from(o)));
+                    }
                 }
-                if p.b_is_last && !login_completed() {
-                    info!("{} 查询委托完成 l={}", self.key(), self.cta.orders.len());
-                    let mut req = CThostFtdcQryTradeField::default();
-                    set_cstr_from_str_truncate_i8(&mut req.BrokerID, broker_id);
-                    set_cstr_from_str_truncate_i8(&mut req.InvestorID, account);
-                    let request_id = self.get_request_id();
-                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                    let result = self.api.req_qry_trade(&mut req, request_id);
-                    if result != 0 {
-                        error!("{} ReqQryTrade = {:?}", self.key(), result);
+                if p.b_is_last {
+                    if !self.login_completed() {
+                        info!("{} 查询委托完成 l={}", self.key(), self.cta.orders.len());
                     }
+                    self.on_query_done(p.n_request_id, &p.p_rsp_info).await;
                 }
             }
             OnRspQryTrade(ref p) => {
                 if let Some(trade) = &p.p_trade {
-                    let trade = TradeRow::from(trade);
-                    if let Some(v) = self.cta.trades.get_mut(&trade.key()) {
-                        *v = trade;
+                    self.cta.apply(GatewayEvent::Trade(TradeRow::from(trade)));
+                }
+                if p.b_is_last {
+                    if !self.login_completed() {
+                        info!(
+                            "{} 查询成交明细完成 l={}",
+                            self.key(),
+                            self.cta.trades.len()
+                        );
+                    }
+                    self.on_query_done(p.n_request_id, &p.p_rsp_info).await;
+                }
+            }
+            OnRspQryParkedOrder(ref p) => {
+                if let Some(o) = &p.p_parked_order {
+                    self.cta
+                        .apply(GatewayEvent::ParkedOrder(ParkedOrderRow::from(o)));
+                }
+                if p.b_is_last {
+                    if !self.login_completed() {
+                        info!(
+                            "{} 查询预埋单完成 l={}",
+                            self.key(),
+                            self.cta.parked_orders.len()
+                        );
+                    }
+                    self.on_query_done(p.n_request_id, &p.p_rsp_info).await;
+                }
+            }
+            OnRspParkedOrderInsert(ref p) => {
+                if let Some(o) = &p.p_parked_order {
+                    let row = ParkedOrderRow::from(o);
+                    let result = match self
+                        .on_error(
+                            "OnRspParkedOrderInsert",
+                            &p.p_rsp_info,
+                            p.n_request_id,
+                            &row.order_ref,
+                        )
+                        .await
+                    {
+                        Some(e) => Err(e.error_msg),
+                        None => {
+                            info!("{} 预埋单已保存 id={}", self.key(), row.id);
+                            Ok(row.id.clone())
+                        }
+                    };
+                    let order_ref = row.order_ref.clone();
+                    let k = if result.is_ok() {
+                        self.cta
+                            .apply(GatewayEvent::ParkedOrder(row))
+                            .unwrap_or_default()
                     } else {
-                        self.cta.trades.insert(trade.key(), trade);
+                        String::new()
+                    };
+                    if let Some(tx) = self.pending_parked.remove(&order_ref) {
+                        let _ = tx.send(result);
                     }
+                    self.send_event(self.make_event("OnRspParkedOrderInsert", &k))
+                        .await;
+                } else {
+                    self.on_error("OnRspParkedOrderInsert", &p.p_rsp_info, p.n_request_id, "")
+                        .await;
                 }
-                if p.b_is_last && !login_completed() {
-                    info!("{} 查询成交明细完成 l={}", self.key(), 0);
-                    self.cta.status = CtaStatus::LoginCompleted;
-                    self.event_sender
-                        .send(self.make_event("LoginCompleted", ""))
+            }
+            OnRspRemoveParkedOrder(ref p) => {
+                if let Some(r) = &p.p_remove_parked_order {
+                    let k = ctp_str(&r.ParkedOrderID).trim().to_string();
+                    let result = match self
+                        .on_error("OnRspRemoveParkedOrder", &p.p_rsp_info, p.n_request_id, &k)
                         .await
-                        .unwrap();
+                    {
+                        Some(e) => Err(Error::OrderActionFailed {
+                            error_id: e.error_id,
+                            msg: e.error_msg,
+                        }),
+                        None => {
+                            if let Some(o) = self.cta.parked_orders.get_mut(&k) {
+                                o.status = THOST_FTDC_PAOS_Deleted as i32;
+                            }
+                            Ok(())
+                        }
+                    };
+                    if let Some(tx) = self.pending_removes.remove(&k) {
+                        let _ = tx.send(result);
+                    }
+                    self.send_event(self.make_event("OnRspRemoveParkedOrder", &k))
+                        .await;
+                } else {
+                    self.on_error("OnRspRemoveParkedOrder", &p.p_rsp_info, p.n_request_id, "")
+                        .await;
+                }
+            }
+            OnRspQryAccountregister(ref p) => {
+                if let Some(r) = &p.p_accountregister {
+                    let mut row = BankAccountRow::from(r);
+                    // 保留已查询的银行余额
+                    if let Some(b) = self.cta.bank_accounts.get(&row.key()) {
+                        row.bank_use_amount = b.bank_use_amount;
+                        row.bank_fetch_amount = b.bank_fetch_amount;
+                    }
+                    let k = self
+                        .cta
+                        .apply(GatewayEvent::BankAccount(row))
+                        .unwrap_or_default();
+                    self.send_event(self.make_event("OnRspQryAccountregister", &k))
+                        .await;
+                }
+                if p.b_is_last {
+                    self.on_query_done(p.n_request_id, &p.p_rsp_info).await;
+                }
+            }
+            OnRspQryTransferSerial(ref p) => {
+                if let Some(t) = &p.p_transfer_serial {
+                    self.cta.apply(GatewayEvent::Transfer(TransferRow::from(t)));
+                }
+                if p.b_is_last {
+                    self.send_event(self.make_event("OnRspQryTransferSerial", ""))
+                        .await;
+                    self.on_query_done(p.n_request_id, &p.p_rsp_info).await;
+                }
+            }
+            OnRspFromBankToFutureByFuture(ref p) => {
+                self.on_transfer_error(
+                    "OnRspFromBankToFutureByFuture",
+                    &p.p_req_transfer,
+                    &p.p_rsp_info,
+                )
+                .await;
+            }
+            OnRspFromFutureToBankByFuture(ref p) => {
+                self.on_transfer_error(
+                    "OnRspFromFutureToBankByFuture",
+                    &p.p_req_transfer,
+                    &p.p_rsp_info,
+                )
+                .await;
+            }
+            OnErrRtnBankToFutureByFuture(ref p) => {
+                self.on_transfer_error(
+                    "OnErrRtnBankToFutureByFuture",
+                    &p.p_req_transfer,
+                    &p.p_rsp_info,
+                )
+                .await;
+            }
+            OnErrRtnFutureToBankByFuture(ref p) => {
+                self.on_transfer_error(
+                    "OnErrRtnFutureToBankByFuture",
+                    &p.p_req_transfer,
+                    &p.p_rsp_info,
+                )
+                .await;
+            }
+            OnRtnFromBankToFutureByFuture(ref p) => {
+                if let Some(t) = &p.p_rsp_transfer {
+                    self.on_transfer_result("OnRtnFromBankToFutureByFuture", t)
+                        .await;
+                }
+            }
+            OnRtnFromFutureToBankByFuture(ref p) => {
+                if let Some(t) = &p.p_rsp_transfer {
+                    self.on_transfer_result("OnRtnFromFutureToBankByFuture", t)
+                        .await;
+                }
+            }
+            OnRspQueryBankAccountMoneyByFuture(ref p) => {
+                self.on_bank_balance_error(
+                    "OnRspQueryBankAccountMoneyByFuture",
+                    &p.p_req_query_account,
+                    &p.p_rsp_info,
+                )
+                .await;
+            }
+            OnErrRtnQueryBankBalanceByFuture(ref p) => {
+                self.on_bank_balance_error(
+                    "OnErrRtnQueryBankBalanceByFuture",
+                    &p.p_req_query_account,
+                    &p.p_rsp_info,
+                )
+                .await;
+            }
+            OnRtnQueryBankBalanceByFuture(ref p) => {
+                if let Some(n) = &p.p_notify_query_account {
+                    let bank_id = ctp_str(&n.BankID);
+                    let msg = gb18030_cstr_to_str_i8(&n.ErrorMsg).to_string();
+                    let tp = if n.ErrorID != 0 {
+                        warn!(
+                            "{} 查询银行余额失败 bank_id={} ErrorID={} ErrorMsg={}",
+                            self.key(),
+                            bank_id,
+                            n.ErrorID,
+                            msg
+                        );
+                        "BankBalanceFailed"
+                    } else {
+                        info!(
+                            "{} 银行余额 bank_id={} use={} fetch={}",
+                            self.key(),
+                            bank_id,
+                            n.BankUseAmount,
+                            n.BankFetchAmount
+                        );
+                        "OnRtnQueryBankBalanceByFuture"
+                    };
+                    if let Some(b) = self.cta.bank_accounts.get_mut(&bank_id) {
+                        if n.ErrorID == 0 {
+                            b.bank_use_amount = n.BankUseAmount;
+                            b.bank_fetch_amount = n.BankFetchAmount;
+                        }
+                        b.status_description = msg;
+                    }
+                    self.send_event(self.make_event(tp, &bank_id)).await;
                 }
             }
             OnRspQryInstrumentCommissionRate(ref p) => {
-                // 未处理
-                if p.p_instrument_commission_rate.is_some() {
-                    let _cr = p.p_instrument_commission_rate.unwrap();
+                if let Some(cr) = &p.p_instrument_commission_rate {
+                    let cr = CommissionRateRow::from(cr);
+                    info!("{} 查询手续费率完成 {:?}", self.key(), cr);
+                    let k = self
+                        .cta
+                        .apply(GatewayEvent::CommissionRate(cr))
+                        .unwrap_or_default();
+                    // 费率返回前收到的成交重新计算手续费
+                    let trades = self
+                        .cta
+                        .trades
+                        .values()
+                        .filter(|t| t.commission == 0.0)
+                        .map(|t| (t.key(), self.trade_commission(t)))
+                        .collect::<Vec<_>>();
+                    for (tk, commission) in trades {
+                        if let Some(t) = self.cta.trades.get_mut(&tk) {
+                            t.commission = commission;
+                        }
+                    }
+                    self.send_event(self.make_event("OnRspQryInstrumentCommissionRate", &k))
+                        .await;
+                }
+                if p.b_is_last {
+                    self.on_query_done(p.n_request_id, &p.p_rsp_info).await;
+                }
+            }
+            OnRspQryInstrumentMarginRate(ref p) => {
+                if let Some(mr) = &p.p_instrument_margin_rate {
+                    let mr = MarginRateRow::from(mr);
+                    info!("{} 查询保证金率完成 {:?}", self.key(), mr);
+                    let k = self
+                        .cta
+                        .apply(GatewayEvent::MarginRate(mr))
+                        .unwrap_or_default();
+                    self.send_event(self.make_event("OnRspQryInstrumentMarginRate", &k))
+                        .await;
+                }
+                if p.b_is_last {
+                    self.on_query_done(p.n_request_id, &p.p_rsp_info).await;
+                }
+            }
+            OnRspOrderInsert(ref p) => {
+                self.on_order_insert_error("OnRspOrderInsert", &p.p_input_order, &p.p_rsp_info)
+                    .await;
+            }
+            OnErrRtnOrderInsert(ref p) => {
+                self.on_order_insert_error("OnErrRtnOrderInsert", &p.p_input_order, &p.p_rsp_info)
+                    .await;
+            }
+            OnRspOrderAction(ref p) => {
+                if let Some(a) = &p.p_input_order_action {
+                    let k = format!("{}:{}:{}", a.FrontID, a.SessionID, ctp_str(&a.OrderRef));
+                    self.on_order_action_error("OnRspOrderAction", k, &p.p_rsp_info)
+                        .await;
+                }
+            }
+            OnErrRtnOrderAction(ref p) => {
+                if let Some(a) = &p.p_order_action {
+                    let k = format!("{}:{}:{}", a.FrontID, a.SessionID, ctp_str(&a.OrderRef));
+                    self.on_order_action_error("OnErrRtnOrderAction", k, &p.p_rsp_info)
+                        .await;
+                }
+            }
+            OnRspError(ref p) => {
+                // 查询请求的错误通过OnRspError返回时, 结束排队的查询
+                if !self.on_query_done(p.n_request_id, &p.p_rsp_info).await {
+                    self.on_error("OnRspError", &p.p_rsp_info, p.n_request_id, "")
+                        .await;
+                }
+            }
+            OnErrRtnExecOrderInsert(ref p) => {
+                self.on_error("OnErrRtnExecOrderInsert", &p.p_rsp_info, 0, "")
+                    .await;
+            }
+            OnErrRtnExecOrderAction(ref p) => {
+                self.on_error("OnErrRtnExecOrderAction", &p.p_rsp_info, 0, "")
+                    .await;
+            }
+            OnErrRtnForQuoteInsert(ref p) => {
+                self.on_error("OnErrRtnForQuoteInsert", &p.p_rsp_info, 0, "")
+                    .await;
+            }
+            OnErrRtnQuoteInsert(ref p) => {
+                self.on_error("OnErrRtnQuoteInsert", &p.p_rsp_info, 0, "")
+                    .await;
+            }
+            OnErrRtnQuoteAction(ref p) => {
+                self.on_error("OnErrRtnQuoteAction", &p.p_rsp_info, 0, "")
+                    .await;
+            }
+            OnErrRtnBatchOrderAction(ref p) => {
+                self.on_error("OnErrRtnBatchOrderAction", &p.p_rsp_info, 0, "")
+                    .await;
+            }
+            OnErrRtnOptionSelfCloseInsert(ref p) => {
+                self.on_error("OnErrRtnOptionSelfCloseInsert", &p.p_rsp_info, 0, "")
+                    .await;
+            }
+            OnErrRtnOptionSelfCloseAction(ref p) => {
+                self.on_error("OnErrRtnOptionSelfCloseAction", &p.p_rsp_info, 0, "")
+                    .await;
+            }
+            OnErrRtnCombActionInsert(ref p) => {
+                self.on_error("OnErrRtnCombActionInsert", &p.p_rsp_info, 0, "")
+                    .await;
+            }
+            OnRtnInstrumentStatus(ref p) => {
+                if let Some(status) = &p.p_instrument_status {
+                    let row = InstrumentStatusRow::from(status);
+                    let was_continuous = self
+                        .cta
+                        .instrument_status
+                        .get(&row.key())
+                        .map_or(false, |r| r.is_continuous());
+                    info!(
+                        "{} 交易状态 {} {} enter_time={}",
+                        self.key(),
+                        row.key(),
+                        row.status_name(),
+                        row.enter_time
+                    );
+                    // 进入或离开连续交易, 用于K线切分等按交易时段处理的功能
+                    let session = match (was_continuous, row.is_continuous()) {
+                        (false, true) => Some("SessionOpen"),
+                        (true, false) => Some("SessionClose"),
+                        _ => None,
+                    };
+                    let k = self
+                        .cta
+                        .apply(GatewayEvent::InstrumentStatus(row))
+                        .unwrap_or_default();
+                    self.send_event(self.make_event("OnRtnInstrumentStatus", &k))
+                        .await;
+                    if let Some(tp) = session {
+                        self.send_event(self.make_event(tp, &k)).await;
+                    }
                 }
-                if p.b_is_last && !login_completed() {}
             }
             OnRtnOrder(ref p) => {
                 if let Some(order) = &p.p_order {
                     let o = OrderRow::from(order);
                     let k = o.key();
-                    if let Some(p) = self.cta.orders.get_mut(&k) {
-                        *p = o;
+                    self.ensure_rates(&o.exchange, &o.symbol);
+                    // 其他终端或其他会话的报单不会有等待中的请求
+                    let pending = if self.cta.is_own_order(&o) {
+                        self.pending_orders.remove(&k)
                     } else {
-                        self.cta.orders.insert(k.clone(), o);
+                        None
+                    };
+                    if let Some(tx) = pending {
+                        if order.OrderSubmitStatus == THOST_FTDC_OSS_InsertRejected as i8 {
+                            let _ = tx.send(Err(o.status_description.clone()));
+                        } else {
+                            let _ = tx.send(Ok(()));
+                        }
                     }
-                    self.event_sender
-                        .send(self.make_event("OnRtnOrder", &k))
-                        .await
-                        .unwrap();
+                    if order.OrderStatus == THOST_FTDC_OST_Canceled as i8 {
+                        if let Some(tx) = self.pending_actions.remove(&k) {
+                            let _ = tx.send(Ok(()));
+                        }
+                    }
+                    // 重传的旧回报不覆盖查询得到的最新状态
+                    if self.cta.apply(GatewayEvent::Order(o)).is_none() {
+                        return;
+                    }
+                    if order.ContingentCondition != THOST_FTDC_CC_Immediately as i8 {
+                        self.cta
+                            .apply(GatewayEvent::ParkedOrder(ParkedOrderRow::from(order)));
+                    }
+                    self.cta.update_frozen();
+                    self.send_event(self.make_event("OnRtnOrder", &k)).await;
                 }
             }
             OnRtnTrade(ref p) => {
                 if let Some(trade) = &p.p_trade {
-                    let trade = TradeRow::from(trade);
-                    let k = trade.key();
-                    if let Some(v) = self.cta.trades.get_mut(&k) {
-                        *v = trade;
-                    } else {
-                        self.cta.trades.insert(trade.key(), trade);
+                    let mut trade = TradeRow::from(trade);
+                    self.ensure_rates(&trade.exchange, &trade.symbol);
+                    trade.commission = self.trade_commission(&trade);
+                    // 重连后私有流会重传已收到的成交, 只处理第一次
+                    let k = match self.cta.apply(GatewayEvent::Trade(trade.clone())) {
+                        Some(k) => k,
+                        None => {
+                            info!("{} 忽略重复的成交回报", self.key());
+                            return;
+                        }
+                    };
+                    self.send_event(self.make_event("OnRtnTrade", &k)).await;
+                    // 登录查询中的成交已包含在持仓查询结果里
+                    if self.login_completed() {
+                        let multiple = self.volume_multiple(&trade.exchange, &trade.symbol);
+                        self.cta.apply_trade(&trade, multiple);
+                        self.cta.update_frozen();
+                        self.cta.revalue_all();
+                        let pk = format!(
+                            "{}:{}:{}",
+                            trade.exchange,
+                            trade.symbol,
+                            posi_direction(trade.direction, trade.offset)
+                        );
+                        self.send_event(self.make_event("Position", &pk)).await;
                     }
-                    self.event_sender
-                        .send(self.make_event("OnRtnTrade", &k))
-                        .await
-                        .unwrap();
                 }
             }
             _ => {}
         }
     }
 }
+
+fn password_update_result(rsp_info: &Option<CThostFtdcRspInfoField>) -> Result<(), Error> {
+    match rsp_info {
+        Some(r) if r.ErrorID != 0 => Err(Error::PasswordUpdateFailed {
+            error_id: r.ErrorID,
+            msg: gb18030_cstr_to_str_i8(&r.ErrorMsg).to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+impl Gateway for Trader {
+    fn conf(&self) -> &TradingAccount {
+        &self.conf
+    }
+
+    fn book(&self) -> &AccountBook {
+        &self.cta
+    }
+
+    fn connect(&mut self) -> Result<(), Error> {
+        info!("{} connect", self.key());
+        self.api.init();
+        Ok(())
+    }
+
+    fn login(&mut self) -> Result<(), Error> {
+        match self.cta.status {
+            CtaStatus::UnKown | CtaStatus::Disconnected => Err(Error::Disconnected),
+            _ => {
+                self.retry_at = None;
+                self.cta.login_failures = 0;
+                self.req_authenticate();
+                Ok(())
+            }
+        }
+    }
+
+    fn query(&mut self, q: GatewayQuery) -> Result<Vec<QueryReply>, Error> {
+        if !self.login_completed() {
+            return Err(Error::NotLoggedIn);
+        }
+        let queries = match q {
+            GatewayQuery::Account => vec![Query::TradingAccount],
+            GatewayQuery::Position => vec![Query::PositionDetail, Query::Position],
+            GatewayQuery::Order => vec![Query::Order],
+            GatewayQuery::Trade => vec![Query::Trade],
+            GatewayQuery::ParkedOrder => vec![Query::Order, Query::ParkedOrder],
+            GatewayQuery::Settlement(day) => vec![Query::SettlementInfo(day)],
+            GatewayQuery::BankAccount => vec![Query::AccountRegister],
+            GatewayQuery::Transfer => vec![Query::TransferSerial],
+            GatewayQuery::DepthMarketData(keys) => {
+                keys.into_iter().map(Query::DepthMarketData).collect()
+            }
+        };
+        Ok(queries
+            .into_iter()
+            .filter_map(|q| self.schedule_query(q))
+            .collect())
+    }
+
+    fn insert_order(
+        &mut self,
+        input: &OrderInput,
+    ) -> Result<(String, oneshot::Receiver<Result<(), String>>), Error> {
+        if self.cta.status != CtaStatus::LoginCompleted {
+            return Err(Error::NotLoggedIn);
+        }
+        if !self.cta.settlement_confirmed {
+            return Err(Error::SettlementNotConfirmed);
+        }
+        if input.symbol.len() == 0 {
+            return Err(Error::InvalidOrder("合约不能为空".into()));
+        }
+        if input.volume <= 0 {
+            return Err(Error::InvalidOrder("数量必须大于0".into()));
+        }
+        if let Some(status) = self.cta.trading_status(&input.exchange, &input.symbol) {
+            if !status.accepts_orders() {
+                if self.conf.block_non_trading {
+                    return Err(Error::NotTrading(format!(
+                        "{} {}",
+                        input.symbol,
+                        status.status_name()
+                    )));
+                }
+                warn!(
+                    "{} {} 当前{}, 报单可能被拒绝",
+                    self.key(),
+                    input.symbol,
+                    status.status_name()
+                );
+            }
+        }
+        let (price_type, time_condition, volume_condition) = match input.order_type {
+            OrderType::Limit => (
+                THOST_FTDC_OPT_LimitPrice,
+                THOST_FTDC_TC_GFD,
+                THOST_FTDC_VC_AV,
+            ),
+            OrderType::Market => (THOST_FTDC_OPT_AnyPrice, THOST_FTDC_TC_IOC, THOST_FTDC_VC_AV),
+            OrderType::Fak => (
+                THOST_FTDC_OPT_LimitPrice,
+                THOST_FTDC_TC_IOC,
+                match input.volume_condition {
+                    VolumeCondition::Any => THOST_FTDC_VC_AV,
+                    VolumeCondition::Min => THOST_FTDC_VC_MV,
+                    VolumeCondition::All => THOST_FTDC_VC_CV,
+                },
+            ),
+            OrderType::Fok => (
+                THOST_FTDC_OPT_LimitPrice,
+                THOST_FTDC_TC_IOC,
+                THOST_FTDC_VC_CV,
+            ),
+        };
+        if volume_condition == THOST_FTDC_VC_MV && input.min_volume <= 0 {
+            return Err(Error::InvalidOrder("最小成交量必须大于0".into()));
+        }
+        if input.is_conditional() && input.stop_price <= 0.0 {
+            return Err(Error::InvalidOrder("条件单触发价必须大于0".into()));
+        }
+        let order_ref = self.next_order_ref();
+        let mut req = CThostFtdcInputOrderField::default();
+        set_cstr_from_str_truncate_i8(&mut req.BrokerID, &self.conf.broker_id);
+        set_cstr_from_str_truncate_i8(&mut req.InvestorID, &self.conf.account);
+        set_cstr_from_str_truncate_i8(&mut req.UserID, &self.conf.account);
+        set_cstr_from_str_truncate_i8(&mut req.ExchangeID, &input.exchange);
+        set_cstr_from_str_truncate_i8(&mut req.InstrumentID, &input.symbol);
+        set_cstr_from_str_truncate_i8(&mut req.OrderRef, &order_ref);
+        req.OrderPriceType = price_type as i8;
+        req.Direction = input.direction as i8;
+        req.CombOffsetFlag[0] = input.offset as i8;
+        req.CombHedgeFlag[0] = THOST_FTDC_HF_Speculation as i8;
+        req.LimitPrice = if input.order_type == OrderType::Market {
+            0.0
+        } else {
+            input.price
+        };
+        req.VolumeTotalOriginal = input.volume;
+        req.TimeCondition = time_condition as i8;
+        req.VolumeCondition = volume_condition as i8;
+        req.MinVolume = if volume_condition == THOST_FTDC_VC_MV {
+            input.min_volume
+        } else {
+            1
+        };
+        if input.is_conditional() {
+            req.ContingentCondition = input.contingent_condition as i8;
+            req.StopPrice = input.stop_price;
+        } else {
+            req.ContingentCondition = THOST_FTDC_CC_Immediately as i8;
+        }
+        req.ForceCloseReason = THOST_FTDC_FCC_NotForceClose as i8;
+        let request_id = self.get_request_id();
+        req.RequestID = request_id;
+        let result = self.api.req_order_insert(&mut req, request_id);
+        if result != 0 {
+            error!("{} ReqOrderInsert={}", self.key(), result);
+            return Err(Error::RequestFailed(result));
+        }
+        let key = self.order_key(&order_ref);
+        info!("{} ReqOrderInsert key={} {:?}", self.key(), key, input);
+        let (tx, rx) = oneshot::channel();
+        self.pending_orders.insert(key.clone(), tx);
+        Ok((key, rx))
+    }
+
+    fn cancel_order(&mut self, key: &str) -> Result<oneshot::Receiver<Result<(), Error>>, Error> {
+        if self.cta.status != CtaStatus::LoginCompleted {
+            return Err(Error::NotLoggedIn);
+        }
+        let o = self
+            .cta
+            .orders
+            .get(key)
+            .ok_or(Error::OrderNotFound(key.to_string()))?;
+        if !o.is_active() {
+            return Err(Error::OrderNotActive(key.to_string()));
+        }
+        let mut req = CThostFtdcInputOrderActionField::default();
+        set_cstr_from_str_truncate_i8(&mut req.BrokerID, &self.conf.broker_id);
+        set_cstr_from_str_truncate_i8(&mut req.InvestorID, &self.conf.account);
+        set_cstr_from_str_truncate_i8(&mut req.UserID, &self.conf.account);
+        set_cstr_from_str_truncate_i8(&mut req.ExchangeID, &o.exchange);
+        set_cstr_from_str_truncate_i8(&mut req.InstrumentID, &o.symbol);
+        set_cstr_from_str_truncate_i8(&mut req.OrderRef, &o.order_ref);
+        set_cstr_from_str_truncate_i8(&mut req.OrderSysID, &o.order_sys_id);
+        req.FrontID = o.front_id;
+        req.SessionID = o.session_id;
+        req.ActionFlag = THOST_FTDC_AF_Delete as i8;
+        let request_id = self.get_request_id();
+        req.RequestID = request_id;
+        let result = self.api.req_order_action(&mut req, request_id);
+        if result != 0 {
+            error!("{} ReqOrderAction={} key={}", self.key(), result, key);
+            return Err(Error::RequestFailed(result));
+        }
+        info!("{} ReqOrderAction key={}", self.key(), key);
+        let (tx, rx) = oneshot::channel();
+        self.pending_actions.insert(key.to_string(), tx);
+        Ok(rx)
+    }
+
+    fn insert_parked_order(
+        &mut self,
+        input: &OrderInput,
+    ) -> Result<oneshot::Receiver<Result<String, String>>, Error> {
+        if self.cta.status != CtaStatus::LoginCompleted {
+            return Err(Error::NotLoggedIn);
+        }
+        if !self.cta.settlement_confirmed {
+            return Err(Error::SettlementNotConfirmed);
+        }
+        if input.symbol.len() == 0 {
+            return Err(Error::InvalidOrder("合约不能为空".into()));
+        }
+        if input.volume <= 0 {
+            return Err(Error::InvalidOrder("数量必须大于0".into()));
+        }
+        if input.order_type != OrderType::Limit {
+            return Err(Error::InvalidOrder("预埋单只支持限价单".into()));
+        }
+        if input.is_conditional() && input.stop_price <= 0.0 {
+            return Err(Error::InvalidOrder("条件单触发价必须大于0".into()));
+        }
+        let order_ref = self.next_order_ref();
+        let mut req = CThostFtdcParkedOrderField::default();
+        set_cstr_from_str_truncate_i8(&mut req.BrokerID, &self.conf.broker_id);
+        set_cstr_from_str_truncate_i8(&mut req.InvestorID, &self.conf.account);
+        set_cstr_from_str_truncate_i8(&mut req.UserID, &self.conf.account);
+        set_cstr_from_str_truncate_i8(&mut req.ExchangeID, &input.exchange);
+        set_cstr_from_str_truncate_i8(&mut req.InstrumentID, &input.symbol);
+        set_cstr_from_str_truncate_i8(&mut req.OrderRef, &order_ref);
+        req.OrderPriceType = THOST_FTDC_OPT_LimitPrice as i8;
+        req.Direction = input.direction as i8;
+        req.CombOffsetFlag[0] = input.offset as i8;
+        req.CombHedgeFlag[0] = THOST_FTDC_HF_Speculation as i8;
+        req.LimitPrice = input.price;
+        req.VolumeTotalOriginal = input.volume;
+        req.TimeCondition = THOST_FTDC_TC_GFD as i8;
+        req.VolumeCondition = THOST_FTDC_VC_AV as i8;
+        req.MinVolume = 1;
+        if input.is_conditional() {
+            req.ContingentCondition = input.contingent_condition as i8;
+            req.StopPrice = input.stop_price;
+        } else {
+            req.ContingentCondition = THOST_FTDC_CC_Immediately as i8;
+        }
+        req.ForceCloseReason = THOST_FTDC_FCC_NotForceClose as i8;
+        let request_id = self.get_request_id();
+        req.RequestID = request_id;
+        let result = self.api.req_parked_order_insert(&mut req, request_id);
+        if result != 0 {
+            error!("{} ReqParkedOrderInsert={}", self.key(), result);
+            return Err(Error::RequestFailed(result));
+        }
+        info!(
+            "{} ReqParkedOrderInsert order_ref={} {:?}",
+            self.key(),
+            order_ref,
+            input
+        );
+        let (tx, rx) = oneshot::channel();
+        self.pending_parked.insert(order_ref, tx);
+        Ok(rx)
+    }
+
+    fn remove_parked_order(
+        &mut self,
+        key: &str,
+    ) -> Result<oneshot::Receiver<Result<(), Error>>, Error> {
+        if self.cta.status != CtaStatus::LoginCompleted {
+            return Err(Error::NotLoggedIn);
+        }
+        let o = self
+            .cta
+            .parked_orders
+            .get(key)
+            .ok_or(Error::OrderNotFound(key.to_string()))?;
+        if !o.is_active() {
+            return Err(Error::OrderNotActive(key.to_string()));
+        }
+        // 条件单是交易所前的本地报单, 通过撤单删除
+        if o.conditional {
+            return self.cancel_order(key);
+        }
+        let mut req = CThostFtdcRemoveParkedOrderField::default();
+        set_cstr_from_str_truncate_i8(&mut req.BrokerID, &self.conf.broker_id);
+        set_cstr_from_str_truncate_i8(&mut req.InvestorID, &self.conf.account);
+        set_cstr_from_str_truncate_i8(&mut req.ParkedOrderID, key);
+        let request_id = self.get_request_id();
+        let result = self.api.req_remove_parked_order(&mut req, request_id);
+        if result != 0 {
+            error!("{} ReqRemoveParkedOrder={} id={}", self.key(), result, key);
+            return Err(Error::RequestFailed(result));
+        }
+        info!("{} ReqRemoveParkedOrder id={}", self.key(), key);
+        let (tx, rx) = oneshot::channel();
+        self.pending_removes.insert(key.to_string(), tx);
+        Ok(rx)
+    }
+
+    fn confirm_settlement(&mut self) -> Result<oneshot::Receiver<Result<(), Error>>, Error> {
+        if self.cta.status != CtaStatus::LoginCompleted {
+            return Err(Error::NotLoggedIn);
+        }
+        let result = self.req_settlement_info_confirm();
+        if result != 0 {
+            return Err(Error::RequestFailed(result));
+        }
+        info!("{} 手动确认结算单", self.key());
+        let (tx, rx) = oneshot::channel();
+        self.pending_confirm = Some(tx);
+        Ok(rx)
+    }
+
+    fn update_password(
+        &mut self,
+        old_password: &str,
+        new_password: &str,
+    ) -> Result<oneshot::Receiver<Result<(), Error>>, Error> {
+        // 认证通过后即可修改, 不要求登录成功
+        if !matches!(
+            self.cta.status,
+            CtaStatus::AuthenticateSucceeded
+                | CtaStatus::LoginFailed
+                | CtaStatus::LoginSucceeded
+                | CtaStatus::LoginCompleted
+        ) {
+            return Err(Error::NotLoggedIn);
+        }
+        let mut req = CThostFtdcUserPasswordUpdateField::default();
+        set_cstr_from_str_truncate_i8(&mut req.BrokerID, &self.conf.broker_id);
+        set_cstr_from_str_truncate_i8(&mut req.UserID, &self.conf.account);
+        set_cstr_from_str_truncate_i8(&mut req.OldPassword, old_password);
+        set_cstr_from_str_truncate_i8(&mut req.NewPassword, new_password);
+        let request_id = self.get_request_id();
+        let result = self.api.req_user_password_update(&mut req, request_id);
+        if result != 0 {
+            error!("{} 修改交易密码请求失败 result={}", self.key(), result);
+            return Err(Error::RequestFailed(result));
+        }
+        info!("{} 修改交易密码", self.key());
+        let (tx, rx) = oneshot::channel();
+        self.pending_password = Some((new_password.to_string(), tx));
+        Ok(rx)
+    }
+
+    fn update_money_password(
+        &mut self,
+        old_password: &str,
+        new_password: &str,
+    ) -> Result<oneshot::Receiver<Result<(), Error>>, Error> {
+        if self.cta.status != CtaStatus::LoginCompleted {
+            return Err(Error::NotLoggedIn);
+        }
+        let mut req = CThostFtdcTradingAccountPasswordUpdateField::default();
+        set_cstr_from_str_truncate_i8(&mut req.BrokerID, &self.conf.broker_id);
+        set_cstr_from_str_truncate_i8(&mut req.AccountID, &self.conf.account);
+        set_cstr_from_str_truncate_i8(&mut req.OldPassword, old_password);
+        set_cstr_from_str_truncate_i8(&mut req.NewPassword, new_password);
+        set_cstr_from_str_truncate_i8(&mut req.CurrencyID, self.conf.currency());
+        let request_id = self.get_request_id();
+        let result = self
+            .api
+            .req_trading_account_password_update(&mut req, request_id);
+        if result != 0 {
+            error!("{} 修改资金密码请求失败 result={}", self.key(), result);
+            return Err(Error::RequestFailed(result));
+        }
+        info!("{} 修改资金密码", self.key());
+        let (tx, rx) = oneshot::channel();
+        self.pending_money_password = Some((new_password.to_string(), tx));
+        Ok(rx)
+    }
+
+    fn transfer(&mut self, input: &TransferInput) -> Result<String, Error> {
+        let bank = self.bank_account(&input.bank_id)?;
+        if input.amount <= 0.0 {
+            return Err(Error::InvalidTransfer("金额必须大于0".into()));
+        }
+        let mut req = CThostFtdcReqTransferField::default();
+        set_cstr_from_str_truncate_i8(&mut req.TradeCode, input.direction.trade_code());
+        set_cstr_from_str_truncate_i8(&mut req.BankID, &bank.bank_id);
+        set_cstr_from_str_truncate_i8(&mut req.BankBranchID, &bank.bank_branch_id);
+        set_cstr_from_str_truncate_i8(&mut req.BrokerID, &self.conf.broker_id);
+        set_cstr_from_str_truncate_i8(&mut req.BankAccount, &bank.bank_account);
+        set_cstr_from_str_truncate_i8(&mut req.BankPassWord, &input.bank_password);
+        set_cstr_from_str_truncate_i8(&mut req.AccountID, &self.conf.account);
+        set_cstr_from_str_truncate_i8(&mut req.Password, &self.broker.money_password);
+        set_cstr_from_str_truncate_i8(&mut req.CurrencyID, self.bank_currency(&bank));
+        req.TradeAmount = input.amount;
+        req.SecuPwdFlag = THOST_FTDC_BPWDF_BlankCheck as i8;
+        let request_id = self.get_request_id();
+        req.RequestID = request_id;
+        let result = match input.direction {
+            TransferDirection::BankToFuture => self
+                .api
+                .req_from_bank_to_future_by_future(&mut req, request_id),
+            TransferDirection::FutureToBank => self
+                .api
+                .req_from_future_to_bank_by_future(&mut req, request_id),
+        };
+        if result != 0 {
+            error!("{} 银期转账请求失败 result={}", self.key(), result);
+            return Err(Error::RequestFailed(result));
+        }
+        info!(
+            "{} 银期转账 {:?} bank_id={} amount={}",
+            self.key(),
+            input.direction,
+            input.bank_id,
+            input.amount
+        );
+        let row = TransferRow {
+            broker_id: self.conf.broker_id.clone(),
+            account: self.conf.account.clone(),
+            bank_id: bank.bank_id,
+            bank_account: bank.bank_account,
+            direction: input.direction.clone(),
+            amount: input.amount,
+            request_id,
+            ..Default::default()
+        };
+        Ok(self
+            .cta
+            .apply(GatewayEvent::Transfer(row))
+            .unwrap_or_default())
+    }
+
+    fn query_bank_balance(&mut self, bank_id: &str, bank_password: &str) -> Result<(), Error> {
+        let bank = self.bank_account(bank_id)?;
+        let mut req = CThostFtdcReqQueryAccountField::default();
+        set_cstr_from_str_truncate_i8(&mut req.TradeCode, "204002");
+        set_cstr_from_str_truncate_i8(&mut req.BankID, &bank.bank_id);
+        set_cstr_from_str_truncate_i8(&mut req.BankBranchID, &bank.bank_branch_id);
+        set_cstr_from_str_truncate_i8(&mut req.BrokerID, &self.conf.broker_id);
+        set_cstr_from_str_truncate_i8(&mut req.BankAccount, &bank.bank_account);
+        set_cstr_from_str_truncate_i8(&mut req.BankPassWord, bank_password);
+        set_cstr_from_str_truncate_i8(&mut req.AccountID, &self.conf.account);
+        set_cstr_from_str_truncate_i8(&mut req.Password, &self.broker.money_password);
+        set_cstr_from_str_truncate_i8(&mut req.CurrencyID, self.bank_currency(&bank));
+        req.SecuPwdFlag = THOST_FTDC_BPWDF_BlankCheck as i8;
+        let request_id = self.get_request_id();
+        req.RequestID = request_id;
+        let result = self
+            .api
+            .req_query_bank_account_money_by_future(&mut req, request_id);
+        if result != 0 {
+            error!("{} 查询银行余额请求失败 result={}", self.key(), result);
+            return Err(Error::RequestFailed(result));
+        }
+        info!("{} 查询银行余额 bank_id={}", self.key(), bank_id);
+        Ok(())
+    }
+
+    fn estimate_order_cost(&mut self, input: &OrderInput) -> Result<OrderCost, Error> {
+        self.ensure_rates(&input.exchange, &input.symbol);
+        let k = format!("{}:{}", input.exchange, input.symbol);
+        let price = if input.order_type == OrderType::Market {
+            self.cta
+                .depth_snapshots
+                .get(&k)
+                .map_or(0.0, |md| md.valuation_price())
+        } else {
+            input.price
+        };
+        let multiple = self.volume_multiple(&input.exchange, &input.symbol);
+        let commission = self
+            .commission_rate(&input.exchange, &input.symbol)
+            .ok_or(Error::RateNotReady(k.clone()))?
+            .commission(input.offset, price, input.volume, multiple);
+        // 平仓不占用保证金
+        let margin = if input.offset == THOST_FTDC_OF_Open as i32 {
+            self.margin_rate(&input.exchange, &input.symbol)
+                .ok_or(Error::RateNotReady(k))?
+                .margin(input.direction, price, input.volume, multiple)
+        } else {
+            0.0
+        };
+        Ok(OrderCost { margin, commission })
+    }
+
+    fn reconnect(&mut self, conf: TradingAccount, rebuild: bool) -> Result<(), Error> {
+        if self.shutting_down {
+            return Err(Error::Disconnected);
+        }
+        let front = self
+            .broker
+            .fronts
+            .iter()
+            .find(|fg| fg.id == conf.front_group)
+            .filter(|fg| !fg.trade_front.is_empty() || !fg.fens_trade_front.is_empty())
+            .cloned()
+            .ok_or(Error::FrontGroupNotFound)?;
+        // 同一前置组保持当前的连接方式, 其它前置组优先使用FENS
+        let fens = if front.id == self.conf.front_group {
+            self.fens
+        } else {
+            !front.fens_trade_front.is_empty()
+        };
+        self.pending_reconnect = Some(Reconnect {
+            conf,
+            front,
+            fens,
+            rebuild,
+        });
+        Ok(())
+    }
+
+    fn exit(&mut self) -> Option<oneshot::Receiver<()>> {
+        self.shutting_down = true;
+        if let Some(sender) = self.exit_sender.take() {
+            let _ = sender.send("exit".to_string());
+        }
+        self.exit_done.take()
+    }
+
+    fn on_market_data(&mut self, md: &MarketDataRow, _instrument: Option<&InstrumentRow>) {
+        self.cta.revalue(md);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn neutral_constants_match_ctp() {
+        assert_eq!(DIRECTION_BUY, THOST_FTDC_D_Buy as i32);
+        assert_eq!(DIRECTION_SELL, THOST_FTDC_D_Sell as i32);
+        assert_eq!(OFFSET_OPEN, THOST_FTDC_OF_Open as i32);
+        assert_eq!(OFFSET_CLOSE, THOST_FTDC_OF_Close as i32);
+        assert_eq!(OFFSET_CLOSE_TODAY, THOST_FTDC_OF_CloseToday as i32);
+        assert_eq!(OFFSET_CLOSE_YESTERDAY, THOST_FTDC_OF_CloseYesterday as i32);
+        assert_eq!(POSI_LONG, THOST_FTDC_PD_Long as i32);
+        assert_eq!(POSI_SHORT, THOST_FTDC_PD_Short as i32);
+        assert_eq!(ORDER_ALL_TRADED, THOST_FTDC_OST_AllTraded as i32);
+        assert_eq!(
+            ORDER_PART_TRADED_QUEUEING,
+            THOST_FTDC_OST_PartTradedQueueing as i32
+        );
+        assert_eq!(
+            ORDER_NO_TRADE_QUEUEING,
+            THOST_FTDC_OST_NoTradeQueueing as i32
+        );
+        assert_eq!(ORDER_CANCELED, THOST_FTDC_OST_Canceled as i32);
+        assert_eq!(ORDER_NOT_TOUCHED, THOST_FTDC_OST_NotTouched as i32);
+        assert_eq!(ORDER_TOUCHED, THOST_FTDC_OST_Touched as i32);
+        assert_eq!(PARKED_NOT_SEND, THOST_FTDC_PAOS_NotSend as i32);
+        assert_eq!(PARKED_SEND, THOST_FTDC_PAOS_Send as i32);
+        assert_eq!(PARKED_DELETED, THOST_FTDC_PAOS_Deleted as i32);
+    }
+
+    #[test]
+    fn retry_keeps_query_at_front_and_waits_interval() {
+        let mut scheduler = QueryScheduler::new(2);
+        let mut rx = scheduler.push(Query::TradingAccount, false);
+        let _ = scheduler.push(Query::Position, false);
+        let now = tokio::time::Instant::now();
+        let qq = scheduler.next_ready(now).unwrap();
+        assert_eq!(qq.query, Query::TradingAccount);
+
+        // 流控拒绝后放回队首, 结果仍在等待
+        scheduler.retry(qq, now);
+        assert!(rx.try_recv().is_err());
+        assert!(scheduler.contains(&Query::TradingAccount));
+        assert!(scheduler.next_ready(now).is_none());
+
+        let later = now + scheduler.interval;
+        let qq = scheduler.next_ready(later).unwrap();
+        assert_eq!(qq.query, Query::TradingAccount);
+        scheduler.sent(11, qq, later);
+        assert_eq!(scheduler.complete(11, Ok(())), Some(Query::TradingAccount));
+        assert!(matches!(rx.try_recv(), Ok(Ok(()))));
+        let qq = scheduler.next_ready(later + scheduler.interval).unwrap();
+        assert_eq!(qq.query, Query::Position);
+    }
+}
@@ -17,6 +17,7 @@ import PositionDetail from './routes/position_detail';
 import Position from './routes/position';
 import Trade from './routes/trade';
 import Broker from './routes/broker';
+import Settlement from './routes/settlement';
 
 import { invoke } from '@tauri-apps/api/tauri';
 import { FloatButton, Modal } from 'antd';
@@ -63,6 +64,10 @@ const router = createBrowserRouter([
         path: "broker",
         element: <Broker></Broker>,
       },
+      {
+        path: "settlement",
+        element: <Settlement></Settlement>,
+      },
     ],
   },
 ]);
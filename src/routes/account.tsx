@@ -56,6 +56,15 @@ const AccountCard = (props: any) => {
 			<Button type="link" onClick={async () => {
 				props.handleEdit();
 			}}>修改</Button>
+			<Button type="link" onClick={async () => {
+				props.handlePassword();
+			}}>改密</Button>
+			<Button type="link" onClick={async () => {
+				const yes = await ask('重新连接并重建当日报单和成交?', '重建');
+				if (yes) {
+					props.handleRebuild();
+				}
+			}}>重建</Button>
 			<Button type="link" onClick={async () => {
 				const yes = await ask('确认删除账户?', '删除');
 				if (yes) {
@@ -72,6 +81,13 @@ export default () => {
 	const [brokerList, setBrokerList] = useState([]);
 	const [isAddOpen, setIsAddOpen] = useState(false);
 	const [form] = Form.useForm();
+	const [passwordForm] = Form.useForm();
+	const [isPasswordOpen, setIsPasswordOpen] = useState(false);
+	const openPassword = (broker_id: string, account: string) => {
+		passwordForm.resetFields();
+		passwordForm.setFieldsValue({ broker_id, account, kind: "trade" });
+		setIsPasswordOpen(true);
+	};
 	useEffect(() => {
 		invoke('account_list').then(res => {
 			console.log('account list', res);
@@ -92,6 +108,10 @@ export default () => {
 			});
 			const unlisten2 = await listen('cta-event', (event: any) => {
 				console.log('account window: cta-event', event);
+				if (event.payload.tp === "PasswordChangeRequired") {
+					messageApi.warning(`账户${event.payload.a}需要修改密码后才能登录`);
+					openPassword(event.payload.b, event.payload.a);
+				}
 				if (event.tp !== "OnRtnOrder"
 					&& event.tp !== "OnRtnTrade"
 				) {
@@ -127,6 +147,17 @@ export default () => {
 		setIsAddOpen(false);
 	};
 
+	const onPasswordFinish = (values: any) => {
+		const v = passwordForm.getFieldsValue(true);
+		const cmd = v.kind === "money" ? 'update_money_password' : 'update_password';
+		invoke(cmd, { brokerId: v.broker_id, account: v.account, oldPassword: v.old_password, newPassword: v.new_password }).then(res => {
+			messageApi.success('密码修改成功');
+			setIsPasswordOpen(false);
+		}).catch(err => {
+			messageApi.error(err);
+		});
+	};
+
 	const onReset = () => {
 		invoke('default_account').then(res => {
 			form.setFieldsValue(res);
@@ -208,6 +239,14 @@ export default () => {
 						});
 					}}
 
+					handlePassword={() => openPassword(e.broker_id, e.account)}
+					handleRebuild={() => {
+						invoke('rebuild_history', { brokerId: e.broker_id, account: e.account }).then(res => {
+							messageApi.info('正在重建当日报单和成交');
+						}).catch(err => {
+							messageApi.error(err);
+						});
+					}}
 					key={index} {...e} > </AccountCard>)}
 			</table>
 			<Modal title="添加账户" footer={null} open={isAddOpen} onOk={() => { setIsAddOpen(false); }} onCancel={() => { setIsAddOpen(false) }}>
@@ -253,6 +292,16 @@ export default () => {
 					<Form.Item name="password" label="密码" rules={[{ required: true }]}>
 						<Input />
 					</Form.Item>
+					<Form.Item name="private_resume" label="私有流重传">
+						<Select placeholder="Quick">
+							<Option value="Restart">Restart</Option>
+							<Option value="Resume">Resume</Option>
+							<Option value="Quick">Quick</Option>
+						</Select>
+					</Form.Item>
+					<Form.Item name="currency_id" label="币种">
+						<Input placeholder="CNY" />
+					</Form.Item>
 					<Form.Item {...tailLayout}>
 						<Button type="primary" htmlType="submit">
 							提交
@@ -263,6 +312,27 @@ export default () => {
 					</Form.Item>
 				</Form>
 			</Modal>
+			<Modal title="修改密码" footer={null} open={isPasswordOpen} onCancel={() => { setIsPasswordOpen(false) }}>
+				<Form {...layout} form={passwordForm} onFinish={onPasswordFinish} style={{ maxWidth: 600 }}>
+					<Form.Item name="kind" label="类型">
+						<Select>
+							<Option value="trade">交易密码</Option>
+							<Option value="money">资金密码</Option>
+						</Select>
+					</Form.Item>
+					<Form.Item name="old_password" label="原密码" rules={[{ required: true }]}>
+						<Input.Password />
+					</Form.Item>
+					<Form.Item name="new_password" label="新密码" rules={[{ required: true }]}>
+						<Input.Password />
+					</Form.Item>
+					<Form.Item {...tailLayout}>
+						<Button type="primary" htmlType="submit">
+							提交
+						</Button>
+					</Form.Item>
+				</Form>
+			</Modal>
 		</div>
 	)
 }
\ No newline at end of file
@@ -8,6 +8,8 @@ import { ExclamationCircleFilled, CloseOutlined } from '@ant-design/icons';
 import { ask } from '@tauri-apps/api/dialog';
 import "./account.css";
 
+const { Option } = Select;
+
 const BrokerRow = (props: any) => {
     return <tr>
         <td>{props.broker_id}</td>
@@ -191,6 +193,39 @@ export default () => {
                     <Form.Item name="app_id" label="AppID" rules={[{}]}>
                         <Input />
                     </Form.Item>
+                    <Form.Item name="route_type" label="接入方式">
+                        <Select placeholder="直连">
+                            <Option value="direct">直连</Option>
+                            <Option value="relay">中继(多连接)</Option>
+                            <Option value="relay_operator">中继(操作员)</Option>
+                        </Select>
+                    </Form.Item>
+                    <Form.Item name="terminal_info" label="终端信息">
+                        <Input />
+                    </Form.Item>
+                    <Form.Item name="inner_ip_address" label="内网IP">
+                        <Input />
+                    </Form.Item>
+                    <Form.Item name="mac_address" label="MAC地址">
+                        <Input />
+                    </Form.Item>
+                    <Form.Item name="hd_serial" label="硬盘序列号">
+                        <Input />
+                    </Form.Item>
+                    <Form.Item {...tailLayout}>
+                        <Button type="link" onClick={() => {
+                            invoke('collect_terminal_info').then((res: any) => {
+                                form.setFieldsValue({
+                                    terminal_info: res.terminal_info,
+                                    inner_ip_address: res.inner_ip_address,
+                                    mac_address: res.mac_address,
+                                    hd_serial: res.hd_serial,
+                                });
+                            }).catch(err => {
+                                messageApi.error(err);
+                            });
+                        }}>采集本机信息</Button>
+                    </Form.Item>
                     <Form.List name="fronts">
                         {(fields, { add, remove }) => (
                             <div style={{ display: 'flex', rowGap: 16, flexDirection: 'column' }}>
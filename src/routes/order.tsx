@@ -66,6 +66,11 @@ export default () => {
 		async function test_listen() {
 			const unlisten = await appWindow.listen('cta-event', (event: any) => {
 				console.log('order table : cta-event', event);
+				if (event.payload.tp == "TradingDayRollover") {
+					invoke('order_rows').then(res => {
+						setOrderList(res as any);
+					});
+				}
 				if (event.tp == "Order") {
 					invoke('get_order_row', { key: event.payload.key, brokerId: event.payload.b, account: event.payload.a }).then(res => {
 						console.log('get order row', res);
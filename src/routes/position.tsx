@@ -56,6 +56,11 @@ export default () => {
         async function test_listen() {
             const unlisten = await appWindow.listen('cta-event', (event: any) => {
                 console.log('position table : cta-event', event);
+                if (event.payload.tp == "TradingDayRollover") {
+                    invoke('position_rows').then(res => {
+                        setPositionList(res as any);
+                    });
+                }
                 if (event.tp == "Position") {
                     invoke('get_position_row', { key: event.payload.key, brokerId: event.payload.b, account: event.payload.a }).then(res => {
                         console.log('get position row', res);
@@ -117,6 +117,8 @@ export default () => {
 					navigate('position-detail-table');
 				} else if (m === 'market-data-table') {
 					navigate('market-data-table');
+				} else if (m === 'settlement') {
+					navigate('settlement');
 				}
 			});
 			return [unlisten];
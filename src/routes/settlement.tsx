@@ -0,0 +1,62 @@
+import { invoke } from '@tauri-apps/api/tauri';
+import { Button, Select, Space, message } from 'antd';
+import React, { useState, useEffect } from 'react'
+import { appWindow } from '@tauri-apps/api/window';
+import "./order.css";
+
+export default () => {
+    const [messageApi, contextHolder] = message.useMessage();
+    const [settlementList, setSettlementList] = useState([]);
+    const [accountList, setAccountList] = useState([]);
+    const [current, setCurrent] = useState("");
+    const reload = () => {
+        invoke('settlement_rows').then(res => {
+            console.log('settlement rows', res);
+            setSettlementList(res as any);
+        });
+        invoke('account_list').then(res => {
+            setAccountList(res as any);
+        });
+    };
+    useEffect(() => {
+        reload();
+        async function test_listen() {
+            const unlisten = await appWindow.listen('cta-event', (event: any) => {
+                const tp = event.payload.tp;
+                if (tp == "OnRspQrySettlementInfo" || tp == "OnRspSettlementInfoConfirm" || tp == "SettlementPending") {
+                    reload();
+                }
+            });
+            return [unlisten];
+        }
+        const unlisten = test_listen();
+        return () => {
+            unlisten.then((ul) => ul.forEach((uf) => uf()));
+        }
+    }, []);
+    const key = (e: any) => `${e.broker_id}:${e.account}:${e.trading_day}`;
+    const selected: any = settlementList.find((e: any) => key(e) == current) || settlementList[0];
+    const account: any = selected && accountList.find((a: any) => a.broker_id == selected.broker_id && a.account == selected.account);
+    const confirm = () => {
+        invoke('confirm_settlement', { brokerId: selected.broker_id, account: selected.account }).then(() => {
+            messageApi.success('结算单已确认');
+            reload();
+        }).catch(e => {
+            messageApi.error(`确认失败 ${e}`);
+        });
+    };
+    return (
+        <div>
+            {contextHolder}
+            <Space style={{ margin: 8 }}>
+                <Select style={{ width: 320 }} value={selected ? key(selected) : undefined} placeholder="没有结算单"
+                    onChange={setCurrent}
+                    options={settlementList.map((e: any) => ({ value: key(e), label: `${e.broker_id}:${e.account} ${e.trading_day}` }))} />
+                <Button type="primary" disabled={!selected || (account && account.settlement_confirmed)} onClick={confirm}>
+                    {account && account.settlement_confirmed ? "已确认" : "确认结算单"}
+                </Button>
+            </Space>
+            <pre style={{ fontFamily: 'monospace', fontSize: 12, padding: 8 }}>{selected ? selected.content : ""}</pre>
+        </div>
+    )
+}